@@ -0,0 +1,552 @@
+//! Protecting servers from raids, i.e. many accounts joining at once to spam.
+//!
+//! The joins of servers with raid protection are tracked in memory. Once they
+//! look like a raid, the server is put into raid mode and responds as it is set
+//! up to until an administrator ends the raid.
+
+use crate::{
+    consts,
+    data::{
+        antiraid::{self, JoinTrackerContainer, RaidProtection, RaidResponse, RecentJoin},
+        audit::{Actor, PendingChange},
+        moderation::{self, CaseAction},
+        ServerSettings,
+    },
+    modlog,
+    prelude::*,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use serenity::{
+    model::{
+        channel::{ChannelType, PermissionOverwrite, PermissionOverwriteType},
+        guild::{Member, VerificationLevel},
+        id::{ChannelId, GuildId, RoleId, UserId},
+        permissions::Permissions,
+    },
+    prelude::*,
+};
+use std::time::Instant;
+
+/// The first second of 2015, which the IDs of Discord count from, in
+/// milliseconds since the Unix epoch.
+const DISCORD_EPOCH: i64 = 1_420_070_400_000;
+
+/// When the account of a user was created, which is part of its ID.
+pub fn created_at(user: UserId) -> DateTime<Utc> {
+    Utc.timestamp_millis((user.0 >> 22) as i64 + DISCORD_EPOCH)
+}
+
+/// Tracks a member joining a server, responding if they are part of a raid.
+///
+/// Returns whether the member was kicked.
+pub fn member_joined(ctx: &Context, guild: GuildId, member: &Member) -> bool {
+    match try_member_joined(ctx, guild, member) {
+        Ok(kicked) => kicked,
+        Err(e) => {
+            error!(
+                "Couldn't check whether {} joining {} is a raid: {:?}",
+                member.user_id().0,
+                guild.0,
+                e
+            );
+            false
+        }
+    }
+}
+
+fn try_member_joined(ctx: &Context, guild: GuildId, member: &Member) -> Result<bool> {
+    let protection = RaidProtection::new(guild.0, &ctx.data)?.read().clone();
+    let user = member.user.read().clone();
+    // Bots can only be added by administrators
+    if !protection.enabled || user.bot {
+        return Ok(false);
+    }
+    if protection.raid_started_at.is_some() {
+        if protection.response() != RaidResponse::Kick {
+            return Ok(false);
+        }
+        kick(ctx, guild, &[user.id], "joined during a raid");
+        return Ok(true);
+    }
+
+    let join = RecentJoin {
+        at: Instant::now(),
+        user_id: user.id.0,
+        account_created_at: created_at(user.id),
+        avatar: user.avatar.clone(),
+        name: user.name.to_lowercase(),
+    };
+    // Detecting the raid under the same lock as recording the join keeps it from
+    // being detected by several joins at once.
+    let raid = {
+        let data = ctx.data.read();
+        let mut tracker = data.get::<JoinTrackerContainer>().failure()?.lock();
+        let joins = tracker.record(guild.0, join, protection.join_window());
+        let raid = detect(&protection, &joins);
+        if raid.is_some() {
+            tracker.clear(guild.0);
+        }
+        raid
+    };
+    let (reason, raiders) = match raid {
+        Some(s) => s,
+        None => return Ok(false),
+    };
+
+    start(ctx, guild, &reason, &raiders, Actor::event())?;
+    Ok(protection.response() == RaidResponse::Kick && raiders.contains(&user.id))
+}
+
+/// Why the recent joins of a server look like a raid and who the raiders are,
+/// if they do.
+fn detect(protection: &RaidProtection, joins: &[RecentJoin]) -> Option<(String, Vec<UserId>)> {
+    let ids = |joins: &[&RecentJoin]| joins.iter().map(|j| UserId(j.user_id)).collect();
+    let window = protection.join_window_seconds;
+
+    if joins.len() >= protection.join_limit.max(2) as usize {
+        let all = joins.iter().collect::<Vec<_>>();
+        return Some((
+            format!("{} members joined within {} seconds", joins.len(), window),
+            ids(&all),
+        ));
+    }
+
+    let limit = protection.similar_limit.max(2) as usize;
+    if let Some(age) = protection.min_account_age() {
+        let now = Utc::now();
+        let new = joins
+            .iter()
+            .filter(|j| {
+                now.signed_duration_since(j.account_created_at)
+                    .to_std()
+                    .map(|d| d < age)
+                    .unwrap_or(true)
+            })
+            .collect::<Vec<_>>();
+        if new.len() >= limit {
+            return Some((
+                format!(
+                    "{} accounts younger than {} joined within {} seconds",
+                    new.len(),
+                    moderation::format_duration(age),
+                    window
+                ),
+                ids(&new),
+            ));
+        }
+    }
+
+    // Only the newest join can have made its group reach the limit
+    let newest = joins.last()?;
+    if let Some(ref avatar) = newest.avatar {
+        let same = joins
+            .iter()
+            .filter(|j| j.avatar.as_ref() == Some(avatar))
+            .collect::<Vec<_>>();
+        if same.len() >= limit {
+            return Some((
+                format!(
+                    "{} members with the same avatar joined within {} seconds",
+                    same.len(),
+                    window
+                ),
+                ids(&same),
+            ));
+        }
+    }
+    let same = joins
+        .iter()
+        .filter(|j| j.name == newest.name)
+        .collect::<Vec<_>>();
+    if same.len() >= limit {
+        return Some((
+            format!(
+                "{} members named `{}` joined within {} seconds",
+                same.len(),
+                newest.name,
+                window
+            ),
+            ids(&same),
+        ));
+    }
+    None
+}
+
+/// Puts a server into raid mode, responding to the raid as the server is set up
+/// to, and alerts the server.
+pub fn start(
+    ctx: &Context,
+    guild: GuildId,
+    reason: &str,
+    raiders: &[UserId],
+    actor: Actor,
+) -> Result<()> {
+    let mut protection = RaidProtection::new(guild.0, &ctx.data)?.read().clone();
+    if protection.raid_started_at.is_some() {
+        return Err(AntiraidErrorKind::AlreadyActive.into());
+    }
+
+    let response = protection.response();
+    match response {
+        RaidResponse::Alert => {}
+        RaidResponse::Lockdown => {
+            protection.locked_channels = lock(ctx, guild)?.iter().map(|c| c.0 as i64).collect();
+        }
+        RaidResponse::Verification => {
+            protection.previous_verification_level = Some(raise_verification(ctx, guild)?);
+        }
+        RaidResponse::Kick => kick(ctx, guild, raiders, reason),
+    }
+
+    let now = Utc::now();
+    let change = PendingChange::new(actor, "raid_started_at", "none", now);
+    protection.raid_started_at = Some(now);
+    let saved: Result<()> = try {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        antiraid::save(&pgconn, &protection, &[change])?;
+    };
+    if let Err(e) = saved {
+        // Ending the raid mode couldn't undo the response without the record of it
+        if let Err(why) = undo(ctx, guild, &protection) {
+            error!("Couldn't undo the raid response in {}: {:?}", guild.0, why);
+        }
+        return Err(e);
+    }
+    RaidProtection::evict(guild.0, &ctx.data)?;
+
+    alert(
+        ctx,
+        guild,
+        &format!(
+            "**Raid detected:** {}. {} End the raid mode with `{}antiraid end`.",
+            reason,
+            match response {
+                RaidResponse::Alert => "Nothing was done about it.",
+                RaidResponse::Lockdown => "Nobody can send messages until it's over.",
+                RaidResponse::Verification => "The verification level was raised to the highest.",
+                RaidResponse::Kick => "The raiders and anyone joining until it's over are kicked.",
+            },
+            consts::PREFIX
+        ),
+    );
+    Ok(())
+}
+
+/// Takes a server out of raid mode, undoing the response to the raid.
+pub fn end(ctx: &Context, guild: GuildId, actor: Actor) -> Result<()> {
+    let mut protection = RaidProtection::new(guild.0, &ctx.data)?.read().clone();
+    let started = match protection.raid_started_at {
+        Some(s) => s,
+        None => return Err(AntiraidErrorKind::NotActive.into()),
+    };
+
+    undo(ctx, guild, &protection)?;
+
+    let change = PendingChange::new(actor, "raid_started_at", started, "none");
+    protection.raid_started_at = None;
+    protection.locked_channels.clear();
+    protection.previous_verification_level = None;
+    {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        antiraid::save(&pgconn, &protection, &[change])?;
+    }
+    RaidProtection::evict(guild.0, &ctx.data)?;
+
+    alert(
+        ctx,
+        guild,
+        "**The raid is over.** Everything is back to normal.",
+    );
+    Ok(())
+}
+
+/// Undoes what the response to a raid changed in a server.
+fn undo(ctx: &Context, guild: GuildId, protection: &RaidProtection) -> Result<()> {
+    unlock(ctx, guild, &protection.locked_channels);
+    if let Some(level) = protection.previous_verification_level {
+        set_verification_level(ctx, guild, verification_level(level))?;
+    }
+    Ok(())
+}
+
+/// Keeps everyone from sending messages in the text channels of a server which
+/// don't explicitly allow or deny it already, returning those which were locked.
+fn lock(ctx: &Context, guild: GuildId) -> Result<Vec<ChannelId>> {
+    let everyone = PermissionOverwriteType::Role(RoleId(guild.0));
+    let channels = {
+        let guild = guild.to_guild_cached(&ctx.cache).failure()?;
+        let guild = guild.read();
+        guild
+            .channels
+            .values()
+            .filter_map(|c| {
+                let c = c.read();
+                if c.kind != ChannelType::Text {
+                    return None;
+                }
+                let (allow, deny) = overwrite_of(&c.permission_overwrites, everyone);
+                if (allow | deny).contains(Permissions::SEND_MESSAGES) {
+                    return None;
+                }
+                Some((c.id, allow, deny))
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut locked = Vec::new();
+    for (channel, allow, deny) in channels {
+        let overwrite = PermissionOverwrite {
+            allow,
+            deny: deny | Permissions::SEND_MESSAGES,
+            kind: everyone,
+        };
+        match channel.create_permission(&ctx.http, &overwrite) {
+            Ok(()) => locked.push(channel),
+            Err(e) => warn!(
+                "Couldn't lock channel {} of {}: {:?}",
+                channel.0, guild.0, e
+            ),
+        }
+    }
+    Ok(locked)
+}
+
+/// Lets everyone send messages in the channels locked for a raid again.
+fn unlock(ctx: &Context, guild: GuildId, channels: &[i64]) {
+    let everyone = PermissionOverwriteType::Role(RoleId(guild.0));
+    for &channel in channels {
+        let channel = ChannelId(channel as u64);
+        // Channels deleted during the raid have nothing left to unlock
+        let (allow, deny) = match channel
+            .to_channel_cached(&ctx.cache)
+            .and_then(|c| c.guild())
+        {
+            Some(c) => overwrite_of(&c.read().permission_overwrites, everyone),
+            None => continue,
+        };
+        let deny = deny - Permissions::SEND_MESSAGES;
+        let result = if allow.is_empty() && deny.is_empty() {
+            channel.delete_permission(&ctx.http, everyone)
+        } else {
+            channel.create_permission(
+                &ctx.http,
+                &PermissionOverwrite {
+                    allow,
+                    deny,
+                    kind: everyone,
+                },
+            )
+        };
+        if let Err(e) = result {
+            warn!(
+                "Couldn't unlock channel {} of {}: {:?}",
+                channel.0, guild.0, e
+            );
+        }
+    }
+}
+
+/// What the overwrite of a channel for the role or member allows and denies.
+fn overwrite_of(
+    overwrites: &[PermissionOverwrite],
+    kind: PermissionOverwriteType,
+) -> (Permissions, Permissions) {
+    overwrites
+        .iter()
+        .find(|o| o.kind == kind)
+        .map(|o| (o.allow, o.deny))
+        .unwrap_or((Permissions::empty(), Permissions::empty()))
+}
+
+/// Raises the verification level of a server to the highest, returning what it
+/// was before.
+fn raise_verification(ctx: &Context, guild: GuildId) -> Result<i16> {
+    let previous = guild
+        .to_guild_cached(&ctx.cache)
+        .failure()?
+        .read()
+        .verification_level;
+    set_verification_level(ctx, guild, VerificationLevel::Higher)?;
+    Ok(match previous {
+        VerificationLevel::None => 0,
+        VerificationLevel::Low => 1,
+        VerificationLevel::Medium => 2,
+        VerificationLevel::High => 3,
+        _ => 4,
+    })
+}
+
+fn set_verification_level(
+    ctx: &Context,
+    mut guild: GuildId,
+    level: VerificationLevel,
+) -> Result<()> {
+    guild.edit(&ctx.http, |g| g.verification_level(level))?;
+    Ok(())
+}
+
+fn verification_level(level: i16) -> VerificationLevel {
+    match level {
+        0 => VerificationLevel::None,
+        1 => VerificationLevel::Low,
+        2 => VerificationLevel::Medium,
+        3 => VerificationLevel::High,
+        _ => VerificationLevel::Higher,
+    }
+}
+
+/// Kicks raiders from a server, opening an automatic case for each.
+fn kick(ctx: &Context, guild: GuildId, raiders: &[UserId], reason: &str) {
+    let reason = format!("Anti-raid: {}.", reason);
+    for &raider in raiders {
+        let result: Result<()> = try {
+            guild.kick(&ctx.http, raider)?;
+            let case = {
+                let pgconn = crate::database::connection(&ctx.data)?;
+                moderation::open(
+                    &pgconn,
+                    guild.0,
+                    CaseAction::Kick,
+                    raider.0,
+                    None,
+                    Some(&reason),
+                    None,
+                )?
+            };
+            modlog::post(&ctx.http, &ctx.data, &case);
+        };
+        if let Err(e) = result {
+            warn!(
+                "Couldn't kick raider {} from {}: {:?}",
+                raider.0, guild.0, e
+            );
+        }
+    }
+}
+
+/// Tells the moderators of a server about a raid in the modlog, or in the
+/// system channel if there is none.
+fn alert(ctx: &Context, guild: GuildId, text: &str) {
+    let result: Result<()> = try {
+        let modlog = *ServerSettings::new(guild.0, &ctx.data)?
+            .read()
+            .modlog_channel();
+        let channel = match modlog {
+            Some(s) => Some(ChannelId(s)),
+            None => guild
+                .to_guild_cached(&ctx.cache)
+                .and_then(|g| g.read().system_channel_id),
+        };
+        if let Some(channel) = channel {
+            channel.say(&ctx.http, text)?;
+        }
+    };
+    if let Err(e) = result {
+        error!("Couldn't alert {} about a raid: {:?}", guild.0, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration as ChronoDuration;
+
+    fn protection() -> RaidProtection {
+        RaidProtection {
+            enabled: true,
+            join_limit: 5,
+            similar_limit: 3,
+            min_account_age_seconds: Some(60 * 60 * 24),
+            ..RaidProtection::default_for(1)
+        }
+    }
+
+    /// A join by a day-old account with a unique avatar and name.
+    fn join(user: u64) -> RecentJoin {
+        RecentJoin {
+            at: Instant::now(),
+            user_id: user,
+            account_created_at: Utc::now() - ChronoDuration::days(100),
+            avatar: Some(format!("avatar{}", user)),
+            name: format!("member{}", user),
+        }
+    }
+
+    fn raiders(raid: Option<(String, Vec<UserId>)>) -> Vec<u64> {
+        raid.unwrap().1.iter().map(|u| u.0).collect()
+    }
+
+    #[test]
+    fn reads_when_accounts_were_created() {
+        assert_eq!(created_at(UserId(0)), Utc.timestamp_millis(DISCORD_EPOCH));
+        assert_eq!(
+            created_at(UserId(1000 << 22)),
+            Utc.timestamp_millis(DISCORD_EPOCH + 1000)
+        );
+    }
+
+    #[test]
+    fn ignores_ordinary_joins() {
+        let joins = (1..5).map(join).collect::<Vec<_>>();
+        assert!(detect(&protection(), &joins).is_none());
+        assert!(detect(&protection(), &[]).is_none());
+    }
+
+    #[test]
+    fn detects_too_many_joins() {
+        let joins = (1..6).map(join).collect::<Vec<_>>();
+        let raid = detect(&protection(), &joins);
+        assert!(raid.as_ref().unwrap().0.starts_with("5 members joined"));
+        assert_eq!(raiders(raid), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn detects_new_accounts() {
+        let mut joins = (1..5).map(join).collect::<Vec<_>>();
+        for j in &mut joins[1..] {
+            j.account_created_at = Utc::now() - ChronoDuration::minutes(5);
+        }
+        let raid = detect(&protection(), &joins);
+        assert!(raid
+            .as_ref()
+            .unwrap()
+            .0
+            .starts_with("3 accounts younger than 1d"));
+        assert_eq!(raiders(raid), [2, 3, 4]);
+        // Without a minimum age, new accounts are fine
+        let protection = RaidProtection {
+            min_account_age_seconds: None,
+            ..protection()
+        };
+        assert!(detect(&protection, &joins).is_none());
+    }
+
+    #[test]
+    fn detects_the_same_avatar_or_name() {
+        let mut joins = (1..5).map(join).collect::<Vec<_>>();
+        for j in &mut joins[..3] {
+            j.avatar = Some(String::from("same"));
+        }
+        // Only groups the newest join is in count
+        assert!(detect(&protection(), &joins).is_none());
+        joins[3].avatar = Some(String::from("same"));
+        assert_eq!(raiders(detect(&protection(), &joins)), [1, 2, 3, 4]);
+
+        let mut joins = (1..5).map(join).collect::<Vec<_>>();
+        for j in &mut joins[1..] {
+            j.name = String::from("spammer");
+        }
+        let raid = detect(&protection(), &joins);
+        assert!(raid.as_ref().unwrap().0.contains("named `spammer`"));
+        assert_eq!(raiders(raid), [2, 3, 4]);
+    }
+
+    #[test]
+    fn default_avatars_arent_alike() {
+        let mut joins = (1..5).map(join).collect::<Vec<_>>();
+        for j in &mut joins {
+            j.avatar = None;
+        }
+        assert!(detect(&protection(), &joins).is_none());
+    }
+}
@@ -0,0 +1,250 @@
+//! Automatically moderating the messages sent in servers by their automod
+//! rules.
+//!
+//! Messages breaking a rule are deleted, and their authors are warned, timed
+//! out or kicked depending on the rule. Everything beyond deleting the message
+//! opens an automatic case, which is posted to the modlog like any other.
+
+use crate::{
+    data::{
+        automod::{GuildRules, RuleAction, RuleKind, SpamTrackerContainer},
+        moderation::{self, Case, CaseAction},
+        scheduledjobs::{self, JobKind},
+        ConfigurationContainer, ServerSettings,
+    },
+    modlog,
+    prelude::*,
+};
+use diesel::Connection;
+use serenity::{
+    model::{channel::Message, id::GuildId, permissions::Permissions},
+    prelude::*,
+};
+use std::time::Duration;
+
+/// A rule broken by a message.
+struct Violation {
+    kind: RuleKind,
+    action: RuleAction,
+    timeout: Option<Duration>,
+    reason: String,
+}
+
+/// Checks a message against the rules of its server and acts on it, returning
+/// whether it broke any.
+///
+/// Bots and members who may manage messages are never moderated.
+pub fn check(ctx: &Context, guild: GuildId, msg: &Message) -> bool {
+    if msg.author.bot {
+        return false;
+    }
+    let violation = match find_violation(ctx, guild, msg) {
+        Ok(Some(s)) => s,
+        Ok(None) => return false,
+        Err(e) => {
+            error!(
+                "Couldn't check message {} against the automod: {:?}",
+                msg.id.0, e
+            );
+            return false;
+        }
+    };
+    if let Err(e) = enforce(ctx, guild, msg, &violation) {
+        error!(
+            "Couldn't enforce the {} rule of {} on {}: {:?}",
+            violation.kind, guild.0, msg.author.id.0, e
+        );
+    }
+    true
+}
+
+/// The harshest rule broken by the message, if any.
+fn find_violation(ctx: &Context, guild: GuildId, msg: &Message) -> Result<Option<Violation>> {
+    let rules = GuildRules::new(guild.0, &ctx.data)?;
+    let rules = rules.read();
+    if rules.rules.is_empty() {
+        return Ok(None);
+    }
+
+    let roles = {
+        let guild = match guild.to_guild_cached(&ctx.cache) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let guild = guild.read();
+        if guild
+            .member_permissions(msg.author.id)
+            .contains(Permissions::MANAGE_MESSAGES)
+        {
+            return Ok(None);
+        }
+        guild
+            .members
+            .get(&msg.author.id)
+            .map(|m| m.roles.iter().map(|r| r.0).collect::<Vec<_>>())
+            .unwrap_or_default()
+    };
+
+    let mut found: Option<Violation> = None;
+    for rule in &rules.rules {
+        if rule.exempts(msg.channel_id.0, &roles) {
+            continue;
+        }
+        let reason = match rule.kind {
+            RuleKind::Spam => spam(ctx, guild, msg, rule.threshold)?,
+            _ => rule.violation(msg),
+        };
+        let reason = match reason {
+            Some(s) => s,
+            None => continue,
+        };
+        if found
+            .as_ref()
+            .map(|f| rule.action > f.action)
+            .unwrap_or(true)
+        {
+            found = Some(Violation {
+                kind: rule.kind,
+                action: rule.action,
+                timeout: rule.timeout,
+                reason,
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// Records the message with the spam tracker, returning why it's spam if it is.
+fn spam(ctx: &Context, guild: GuildId, msg: &Message, threshold: u32) -> Result<Option<String>> {
+    if msg.content.trim().is_empty() {
+        return Ok(None);
+    }
+    let data = ctx.data.read();
+    let mut tracker = data.get::<SpamTrackerContainer>().failure()?.lock();
+    let count = tracker.record(guild.0, msg.author.id.0, &msg.content);
+    if count < threshold as usize {
+        return Ok(None);
+    }
+    // Starting over keeps every following message from being punished again
+    tracker.clear(guild.0, msg.author.id.0);
+    Ok(Some(format!("sent the same message {} times", count)))
+}
+
+/// Deletes the message and does what the broken rule says to its author.
+fn enforce(ctx: &Context, guild: GuildId, msg: &Message, violation: &Violation) -> Result<()> {
+    msg.channel_id.delete_message(&ctx.http, msg.id)?;
+
+    let target = msg.author.id;
+    let reason = format!("Automod: {} ({} rule).", violation.reason, violation.kind);
+    let open = |action: CaseAction| -> Result<Case> {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        Ok(moderation::open(
+            &pgconn,
+            guild.0,
+            action,
+            target.0,
+            None,
+            Some(&reason),
+            None,
+        )?)
+    };
+
+    let case = match violation.action {
+        RuleAction::Delete => {
+            notify(ctx, guild, msg, violation, None);
+            return Ok(());
+        }
+        RuleAction::Warn => open(CaseAction::Warn)?,
+        RuleAction::Timeout => {
+            let role = *ServerSettings::new(guild.0, &ctx.data)?.read().mute_role();
+            let role = match role {
+                Some(s) => s,
+                None => return Err(ModerationErrorKind::NoMuteRole.into()),
+            };
+            let duration = match violation.timeout {
+                Some(s) => s,
+                None => Duration::from_secs(
+                    *ctx.data
+                        .read()
+                        .get::<ConfigurationContainer>()
+                        .failure()?
+                        .automod()
+                        .default_timeout_seconds(),
+                ),
+            };
+            let until =
+                scheduledjobs::due_in(duration).ok_or(ModerationErrorKind::DurationTooLong)?;
+            // Opening the case first keeps the timeout from being left without a
+            // job lifting it
+            let pgconn = crate::database::connection(&ctx.data)?;
+            let case = pgconn.transaction::<_, diesel::result::Error, _>(|| {
+                let case = moderation::open(
+                    &pgconn,
+                    guild.0,
+                    CaseAction::Mute,
+                    target.0,
+                    None,
+                    Some(&reason),
+                    Some(duration),
+                )?;
+                scheduledjobs::schedule(
+                    &pgconn,
+                    JobKind::Unmute,
+                    guild.0,
+                    target.0,
+                    Some(case.case_number),
+                    until,
+                )?;
+                Ok(case)
+            })?;
+            if let Err(why) = ctx.http.add_member_role(guild.0, target.0, role) {
+                moderation::withdraw(&pgconn, &case)?;
+                return Err(why.into());
+            }
+            case
+        }
+        RuleAction::Kick => {
+            // They can't be sent a DM once they share no server with the bot
+            notify(ctx, guild, msg, violation, None);
+            guild.kick(&ctx.http, target)?;
+            open(CaseAction::Kick)?
+        }
+    };
+    if violation.action != RuleAction::Kick {
+        notify(ctx, guild, msg, violation, Some(&case));
+    }
+    modlog::post(&ctx.http, &ctx.data, &case);
+    Ok(())
+}
+
+/// Lets the author of the message know why it was removed, if they accept DMs.
+fn notify(
+    ctx: &Context,
+    guild: GuildId,
+    msg: &Message,
+    violation: &Violation,
+    case: Option<&Case>,
+) {
+    let guild_name = guild
+        .to_guild_cached(&ctx.cache)
+        .map(|g| g.read().name.clone())
+        .unwrap_or_default();
+    let consequence = match (violation.action, case.and_then(Case::duration)) {
+        (RuleAction::Warn, _) => String::from(" This is a warning."),
+        (RuleAction::Timeout, Some(d)) => format!(
+            " You have been muted for {}.",
+            moderation::format_duration(d)
+        ),
+        (RuleAction::Kick, _) => String::from(" You have been kicked."),
+        _ => String::new(),
+    };
+    let text = format!(
+        "Your message in {} was removed because you {}.{}",
+        guild_name, violation.reason, consequence
+    );
+    let _ = msg
+        .author
+        .id
+        .create_dm_channel(&ctx.http)
+        .and_then(|dm| dm.say(&ctx.http, &text));
+}
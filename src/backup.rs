@@ -0,0 +1,305 @@
+use crate::{
+    consts::SCHEMA_VERSION,
+    data::{
+        antiraid::RaidProtection,
+        automod::AutomodRule,
+        customcommands::CustomCommand,
+        eventhooks::EventHook,
+        greetings::Greeting,
+        moderation::Case,
+        onboarding::{Onboarding, PendingVerification},
+        scheduledjobs::Job,
+    },
+    prelude::*,
+    scheme::{server_settings, settings_audit, user_settings},
+};
+use chrono::{DateTime, Utc};
+use diesel::{connection::SimpleConnection, prelude::*, sql_types::Text, Column, Table};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// The version of the archive format, bumped whenever its layout changes.
+pub const ARCHIVE_FORMAT_VERSION: u32 = 1;
+
+/// How many rows are inserted per statement when restoring.
+const INSERT_CHUNK_SIZE: usize = 1000;
+
+/// Serial columns whose sequences have to be moved past the restored rows.
+const SERIAL_COLUMNS: &[(&str, &str)] = &[("settings_audit", "id"), ("scheduled_jobs", "id")];
+
+/// A dump of every table, as written by `asami export`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Archive {
+    pub format_version: u32,
+    pub schema_version: u32,
+    pub created_at: DateTime<Utc>,
+    pub tables: Tables,
+}
+
+macro_rules! backup_tables {
+    ($($table:ident => $row:ident),* $(,)?) => {
+        #[derive(Serialize, Deserialize, Debug, Default)]
+        pub struct Tables {
+            $(
+                #[serde(default)]
+                pub $table: Vec<$row>,
+            )*
+        }
+
+        impl Tables {
+            fn dump(conn: &PgConnection) -> QueryResult<Self> {
+                Ok(Tables {
+                    $($table: crate::scheme::$table::table.load(conn)?,)*
+                })
+            }
+
+            fn columns() -> Vec<(&'static str, Vec<&'static str>)> {
+                vec![$((stringify!($table), column_names::<crate::scheme::$table::table>()),)*]
+            }
+
+            fn row_counts(conn: &PgConnection) -> QueryResult<Vec<(&'static str, i64)>> {
+                Ok(vec![
+                    $((
+                        stringify!($table),
+                        crate::scheme::$table::table.count().get_result(conn)?,
+                    ),)*
+                ])
+            }
+
+            fn restore(&self, conn: &PgConnection) -> QueryResult<()> {
+                $(
+                    for chunk in self.$table.chunks(INSERT_CHUNK_SIZE) {
+                        diesel::insert_into(crate::scheme::$table::table)
+                            .values(chunk)
+                            .execute(conn)?;
+                    }
+                )*
+                Ok(())
+            }
+
+            fn summary(&self) -> Vec<String> {
+                vec![$(format!("{}: {} rows", stringify!($table), self.$table.len()),)*]
+            }
+        }
+    };
+}
+
+backup_tables! {
+    user_settings => UserSettingsRow,
+    server_settings => ServerSettingsRow,
+    settings_audit => SettingsAuditRow,
+    custom_commands => CustomCommand,
+    event_hooks => EventHook,
+    moderation_cases => Case,
+    scheduled_jobs => Job,
+    automod_rules => AutomodRule,
+    raid_protection => RaidProtection,
+    greetings => Greeting,
+    onboarding => Onboarding,
+    pending_verifications => PendingVerification,
+}
+
+/// The names of a tuple of columns.
+trait ColumnNames {
+    fn names() -> Vec<&'static str>;
+}
+
+macro_rules! impl_column_names {
+    ($($column:ident),+) => {
+        impl<$($column: Column),+> ColumnNames for ($($column,)+) {
+            fn names() -> Vec<&'static str> {
+                vec![$($column::NAME),+]
+            }
+        }
+    };
+}
+
+impl_column_names!(A);
+impl_column_names!(A, B);
+impl_column_names!(A, B, C);
+impl_column_names!(A, B, C, D);
+impl_column_names!(A, B, C, D, E);
+impl_column_names!(A, B, C, D, E, F);
+impl_column_names!(A, B, C, D, E, F, G);
+impl_column_names!(A, B, C, D, E, F, G, H);
+impl_column_names!(A, B, C, D, E, F, G, H, I);
+impl_column_names!(A, B, C, D, E, F, G, H, I, J);
+impl_column_names!(A, B, C, D, E, F, G, H, I, J, K);
+impl_column_names!(A, B, C, D, E, F, G, H, I, J, K, L);
+
+/// The names of the columns `scheme.rs` declares for a table.
+fn column_names<T: Table>() -> Vec<&'static str>
+where
+    T::AllColumns: ColumnNames,
+{
+    <T::AllColumns as ColumnNames>::names()
+}
+
+/// A column of a table, as the database lists it.
+#[derive(QueryableByName)]
+struct ColumnName {
+    #[sql_type = "Text"]
+    column_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Queryable, Insertable)]
+#[table_name = "user_settings"]
+pub struct UserSettingsRow {
+    id: i64,
+    blacklisted: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Queryable, Insertable)]
+#[table_name = "server_settings"]
+pub struct ServerSettingsRow {
+    id: i64,
+    blacklisted: bool,
+    departed_at: Option<DateTime<Utc>>,
+    hooks_enabled: bool,
+    hook_error_channel: Option<i64>,
+    mute_role: Option<i64>,
+    modlog_channel: Option<i64>,
+    message_log_channel: Option<i64>,
+    message_log_ignored: Vec<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Queryable, Insertable)]
+#[table_name = "settings_audit"]
+pub struct SettingsAuditRow {
+    id: i64,
+    target_kind: String,
+    target_id: i64,
+    actor_id: Option<i64>,
+    source: String,
+    field: String,
+    old_value: String,
+    new_value: String,
+    created_at: DateTime<Utc>,
+}
+
+/// Runs the command line interface with the arguments given to the program,
+/// excluding the program itself.
+pub fn run_cli(args: &[String], pool: &PgPool) -> Result<()> {
+    let (command, path) = match args {
+        [command, path] => (command.as_str(), Path::new(path)),
+        _ => return Err(CliErrorKind::Usage.into()),
+    };
+    let conn = pool.get()?;
+
+    match command {
+        "export" => {
+            let archive = export(&conn)?;
+            fs::write(path, serde_json::to_vec(&archive)?)?;
+            info!("Exported the database to {}:", path.display());
+            for line in archive.tables.summary() {
+                info!("  {}", line);
+            }
+        }
+        "import" => {
+            let archive: Archive = serde_json::from_slice(&fs::read(path)?)?;
+            import(&conn, &archive)?;
+            info!("Imported {} into the database:", path.display());
+            for line in archive.tables.summary() {
+                info!("  {}", line);
+            }
+        }
+        _ => return Err(CliErrorKind::Usage.into()),
+    }
+
+    Ok(())
+}
+
+/// Dumps every table in a single transaction.
+pub fn export(conn: &PgConnection) -> Result<Archive> {
+    let tables = conn
+        .build_transaction()
+        .read_only()
+        .repeatable_read()
+        .run(|| Tables::dump(conn))?;
+
+    Ok(Archive {
+        format_version: ARCHIVE_FORMAT_VERSION,
+        schema_version: SCHEMA_VERSION,
+        created_at: Utc::now(),
+        tables,
+    })
+}
+
+/// Restores an archive into an empty database of the current schema version.
+pub fn import(conn: &PgConnection, archive: &Archive) -> Result<()> {
+    if archive.format_version > ARCHIVE_FORMAT_VERSION {
+        return Err(CliErrorKind::UnsupportedArchive(archive.format_version).into());
+    }
+    if archive.schema_version != SCHEMA_VERSION {
+        return Err(CliErrorKind::SchemaMismatch {
+            archive: archive.schema_version,
+            current: SCHEMA_VERSION,
+        }
+        .into());
+    }
+
+    conn.transaction::<_, failure::Error, _>(|| {
+        check_schema(conn)?;
+        for (table, count) in Tables::row_counts(conn)? {
+            if count != 0 {
+                return Err(CliErrorKind::DatabaseNotEmpty(table.to_owned()).into());
+            }
+        }
+
+        archive.tables.restore(conn)?;
+
+        for (table, column) in SERIAL_COLUMNS {
+            conn.batch_execute(&format!(
+                "SELECT setval(pg_get_serial_sequence('{table}', '{column}'), \
+                 COALESCE((SELECT MAX({column}) FROM {table}), 0) + 1, false)",
+                table = table,
+                column = column
+            ))?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Checks that the tables of the database have the columns `scheme.rs` declares,
+/// as the schema version is only bumped by hand and may have been forgotten.
+fn check_schema(conn: &PgConnection) -> Result<()> {
+    for (table, expected) in Tables::columns() {
+        let actual = diesel::sql_query(
+            "SELECT column_name::text AS column_name FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name = $1",
+        )
+        .bind::<Text, _>(table)
+        .load::<ColumnName>(conn)?
+        .into_iter()
+        .map(|c| c.column_name)
+        .collect::<Vec<_>>();
+
+        let missing = expected
+            .iter()
+            .filter(|c| !actual.iter().any(|a| a == *c))
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        let unexpected = actual
+            .iter()
+            .filter(|a| !expected.contains(&a.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() || !unexpected.is_empty() {
+            let list = |columns: Vec<String>| {
+                if columns.is_empty() {
+                    String::from("none")
+                } else {
+                    columns.join(", ")
+                }
+            };
+            return Err(CliErrorKind::SchemaDiffers {
+                table: table.to_owned(),
+                missing: list(missing),
+                unexpected: list(unexpected),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,528 @@
+use super::prelude::*;
+use crate::{
+    data::{
+        audit::{self, AuditTarget},
+        eventhooks::{self, GuildHooks, HookEvent},
+        greetings::{GreetingKind, TEMPLATE_LENGTH_LIMIT},
+        guildconfig::{DocumentFormat, GuildConfig, GuildSettings, Remapper},
+        onboarding::{VerificationMethod, MAX_AUTO_ROLES},
+        ConfigurationContainer,
+    },
+    greetings as greeter,
+    scripting::owner::extract_code,
+};
+
+/// Checks the sections of an imported configuration against the limits of the
+/// commands setting them up.
+fn check_import(
+    ctx: &Context,
+    msg: &Message,
+    settings: &GuildSettings,
+) -> std::result::Result<(), CommandError> {
+    if let Some(commands) = &settings.custom_commands {
+        let (max_per_guild, max_source_bytes) = {
+            let data = ctx.data.read();
+            let config = data
+                .get::<ConfigurationContainer>()
+                .failure()?
+                .custom_commands();
+            (*config.max_per_guild(), *config.max_source_bytes())
+        };
+        if commands.len() > max_per_guild {
+            return Err(CustomCommandErrorKind::TooMany(max_per_guild).into());
+        }
+        for (name, code) in commands {
+            super::customcommands::check_command_name(name)?;
+            if code.len() > max_source_bytes {
+                return Err(CustomCommandErrorKind::TooLong(max_source_bytes).into());
+            }
+        }
+    }
+    if let Some(hooks) = &settings.hooks {
+        let max_source_bytes = *ctx
+            .data
+            .read()
+            .get::<ConfigurationContainer>()
+            .failure()?
+            .custom_commands()
+            .max_source_bytes();
+        for (event, code) in hooks {
+            parse_event(event)?;
+            if code.len() > max_source_bytes {
+                return Err(CustomCommandErrorKind::TooLong(max_source_bytes).into());
+            }
+        }
+    }
+    if let Some(rules) = &settings.automod {
+        let (max_bytes, max_patterns) = {
+            let data = ctx.data.read();
+            let config = data.get::<ConfigurationContainer>().failure()?.automod();
+            (*config.max_pattern_bytes(), *config.max_patterns())
+        };
+        for (kind, rule) in rules {
+            super::automod::parse_rule(kind)?;
+            super::automod::parse_action(&rule.action)?;
+            if rule.patterns.len() > max_patterns {
+                return Err(AutomodErrorKind::TooManyPatterns(max_patterns).into());
+            }
+            for pattern in &rule.patterns {
+                super::automod::check_pattern(pattern, max_bytes)?;
+            }
+        }
+    }
+    if let Some(protection) = &settings.raid_protection {
+        let max = *ctx
+            .data
+            .read()
+            .get::<ConfigurationContainer>()
+            .failure()?
+            .antiraid()
+            .max_join_window_seconds();
+        super::antiraid::parse_response(&protection.response)?;
+        if protection.join_window_seconds.max(0) as u64 > max {
+            return Err(AntiraidErrorKind::WindowTooLong(max).into());
+        }
+    }
+    if let Some(greetings) = &settings.greetings {
+        let values = greeter::values(ctx, msg.guild_id.failure()?, &msg.author);
+        for (kind, greeting) in greetings {
+            let kind = kind.parse::<GreetingKind>().map_err(|()| {
+                let kinds = GreetingKind::ALL
+                    .iter()
+                    .map(|k| format!("`{}`", k))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                GreetingErrorKind::UnknownKind(kind.to_owned(), kinds)
+            })?;
+            if kind == GreetingKind::Farewell && greeting.channel.is_none() {
+                return Err(GreetingErrorKind::FarewellDirectMessage.into());
+            }
+            if greeting.template.chars().count() > TEMPLATE_LENGTH_LIMIT {
+                return Err(GreetingErrorKind::TooLong(TEMPLATE_LENGTH_LIMIT).into());
+            }
+            greeter::render(&greeting.template, &values)?;
+        }
+    }
+    if let Some(onboarding) = &settings.onboarding {
+        if onboarding.auto_roles.len() > MAX_AUTO_ROLES {
+            return Err(VerificationErrorKind::TooManyRoles(MAX_AUTO_ROLES).into());
+        }
+        if let Some(method) = &onboarding.verification {
+            method
+                .parse::<VerificationMethod>()
+                .map_err(|()| CommandUsageKind::GateUsage)?;
+            if onboarding.auto_roles.is_empty() {
+                return Err(VerificationErrorKind::NoRoles.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[command]
+#[description = "Shows the history of changes made to the settings of a user or server."]
+#[usage = "<user|server> [id] [amount]"]
+fn audit(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let kind = match args.single::<String>()?.to_lowercase().as_str() {
+        "user" => AuditTarget::User,
+        "server" => AuditTarget::Server,
+        _ => return Err(CommandUsageKind::AuditUsage.into()),
+    };
+    let target = if args.is_empty() {
+        match kind {
+            AuditTarget::User => msg.author.id.0,
+            AuditTarget::Server => msg.guild_id.failure()?.0,
+        }
+    } else {
+        args.single::<u64>()?
+    };
+    let limit = if args.is_empty() {
+        10
+    } else {
+        args.single::<i64>()?.max(1).min(15)
+    };
+
+    if !is_owner(ctx, msg.author.id) {
+        let own_guild = kind == AuditTarget::Server && msg.guild_id.map(|g| g.0) == Some(target);
+        if !own_guild || !has_guild_permissions(ctx, msg, Permissions::ADMINISTRATOR) {
+            return Err(PermissionErrorKind::AuditForeignTarget.into());
+        }
+    }
+
+    let entries = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        audit::history(&pgconn, kind, target, limit)?
+    };
+
+    if entries.is_empty() {
+        msg.reply(
+            &ctx,
+            &format!("There is no history for {} {}.", kind.as_str(), target),
+        )?;
+        return Ok(());
+    }
+
+    let lines = entries
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n");
+    msg.reply(
+        &ctx,
+        &format!(
+            "The latest changes to {} {}:\n```\n{}\n```",
+            kind.as_str(),
+            target,
+            lines
+        ),
+    )?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Exports the configuration of this server as a file, which can be imported again \
+                 with `importconfig`."]
+#[usage = "[json|toml]"]
+fn exportconfig(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let format = if args.is_empty() {
+        DocumentFormat::Json
+    } else {
+        match args.single::<String>()?.to_lowercase().as_str() {
+            "json" => DocumentFormat::Json,
+            "toml" => DocumentFormat::Toml,
+            _ => return Err(CommandUsageKind::ExportConfigUsage.into()),
+        }
+    };
+
+    let guild = msg.guild(&ctx.cache).failure()?;
+    let document = {
+        let guild = guild.read();
+        GuildConfig::capture(&guild, &ctx.data)?.serialize(format)?
+    };
+    let file_name = format!(
+        "{}-config.{}",
+        msg.guild_id.failure()?.0,
+        format.extension()
+    );
+
+    msg.channel_id.send_files(
+        &ctx.http,
+        vec![(document.as_bytes(), file_name.as_str())],
+        |m| m.content("Here is the configuration of this server."),
+    )?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Shows what importing the attached configuration file would change. \
+Add `apply` to import it. Channels and roles which don't exist in this server are looked up \
+by name."]
+#[usage = "[apply]"]
+fn importconfig(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let apply = !args.is_empty() && args.single::<String>()?.eq_ignore_ascii_case("apply");
+    let attachment = match msg.attachments.first() {
+        Some(s) => s,
+        None => return Err(CommandUsageKind::ImportConfigNoAttachment.into()),
+    };
+    let content = String::from_utf8(attachment.download()?)?;
+    let incoming = GuildConfig::deserialize(
+        &content,
+        DocumentFormat::from_file_name(&attachment.filename),
+    )?;
+    check_import(ctx, msg, &incoming.settings)?;
+
+    let guild = msg.guild(&ctx.cache).failure()?;
+    let (current, incoming, unresolved) = {
+        let guild = guild.read();
+        let current = GuildConfig::capture(&guild, &ctx.data)?;
+        let mut remapper = Remapper::new(&guild);
+        let incoming = incoming.settings.remap(&mut remapper);
+        (current, incoming, remapper.unresolved)
+    };
+    let changes = current.settings.diff(&incoming)?;
+
+    if apply && !changes.is_empty() {
+        incoming.apply(
+            msg.guild_id.failure()?,
+            &ctx.data,
+            Actor::command(msg.author.id.0),
+        )?;
+    }
+
+    let mut reply = if changes.is_empty() {
+        String::from("The configuration is identical to the current one; nothing to import.")
+    } else {
+        format!(
+            "{}\n```diff\n{}\n```",
+            if apply {
+                "Imported the configuration with the following changes:"
+            } else {
+                "Importing would make the following changes; run `a!importconfig apply` with \
+                 the file to import it:"
+            },
+            changes
+                .iter()
+                .map(|c| format!("~ {}", c))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    };
+    if !unresolved.is_empty() {
+        reply.push_str(&format!(
+            "\nThese couldn't be found in this server and were left unset: {}",
+            unresolved.join(", ")
+        ));
+    }
+    msg.reply(&ctx, &reply)?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[description = "Manages the ketos hooks this server runs when events happen in it. \
+Hooks get the name of the event as `event` and the server as `guild-id`, along with \
+`user-id` and `user-name` for `member-join` and `member-leave`, `author-id`, `author-name`, \
+`channel-id`, `message-id` and `content` for `message`, and `user-id`, `channel-id`, \
+`message-id` and `emoji` for `reaction-add`. Whatever they print and return is sent to the \
+channel of the event. Owners of the bot may add `global` to manage hooks run in every server."]
+#[usage = "[global] <list|show|set|remove> [event] [code] or <enable|disable|errors [channel|off]>"]
+fn hook(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild_id.failure()?;
+    if args.is_empty() {
+        return Err(CommandUsageKind::HookUsage.into());
+    }
+    let mut action = args.single::<String>()?.to_lowercase();
+    let global = action == "global";
+    if global {
+        if !is_owner(ctx, msg.author.id) {
+            return Err(PermissionErrorKind::HookGlobalNotOwner.into());
+        }
+        if args.is_empty() {
+            return Err(CommandUsageKind::HookUsage.into());
+        }
+        action = args.single::<String>()?.to_lowercase();
+    } else if !is_owner(ctx, msg.author.id)
+        && !has_guild_permissions(ctx, msg, Permissions::ADMINISTRATOR)
+    {
+        return Err(PermissionErrorKind::HookNotAdministrator.into());
+    }
+    let scope = if global { eventhooks::GLOBAL } else { guild.0 };
+    let actor = Actor::command(msg.author.id.0);
+
+    match (action.as_str(), global) {
+        ("list", _) => {
+            let hooks = {
+                let pgconn = crate::database::connection(&ctx.data)?;
+                eventhooks::list(&pgconn, scope)?
+            };
+            let reply = if hooks.is_empty() {
+                String::from("There are no hooks.")
+            } else {
+                format!(
+                    "Hooks are set for: {}",
+                    hooks
+                        .iter()
+                        .map(|h| format!("`{}`", h.event))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            };
+            msg.reply(&ctx, &reply)?;
+        }
+        ("show", _) => {
+            let event = event_arg(&mut args)?;
+            let hook = {
+                let pgconn = crate::database::connection(&ctx.data)?;
+                eventhooks::find(&pgconn, scope, event)?
+            };
+            let hook = match hook {
+                Some(s) => s,
+                None => return Err(EventHookErrorKind::NotSet(event.to_string()).into()),
+            };
+            let content = format!(
+                "`{}`:\n```lisp\n{}\n```",
+                event,
+                hook.source.replace("```", "`\u{200b}``")
+            );
+            if content.chars().count() <= crate::consts::MESSAGE_LENGTH_LIMIT {
+                msg.channel_id.say(&ctx.http, &content)?;
+            } else {
+                let file_name = format!("{}.lisp", event);
+                msg.channel_id.send_files(
+                    &ctx.http,
+                    vec![(hook.source.as_bytes(), file_name.as_str())],
+                    |m| m.content(&format!("`{}`:", event)),
+                )?;
+            }
+        }
+        ("set", _) => {
+            let event = event_arg(&mut args)?;
+            let rest = args.rest();
+            let code = extract_code(rest).unwrap_or(rest).trim();
+            if code.is_empty() {
+                return Err(CommandUsageKind::HookUsage.into());
+            }
+            {
+                let max_source_bytes = *ctx
+                    .data
+                    .read()
+                    .get::<ConfigurationContainer>()
+                    .failure()?
+                    .custom_commands()
+                    .max_source_bytes();
+                if code.len() > max_source_bytes {
+                    return Err(CustomCommandErrorKind::TooLong(max_source_bytes).into());
+                }
+                let pgconn = crate::database::connection(&ctx.data)?;
+                eventhooks::set(&pgconn, scope, event, code, actor)?;
+            }
+            GuildHooks::evict(scope, &ctx.data)?;
+            msg.reply(&ctx, &format!("Set the hook for `{}`.", event))?;
+        }
+        ("remove", _) => {
+            let event = event_arg(&mut args)?;
+            let removed = {
+                let pgconn = crate::database::connection(&ctx.data)?;
+                eventhooks::remove(&pgconn, scope, event, actor)?
+            };
+            if !removed {
+                return Err(EventHookErrorKind::NotSet(event.to_string()).into());
+            }
+            GuildHooks::evict(scope, &ctx.data)?;
+            msg.reply(&ctx, &format!("Removed the hook for `{}`.", event))?;
+        }
+        ("enable", false) | ("disable", false) => {
+            let enabled = action == "enable";
+            let settings = ServerSettings::new(guild.0, &ctx.data)?;
+            {
+                let mut write = settings.write();
+                write.set_hooks_enabled(enabled, actor);
+                write.save()?;
+            }
+            msg.reply(
+                &ctx,
+                if enabled {
+                    "The hooks of this server are now run."
+                } else {
+                    "The hooks of this server are no longer run."
+                },
+            )?;
+        }
+        ("errors", false) => {
+            let channel = if args.is_empty() {
+                Some(msg.channel_id)
+            } else {
+                let arg = args.single::<String>()?;
+                if arg.eq_ignore_ascii_case("off") {
+                    None
+                } else {
+                    Some(guild_channel(ctx, guild, arg.parse::<ChannelId>()?)?)
+                }
+            };
+            let settings = ServerSettings::new(guild.0, &ctx.data)?;
+            {
+                let mut write = settings.write();
+                write.set_hook_error_channel(channel.map(|c| c.0), actor);
+                write.save()?;
+            }
+            msg.reply(
+                &ctx,
+                &match channel {
+                    Some(channel) => {
+                        format!("Errors of hooks are now reported to {}.", channel.mention())
+                    }
+                    None => String::from("Errors of hooks are no longer reported."),
+                },
+            )?;
+        }
+        _ => return Err(CommandUsageKind::HookUsage.into()),
+    }
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Sets the channel edited and deleted messages are logged to, or stops logging \
+                 them with `off`. Messages of ignored channels aren't logged."]
+#[usage = "<channel|off> or <ignore|unignore> <channel>"]
+fn messagelog(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild_id.failure()?;
+    if args.is_empty() {
+        return Err(CommandUsageKind::MessageLogUsage.into());
+    }
+    let arg = args.single::<String>()?.to_lowercase();
+    let actor = Actor::command(msg.author.id.0);
+    let settings = ServerSettings::new(guild.0, &ctx.data)?;
+
+    let reply = match arg.as_str() {
+        "ignore" | "unignore" => {
+            let channel = args.single::<ChannelId>()?;
+            // Channels which were deleted can still be unignored
+            if arg == "ignore" {
+                guild_channel(ctx, guild, channel)?;
+            }
+            let mut write = settings.write();
+            let mut ignored = write.message_log_ignored().clone();
+            if arg == "ignore" {
+                if !ignored.contains(&channel.0) {
+                    ignored.push(channel.0);
+                }
+            } else {
+                ignored.retain(|&c| c != channel.0);
+            }
+            write.set_message_log_ignored(ignored, actor);
+            write.save()?;
+            if arg == "ignore" {
+                format!("Messages in {} are no longer logged.", channel.mention())
+            } else {
+                format!("Messages in {} are logged again.", channel.mention())
+            }
+        }
+        "off" => {
+            let mut write = settings.write();
+            write.set_message_log_channel(None, actor);
+            write.save()?;
+            String::from("Edited and deleted messages are no longer logged.")
+        }
+        _ => {
+            let channel = arg
+                .parse::<ChannelId>()
+                .map_err(|_| CommandUsageKind::MessageLogUsage)?;
+            let channel = guild_channel(ctx, guild, channel)?;
+            let mut write = settings.write();
+            write.set_message_log_channel(Some(channel.0), actor);
+            write.save()?;
+            format!(
+                "Edited and deleted messages are now logged to {}.",
+                channel.mention()
+            )
+        }
+    };
+    msg.reply(&ctx, &reply)?;
+
+    Ok(())
+}
+
+fn event_arg(args: &mut Args) -> std::result::Result<HookEvent, CommandError> {
+    parse_event(&args.single::<String>()?)
+}
+
+fn parse_event(name: &str) -> std::result::Result<HookEvent, CommandError> {
+    name.parse().map_err(|()| {
+        let events = HookEvent::ALL
+            .iter()
+            .map(|e| format!("`{}`", e))
+            .collect::<Vec<_>>()
+            .join(", ");
+        EventHookErrorKind::UnknownEvent(name.to_owned(), events).into()
+    })
+}
@@ -0,0 +1,165 @@
+use super::prelude::*;
+use crate::{
+    antiraid,
+    data::{
+        antiraid::{self as protection, RaidProtection, RaidResponse},
+        audit::{self, PendingChange},
+        moderation, ConfigurationContainer,
+    },
+};
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Manages how the server is protected from raids. A raid is many members joining \
+at once, or several new accounts or accounts with the same avatar or name. Once one is detected \
+the server is alerted and, depending on the response, locked down, has its verification level \
+raised or kicks the raiders, until the raid is ended with `antiraid end`."]
+#[usage = "[status], <on|off|start|end>, joins <amount> <seconds>, similar <amount>, \
+           accountage <duration|off> or response <alert|lockdown|verification|kick>"]
+fn antiraid(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild_id.failure()?;
+    let actor = Actor::command(msg.author.id.0);
+    let action = if args.is_empty() {
+        String::from("status")
+    } else {
+        args.single::<String>()?.to_lowercase()
+    };
+    let mut protection = RaidProtection::new(guild.0, &ctx.data)?.read().clone();
+
+    let change = match action.as_str() {
+        "status" => {
+            msg.reply(&ctx, &status(&protection))?;
+            return Ok(());
+        }
+        "start" => {
+            let reason = format!("started by {}", msg.author.tag());
+            antiraid::start(ctx, guild, &reason, &[], actor)?;
+            return Ok(());
+        }
+        "end" => {
+            antiraid::end(ctx, guild, actor)?;
+            return Ok(());
+        }
+        "on" | "off" => {
+            let enabled = action == "on";
+            let change = PendingChange::new(actor, "raid_protection", protection.enabled, enabled);
+            protection.enabled = enabled;
+            change
+        }
+        "joins" => {
+            let amount = args.single::<u32>()?.max(2) as i32;
+            let seconds = args.single::<u64>()?.max(1);
+            let max = *ctx
+                .data
+                .read()
+                .get::<ConfigurationContainer>()
+                .failure()?
+                .antiraid()
+                .max_join_window_seconds();
+            if seconds > max {
+                return Err(AntiraidErrorKind::WindowTooLong(max).into());
+            }
+            let change = PendingChange::new(
+                actor,
+                "raid_join_limit",
+                format!(
+                    "{} in {}s",
+                    protection.join_limit, protection.join_window_seconds
+                ),
+                format!("{} in {}s", amount, seconds),
+            );
+            protection.join_limit = amount;
+            protection.join_window_seconds = seconds as i32;
+            change
+        }
+        "similar" => {
+            let amount = args.single::<u32>()?.max(2) as i32;
+            let change = PendingChange::new(
+                actor,
+                "raid_similar_limit",
+                protection.similar_limit,
+                amount,
+            );
+            protection.similar_limit = amount;
+            change
+        }
+        "accountage" => {
+            let arg = args.single::<String>()?;
+            let age = if arg.eq_ignore_ascii_case("off") {
+                None
+            } else {
+                let age =
+                    moderation::parse_duration(&arg).ok_or(CommandUsageKind::AntiraidUsage)?;
+                Some(age.as_secs() as i64)
+            };
+            let change = PendingChange::new(
+                actor,
+                "raid_min_account_age_seconds",
+                audit::optional(&protection.min_account_age_seconds),
+                audit::optional(&age),
+            );
+            protection.min_account_age_seconds = age;
+            change
+        }
+        "response" => {
+            let response = parse_response(&args.single::<String>()?)?;
+            let change =
+                PendingChange::new(actor, "raid_response", protection.response(), response);
+            protection.response = response.as_str().to_owned();
+            change
+        }
+        _ => return Err(CommandUsageKind::AntiraidUsage.into()),
+    };
+
+    {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        protection::save(&pgconn, &protection, &[change])?;
+    }
+    RaidProtection::evict(guild.0, &ctx.data)?;
+    msg.reply(&ctx, &status(&protection))?;
+
+    Ok(())
+}
+
+pub fn parse_response(name: &str) -> std::result::Result<RaidResponse, CommandError> {
+    name.parse().map_err(|()| {
+        let responses = RaidResponse::ALL
+            .iter()
+            .map(|r| format!("`{}`", r))
+            .collect::<Vec<_>>()
+            .join(", ");
+        AntiraidErrorKind::UnknownResponse(name.to_owned(), responses).into()
+    })
+}
+
+/// Describes how the server is protected from raids.
+fn status(protection: &RaidProtection) -> String {
+    let mut status = if protection.enabled {
+        let new_accounts = match protection.min_account_age() {
+            Some(age) => format!(
+                "accounts younger than {} or ",
+                moderation::format_duration(age)
+            ),
+            None => String::new(),
+        };
+        format!(
+            "Raid protection is on. A raid is {} members joining within {} seconds, or {} \
+             {}members with the same avatar or name. The response is `{}`.",
+            protection.join_limit,
+            protection.join_window_seconds,
+            protection.similar_limit,
+            new_accounts,
+            protection.response(),
+        )
+    } else {
+        String::from("Raid protection is off.")
+    };
+    if let Some(started) = protection.raid_started_at {
+        status.push_str(&format!(
+            "\nThe server has been in raid mode since {}.",
+            started.format("%Y-%m-%d %H:%M:%S UTC")
+        ));
+    }
+    status
+}
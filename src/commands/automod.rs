@@ -0,0 +1,266 @@
+use super::prelude::*;
+use crate::data::{
+    automod::{self, AutomodRule, GuildRules, RuleAction, RuleKind},
+    moderation, ConfigurationContainer,
+};
+use serenity::utils::{parse_channel, parse_role};
+
+/// What a rule is exempted for.
+#[derive(Clone, Copy)]
+enum Exemption {
+    Role(RoleId),
+    Channel(ChannelId),
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Manages the rules messages are automatically moderated by: `words`, `invites`, \
+`mentions`, `caps`, `zalgo` and `spam`. Messages breaking a rule are deleted, and their authors \
+are warned, timed out or kicked if the rule says so."]
+#[usage = "[list], <set|remove> <rule> [action] [limit] [duration], \
+           words <add|remove> <word|/regex/> or <exempt|unexempt> <rule> <role|channel>"]
+fn automod(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild_id.failure()?;
+    let actor = Actor::command(msg.author.id.0);
+    let action = if args.is_empty() {
+        String::from("list")
+    } else {
+        args.single::<String>()?.to_lowercase()
+    };
+
+    let reply = match action.as_str() {
+        "list" => {
+            let rules = {
+                let pgconn = crate::database::connection(&ctx.data)?;
+                automod::list(&pgconn, guild.0)?
+            };
+            if rules.is_empty() {
+                String::from("This server has no automod rules.")
+            } else {
+                format!(
+                    "The automod rules of this server:\n{}",
+                    rules.iter().map(describe).collect::<Vec<_>>().join("\n")
+                )
+            }
+        }
+        "set" => {
+            let kind = rule_arg(&mut args)?;
+            let rule_action = if args.is_empty() {
+                RuleAction::Delete
+            } else {
+                action_arg(&mut args)?
+            };
+            let mut threshold = None;
+            let mut timeout = None;
+            while !args.is_empty() {
+                let arg = args.single::<String>()?;
+                if let Some(duration) = moderation::parse_duration(&arg) {
+                    timeout = Some(duration.as_secs() as i64);
+                } else if let Ok(limit) = arg.parse::<i32>() {
+                    threshold = Some(limit.max(1));
+                } else {
+                    return Err(CommandUsageKind::AutomodUsage.into());
+                }
+            }
+
+            {
+                let pgconn = crate::database::connection(&ctx.data)?;
+                let mut rule = automod::find(&pgconn, guild.0, kind)?
+                    .unwrap_or_else(|| AutomodRule::new(guild.0, kind));
+                rule.action = rule_action.as_str().to_owned();
+                rule.threshold = threshold;
+                rule.timeout_seconds = timeout;
+                automod::save(&pgconn, &rule, actor)?;
+            }
+            GuildRules::evict(guild.0, &ctx.data)?;
+            format!(
+                "Messages breaking the `{}` rule are now handled with `{}`.",
+                kind, rule_action
+            )
+        }
+        "remove" => {
+            let kind = rule_arg(&mut args)?;
+            let removed = {
+                let pgconn = crate::database::connection(&ctx.data)?;
+                automod::remove(&pgconn, guild.0, kind, actor)?
+            };
+            if !removed {
+                return Err(AutomodErrorKind::NotSet(kind.to_string()).into());
+            }
+            GuildRules::evict(guild.0, &ctx.data)?;
+            format!("Removed the `{}` rule.", kind)
+        }
+        "words" => {
+            let add = match args.single::<String>()?.to_lowercase().as_str() {
+                "add" => true,
+                "remove" => false,
+                _ => return Err(CommandUsageKind::AutomodUsage.into()),
+            };
+            let pattern = args.rest().trim().to_owned();
+            if pattern.is_empty() {
+                return Err(CommandUsageKind::AutomodUsage.into());
+            }
+
+            {
+                let (max_bytes, max_patterns) = {
+                    let data = ctx.data.read();
+                    let config = data.get::<ConfigurationContainer>().failure()?.automod();
+                    (*config.max_pattern_bytes(), *config.max_patterns())
+                };
+                let pgconn = crate::database::connection(&ctx.data)?;
+                let mut rule = match automod::find(&pgconn, guild.0, RuleKind::Words)? {
+                    Some(s) => s,
+                    None => return Err(AutomodErrorKind::NotSet(String::from("words")).into()),
+                };
+                if add {
+                    check_pattern(&pattern, max_bytes)?;
+                    if rule.patterns.len() >= max_patterns {
+                        return Err(AutomodErrorKind::TooManyPatterns(max_patterns).into());
+                    }
+                    if !rule.patterns.contains(&pattern) {
+                        rule.patterns.push(pattern.clone());
+                    }
+                } else {
+                    rule.patterns.retain(|p| *p != pattern);
+                }
+                automod::save(&pgconn, &rule, actor)?;
+            }
+            GuildRules::evict(guild.0, &ctx.data)?;
+            if add {
+                format!("`{}` is now banned.", pattern)
+            } else {
+                format!("`{}` is no longer banned.", pattern)
+            }
+        }
+        "exempt" | "unexempt" => {
+            let exempt = action == "exempt";
+            let kind = rule_arg(&mut args)?;
+            let exemption = exemption_arg(ctx, guild, &args.single::<String>()?)?;
+
+            {
+                let pgconn = crate::database::connection(&ctx.data)?;
+                let mut rule = match automod::find(&pgconn, guild.0, kind)? {
+                    Some(s) => s,
+                    None => return Err(AutomodErrorKind::NotSet(kind.to_string()).into()),
+                };
+                let (list, id) = match exemption {
+                    Exemption::Role(role) => (&mut rule.exempt_roles, role.0 as i64),
+                    Exemption::Channel(channel) => (&mut rule.exempt_channels, channel.0 as i64),
+                };
+                list.retain(|&e| e != id);
+                if exempt {
+                    list.push(id);
+                }
+                automod::save(&pgconn, &rule, actor)?;
+            }
+            GuildRules::evict(guild.0, &ctx.data)?;
+            let target = match exemption {
+                Exemption::Role(role) => role.mention(),
+                Exemption::Channel(channel) => channel.mention(),
+            };
+            if exempt {
+                format!("The `{}` rule no longer applies to {}.", kind, target)
+            } else {
+                format!("The `{}` rule applies to {} again.", kind, target)
+            }
+        }
+        _ => return Err(CommandUsageKind::AutomodUsage.into()),
+    };
+    msg.reply(&ctx, &reply)?;
+
+    Ok(())
+}
+
+/// A line describing a rule for `automod list`.
+fn describe(rule: &AutomodRule) -> String {
+    let mut parts = vec![format!("`{}`: {}", rule.kind, rule.action)];
+    if let Some(limit) = rule.effective_threshold() {
+        parts.push(format!("limit {}", limit));
+    }
+    if let Some(seconds) = rule.timeout_seconds {
+        parts.push(format!(
+            "for {}",
+            moderation::format_duration(std::time::Duration::from_secs(seconds as u64))
+        ));
+    }
+    if !rule.patterns.is_empty() {
+        parts.push(format!("{} banned words and patterns", rule.patterns.len()));
+    }
+    let exempt = rule
+        .exempt_roles
+        .iter()
+        .map(|&r| RoleId(r as u64).mention())
+        .chain(
+            rule.exempt_channels
+                .iter()
+                .map(|&c| ChannelId(c as u64).mention()),
+        )
+        .collect::<Vec<_>>();
+    if !exempt.is_empty() {
+        parts.push(format!("except for {}", exempt.join(", ")));
+    }
+    parts.join(", ")
+}
+
+fn rule_arg(args: &mut Args) -> std::result::Result<RuleKind, CommandError> {
+    parse_rule(&args.single::<String>()?)
+}
+
+pub fn parse_rule(name: &str) -> std::result::Result<RuleKind, CommandError> {
+    name.parse().map_err(|()| {
+        let rules = RuleKind::ALL
+            .iter()
+            .map(|k| format!("`{}`", k))
+            .collect::<Vec<_>>()
+            .join(", ");
+        AutomodErrorKind::UnknownRule(name.to_owned(), rules).into()
+    })
+}
+
+fn action_arg(args: &mut Args) -> std::result::Result<RuleAction, CommandError> {
+    parse_action(&args.single::<String>()?)
+}
+
+pub fn parse_action(name: &str) -> std::result::Result<RuleAction, CommandError> {
+    name.parse().map_err(|()| {
+        let actions = RuleAction::ALL
+            .iter()
+            .map(|a| format!("`{}`", a))
+            .collect::<Vec<_>>()
+            .join(", ");
+        AutomodErrorKind::UnknownAction(name.to_owned(), actions).into()
+    })
+}
+
+/// Checks whether a banned word or pattern may be added.
+pub fn check_pattern(pattern: &str, max_bytes: usize) -> std::result::Result<(), CommandError> {
+    if pattern.len() > max_bytes {
+        return Err(AutomodErrorKind::PatternTooLong(max_bytes).into());
+    }
+    if let Err(e) = automod::compile_pattern(pattern) {
+        return Err(AutomodErrorKind::InvalidPattern(pattern.to_owned(), e.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Parses a role or channel of the server, by mention or ID.
+fn exemption_arg(ctx: &Context, guild: GuildId, arg: &str) -> Result<Exemption> {
+    if let Some(role) = parse_role(arg) {
+        return Ok(Exemption::Role(RoleId(role)));
+    }
+    if let Some(channel) = parse_channel(arg) {
+        return Ok(Exemption::Channel(ChannelId(channel)));
+    }
+
+    let id = arg.parse::<u64>().ok();
+    let guild = guild.to_guild_cached(&ctx.cache).failure()?;
+    let guild = guild.read();
+    match id {
+        Some(id) if guild.roles.contains_key(&RoleId(id)) => Ok(Exemption::Role(RoleId(id))),
+        Some(id) if guild.channels.contains_key(&ChannelId(id)) => {
+            Ok(Exemption::Channel(ChannelId(id)))
+        }
+        _ => Err(AutomodErrorKind::UnknownExemption(arg.to_owned()).into()),
+    }
+}
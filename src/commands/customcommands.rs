@@ -0,0 +1,193 @@
+use super::prelude::*;
+use crate::{
+    data::{customcommands, ConfigurationContainer},
+    scripting::owner::extract_code,
+};
+
+/// Checks the name and code of a command before it is saved, returning the name
+/// in lowercase.
+fn validate(ctx: &Context, args: &mut Args) -> std::result::Result<(String, String), CommandError> {
+    let name = args.single::<String>()?.to_lowercase();
+    let rest = args.rest();
+    let code = extract_code(rest).unwrap_or(rest).trim();
+    if code.is_empty() {
+        return Err(CommandUsageKind::CustomCommandUsage.into());
+    }
+
+    check_command_name(&name)?;
+
+    let max_source_bytes = *ctx
+        .data
+        .read()
+        .get::<ConfigurationContainer>()
+        .failure()?
+        .custom_commands()
+        .max_source_bytes();
+    if code.len() > max_source_bytes {
+        return Err(CustomCommandErrorKind::TooLong(max_source_bytes).into());
+    }
+
+    Ok((name, code.to_owned()))
+}
+
+/// Checks whether a command may be named so, which has to be in lowercase.
+pub fn check_command_name(name: &str) -> std::result::Result<(), CommandError> {
+    let valid = name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        return Err(CustomCommandErrorKind::InvalidName.into());
+    }
+    if super::is_builtin(name) {
+        return Err(CustomCommandErrorKind::Builtin(name.to_owned()).into());
+    }
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Creates a command for this server, which runs the given ketos code. \
+The code gets the arguments as `args`, along with `author-id`, `author-name`, `channel-id` \
+and `guild-id`. Whatever it prints and the value it returns are sent as the reply."]
+#[usage = "<name> <code>"]
+fn create(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let (name, code) = validate(ctx, &mut args)?;
+    let guild = msg.guild_id.failure()?;
+
+    {
+        let max_per_guild = *ctx
+            .data
+            .read()
+            .get::<ConfigurationContainer>()
+            .failure()?
+            .custom_commands()
+            .max_per_guild();
+        let pgconn = crate::database::connection(&ctx.data)?;
+        if customcommands::find(&pgconn, guild.0, &name)?.is_some() {
+            return Err(CustomCommandErrorKind::AlreadyExists(name).into());
+        }
+        if customcommands::count(&pgconn, guild.0)? as usize >= max_per_guild {
+            return Err(CustomCommandErrorKind::TooMany(max_per_guild).into());
+        }
+        customcommands::create(
+            &pgconn,
+            guild.0,
+            &name,
+            &code,
+            Actor::command(msg.author.id.0),
+        )?;
+    }
+
+    msg.reply(&ctx, &format!("Created the command `{}`.", name))?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Replaces the code of a command of this server."]
+#[usage = "<name> <code>"]
+fn edit(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let (name, code) = validate(ctx, &mut args)?;
+    let guild = msg.guild_id.failure()?;
+
+    let edited = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        customcommands::edit(
+            &pgconn,
+            guild.0,
+            &name,
+            &code,
+            Actor::command(msg.author.id.0),
+        )?
+    };
+    if !edited {
+        return Err(CustomCommandErrorKind::NotFound(name).into());
+    }
+
+    msg.reply(&ctx, &format!("Edited the command `{}`.", name))?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Deletes a command of this server."]
+#[usage = "<name>"]
+fn delete(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let name = args.single::<String>()?.to_lowercase();
+    let guild = msg.guild_id.failure()?;
+
+    let deleted = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        customcommands::delete(&pgconn, guild.0, &name, Actor::command(msg.author.id.0))?
+    };
+    if !deleted {
+        return Err(CustomCommandErrorKind::NotFound(name).into());
+    }
+
+    msg.reply(&ctx, &format!("Deleted the command `{}`.", name))?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[description = "Lists the commands of this server."]
+fn list(ctx: &mut Context, msg: &Message) -> CommandResult {
+    let guild = msg.guild_id.failure()?;
+    let commands = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        customcommands::list(&pgconn, guild.0)?
+    };
+
+    if commands.is_empty() {
+        msg.reply(&ctx, "This server has no commands of its own.")?;
+        return Ok(());
+    }
+
+    let names = commands
+        .iter()
+        .map(|c| format!("`{}`", c.name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    msg.reply(&ctx, &format!("The commands of this server: {}", names))?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[description = "Shows the code of a command of this server."]
+#[usage = "<name>"]
+fn show(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let name = args.single::<String>()?.to_lowercase();
+    let guild = msg.guild_id.failure()?;
+    let command = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        customcommands::find(&pgconn, guild.0, &name)?
+    };
+    let command = match command {
+        Some(s) => s,
+        None => return Err(CustomCommandErrorKind::NotFound(name).into()),
+    };
+
+    let source = command.source.replace("```", "`\u{200b}``");
+    let content = format!("`{}`:\n```lisp\n{}\n```", command.name, source);
+    if content.chars().count() <= crate::consts::MESSAGE_LENGTH_LIMIT {
+        msg.channel_id.say(&ctx.http, &content)?;
+    } else {
+        let file_name = format!("{}.lisp", command.name);
+        msg.channel_id.send_files(
+            &ctx.http,
+            vec![(command.source.as_bytes(), file_name.as_str())],
+            |m| m.content(&format!("`{}`:", command.name)),
+        )?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use super::prelude::*;
+use crate::{
+    data::greetings::{self, Greeting, GreetingKind, GuildGreetings, TEMPLATE_LENGTH_LIMIT},
+    greetings::{self as greeter, PLACEHOLDERS},
+};
+use chrono::Utc;
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Manages the message sent when a member joins the server. Templates may contain \
+`{user}`, `{name}`, `{tag}`, `{id}`, `{server}`, `{count}` and `{age}`, which are replaced with \
+what they stand for. The message is sent to the channel it was set in, or to the member with \
+`channel dm`."]
+#[usage = "[show|test|off], set <template>, channel <channel|dm> or embed <on|off>"]
+fn welcome(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    greeting(ctx, msg, args, GreetingKind::Welcome)
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Manages the message sent when a member leaves the server. Templates may contain \
+`{user}`, `{name}`, `{tag}`, `{id}`, `{server}`, `{count}` and `{age}`, which are replaced with \
+what they stand for. The message is sent to the channel it was set in."]
+#[usage = "[show|test|off], set <template>, channel <channel> or embed <on|off>"]
+fn farewell(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    greeting(ctx, msg, args, GreetingKind::Farewell)
+}
+
+/// Runs the `welcome` and `farewell` commands, which only differ in their kind.
+fn greeting(ctx: &mut Context, msg: &Message, mut args: Args, kind: GreetingKind) -> CommandResult {
+    let guild = msg.guild_id.failure()?;
+    let actor = Actor::command(msg.author.id.0);
+    let action = if args.is_empty() {
+        String::from("show")
+    } else {
+        args.single::<String>()?.to_lowercase()
+    };
+    let usage = || CommandUsageKind::GreetingUsage(kind.to_string());
+
+    let current = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        greetings::find(&pgconn, guild.0, kind)?
+    };
+    let not_set = || GreetingErrorKind::NotSet(kind.to_string());
+
+    let reply = match action.as_str() {
+        "show" => match current {
+            Some(s) => format!("{}\n```\n{}\n```", describe(&s), s.template),
+            None => format!("This server has no {} message.", kind),
+        },
+        "test" => {
+            if !greeter::send(ctx, guild, &msg.author, kind)? {
+                return Err(not_set().into());
+            }
+            return Ok(());
+        }
+        "off" => {
+            let removed = {
+                let pgconn = crate::database::connection(&ctx.data)?;
+                greetings::remove(&pgconn, guild.0, kind, actor)?
+            };
+            if !removed {
+                return Err(not_set().into());
+            }
+            GuildGreetings::evict(guild.0, &ctx.data)?;
+            format!("This server no longer has a {} message.", kind)
+        }
+        "set" => {
+            let template = args.rest().trim().to_owned();
+            if template.is_empty() {
+                return Err(usage().into());
+            }
+            if template.chars().count() > TEMPLATE_LENGTH_LIMIT {
+                return Err(GreetingErrorKind::TooLong(TEMPLATE_LENGTH_LIMIT).into());
+            }
+            // Catches mistakes now rather than once a member joins
+            greeter::render(&template, &greeter::values(ctx, guild, &msg.author))?;
+            let greeting = match current {
+                Some(s) => Greeting { template, ..s },
+                None => Greeting {
+                    guild_id: guild.0 as i64,
+                    kind: kind.as_str().to_owned(),
+                    channel_id: Some(msg.channel_id.0 as i64),
+                    template,
+                    embed: false,
+                    author_id: None,
+                    updated_at: Utc::now(),
+                },
+            };
+            save(ctx, &greeting, actor)?
+        }
+        "channel" => {
+            let mut greeting = current.ok_or_else(not_set)?;
+            let arg = args.single::<String>()?;
+            greeting.channel_id = if arg.eq_ignore_ascii_case("dm") {
+                if kind == GreetingKind::Farewell {
+                    return Err(GreetingErrorKind::FarewellDirectMessage.into());
+                }
+                None
+            } else {
+                let channel = arg.parse::<ChannelId>().map_err(|_| usage())?;
+                Some(guild_channel(ctx, guild, channel)?.0 as i64)
+            };
+            save(ctx, &greeting, actor)?
+        }
+        "embed" => {
+            let mut greeting = current.ok_or_else(not_set)?;
+            greeting.embed = match args.single::<String>()?.to_lowercase().as_str() {
+                "on" => true,
+                "off" => false,
+                _ => return Err(usage().into()),
+            };
+            save(ctx, &greeting, actor)?
+        }
+        _ => return Err(usage().into()),
+    };
+    msg.reply(&ctx, &reply)?;
+
+    Ok(())
+}
+
+/// Saves a greeting, returning a description of it to reply with.
+fn save(ctx: &Context, greeting: &Greeting, actor: Actor) -> Result<String> {
+    {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        greetings::save(&pgconn, greeting, actor)?;
+    }
+    GuildGreetings::evict(greeting.guild_id as u64, &ctx.data)?;
+    Ok(describe(greeting))
+}
+
+/// Describes where and how a greeting is sent.
+fn describe(greeting: &Greeting) -> String {
+    let destination = match greeting.channel_id {
+        Some(s) => format!("to {}", ChannelId(s as u64).mention()),
+        None => String::from("to the member directly"),
+    };
+    let placeholders = PLACEHOLDERS
+        .iter()
+        .map(|(p, d)| format!("`{{{}}}`: {}", p, d))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "The {} message is sent {}{}. Its placeholders are:\n{}",
+        greeting.kind,
+        destination,
+        if greeting.embed { " as an embed" } else { "" },
+        placeholders
+    )
+}
@@ -1,6 +1,7 @@
 pub(crate) mod prelude {
     pub use super::super::prelude::*;
-    pub use crate::data::{ServerSettings, UserSettings};
+    pub use super::permissions::{guild_channel, has_guild_permissions, is_owner};
+    pub use crate::data::{audit::Actor, ServerSettings, UserSettings};
     pub use parking_lot::Mutex;
     pub use serenity::{
         client::bridge::gateway::ShardManager,
@@ -14,11 +15,42 @@ pub(crate) mod prelude {
     pub use std::sync::Arc;
 }
 
+pub mod administration;
+pub mod antiraid;
+pub mod automod;
+pub mod customcommands;
+pub mod greetings;
 pub mod help;
 pub mod miscellaneous;
+pub mod moderation;
+pub mod onboarding;
 pub mod owner;
+pub mod permissions;
+pub mod privacy;
 use self::prelude::*;
-use self::{miscellaneous::*, owner::*};
+use self::{
+    administration::*, antiraid::*, automod::*, customcommands::*, greetings::*, miscellaneous::*,
+    moderation::*, onboarding::*, owner::*, privacy::*,
+};
+
+group!({
+    name: "Administration",
+    options: {
+        description: "Commands for managing how the bot behaves in a server are located here.",
+    },
+    commands: [
+        audit, exportconfig, importconfig, hook, messagelog, welcome, farewell, autorole, gate
+    ],
+});
+
+group!({
+    name: "CustomCommands",
+    options: {
+        prefixes: ["cc"],
+        description: "Commands for managing the commands a server defines for itself are located here.",
+    },
+    commands: [create, edit, delete, list, show],
+});
 
 group!({
     name: "Developer",
@@ -26,7 +58,7 @@ group!({
         owner_only: true,
         description: "Commands only available for the developer of the bot are located here.",
     },
-    commands: [quit, evaluate, blacklist],
+    commands: [quit, evaluate, discard, session, blacklist, cache],
 });
 
 group!({
@@ -34,5 +66,54 @@ group!({
     options: {
         description: "All miscellaneous commands which did not fit in any other group are located here.",
     },
-    commands: [ping],
+    commands: [ping, verify],
+});
+
+group!({
+    name: "Moderation",
+    options: {
+        description: "Commands for moderating the members of a server are located here.",
+    },
+    commands: [
+        kick, ban, softban, unban, mute, unmute, warn, case, reason, cases, muterole, modlog,
+        automod, antiraid
+    ],
 });
+
+group!({
+    name: "Privacy",
+    options: {
+        description: "Commands for managing the data the bot stores about you are located here.",
+    },
+    commands: [mydata, forgetme],
+});
+
+/// Whether a name is taken by a built-in command or group prefix, which always
+/// take precedence over the commands servers define.
+pub fn is_builtin(name: &str) -> bool {
+    let groups = [
+        &ADMINISTRATION_GROUP,
+        &CUSTOMCOMMANDS_GROUP,
+        &DEVELOPER_GROUP,
+        &MISCELLANEOUS_GROUP,
+        &MODERATION_GROUP,
+        &PRIVACY_GROUP,
+    ];
+    name.eq_ignore_ascii_case("help")
+        || groups.iter().any(|group| {
+            if group.options.prefixes.is_empty() {
+                group
+                    .options
+                    .commands
+                    .iter()
+                    .flat_map(|c| c.options.names.iter())
+                    .any(|n| n.eq_ignore_ascii_case(name))
+            } else {
+                group
+                    .options
+                    .prefixes
+                    .iter()
+                    .any(|p| p.eq_ignore_ascii_case(name))
+            }
+        })
+}
@@ -0,0 +1,537 @@
+use super::prelude::*;
+use crate::{
+    data::{
+        moderation::{self, Case, CaseAction},
+        scheduledjobs::{self, JobKind},
+    },
+    modlog,
+};
+use chrono::{DateTime, Utc};
+use diesel::Connection;
+use std::time::Duration;
+
+/// The longest reason Discord keeps in the audit log of a server.
+const AUDIT_LOG_REASON_LIMIT: usize = 512;
+
+/// Checks whether the author of the message may take action against the target.
+///
+/// Nobody may target themselves, the bot or the owner of the server, and only
+/// the owner or members whose highest role is above that of the target may
+/// target other members.
+fn check_target(ctx: &Context, msg: &Message, target: UserId) -> Result<()> {
+    let guild = msg.guild(&ctx.cache).failure()?;
+    let guild = guild.read();
+
+    if target == msg.author.id {
+        return Err(ModerationErrorKind::SelfTarget.into());
+    }
+    if target == ctx.cache.read().user.id {
+        return Err(ModerationErrorKind::BotTarget.into());
+    }
+    if target == guild.owner_id {
+        return Err(ModerationErrorKind::OwnerTarget.into());
+    }
+    if msg.author.id == guild.owner_id {
+        return Ok(());
+    }
+
+    let position = |user: UserId| {
+        guild
+            .members
+            .get(&user)
+            .and_then(|m| {
+                m.roles
+                    .iter()
+                    .filter_map(|r| guild.roles.get(r))
+                    .map(|r| r.position)
+                    .max()
+            })
+            .unwrap_or(0)
+    };
+    if position(msg.author.id) <= position(target) {
+        return Err(ModerationErrorKind::Hierarchy.into());
+    }
+    Ok(())
+}
+
+/// Takes an optional duration off the front of the arguments.
+fn duration_arg(args: &mut Args) -> Option<Duration> {
+    let duration = args.current().and_then(moderation::parse_duration);
+    if duration.is_some() {
+        args.advance();
+    }
+    duration
+}
+
+/// When an action taken for the duration should be undone, if it is temporary.
+fn lift_at(duration: Option<Duration>) -> Result<Option<DateTime<Utc>>> {
+    match duration {
+        Some(d) => match scheduledjobs::due_in(d) {
+            Some(at) => Ok(Some(at)),
+            None => Err(ModerationErrorKind::DurationTooLong.into()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Schedules the job which undoes a temporary action, or cancels any pending
+/// one if the action is permanent or was undone by hand.
+fn schedule_lift(
+    conn: &PgConnection,
+    job: JobKind,
+    guild: GuildId,
+    target: UserId,
+    case: &Case,
+    at: Option<DateTime<Utc>>,
+) -> Result<()> {
+    match at {
+        Some(at) => {
+            scheduledjobs::schedule(conn, job, guild.0, target.0, Some(case.case_number), at)?
+        }
+        None => {
+            scheduledjobs::cancel(conn, job, guild.0, target.0)?;
+        }
+    }
+    Ok(())
+}
+
+/// Takes the rest of the arguments as the reason, if there is one.
+fn reason_arg(args: &Args) -> Option<String> {
+    let reason = args.rest().trim();
+    if reason.is_empty() {
+        None
+    } else {
+        Some(reason.to_owned())
+    }
+}
+
+/// The reason to put in the audit log of the server.
+fn audit_log_reason(msg: &Message, reason: &Option<String>) -> String {
+    let reason = format!(
+        "{}: {}",
+        msg.author.tag(),
+        reason
+            .as_ref()
+            .map(String::as_str)
+            .unwrap_or("no reason given")
+    );
+    reason.chars().take(AUDIT_LOG_REASON_LIMIT).collect()
+}
+
+/// Opens a case for an action, together with scheduling or cancelling the job
+/// which undoes it if one is given.
+fn open_case(
+    ctx: &Context,
+    msg: &Message,
+    action: CaseAction,
+    target: UserId,
+    reason: &Option<String>,
+    duration: Option<Duration>,
+    lift: Option<(JobKind, Option<DateTime<Utc>>)>,
+) -> Result<Case> {
+    let guild = msg.guild_id.failure()?;
+    let pgconn = crate::database::connection(&ctx.data)?;
+    pgconn.transaction::<_, failure::Error, _>(|| {
+        let case = moderation::open(
+            &pgconn,
+            guild.0,
+            action,
+            target.0,
+            Some(msg.author.id.0),
+            reason.as_ref().map(String::as_str),
+            duration,
+        )?;
+        if let Some((job, at)) = lift {
+            schedule_lift(&pgconn, job, guild, target, &case, at)?;
+        }
+        Ok(case)
+    })
+}
+
+/// Deletes a case opened for an action which then couldn't be taken.
+fn withdraw_case(ctx: &Context, case: &Case) -> Result<()> {
+    let pgconn = crate::database::connection(&ctx.data)?;
+    moderation::withdraw(&pgconn, case)?;
+    Ok(())
+}
+
+/// Confirms a case of an action which was taken and posts it to the modlog.
+fn announce_case(ctx: &Context, msg: &Message, case: &Case) -> Result<()> {
+    msg.reply(
+        &ctx,
+        &format!(
+            "Case #{}: {} {}{}.",
+            case.case_number,
+            case.action,
+            UserId(case.target_id as u64).mention(),
+            match case.duration() {
+                Some(d) => format!(" for {}", moderation::format_duration(d)),
+                None => String::new(),
+            },
+        ),
+    )?;
+    modlog::post(&ctx.http, &ctx.data, case);
+    Ok(())
+}
+
+/// The role muted members get in the server.
+fn mute_role(ctx: &Context, guild: GuildId) -> Result<RoleId> {
+    let settings = ServerSettings::new(guild.0, &ctx.data)?;
+    let role = *settings.read().mute_role();
+    match role {
+        Some(role) => Ok(RoleId(role)),
+        None => Err(ModerationErrorKind::NoMuteRole.into()),
+    }
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(KICK_MEMBERS)]
+#[description = "Kicks a member from the server."]
+#[usage = "<user> [reason]"]
+fn kick(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let target = args.single::<UserId>()?;
+    let reason = reason_arg(&args);
+    check_target(ctx, msg, target)?;
+
+    msg.guild_id.failure()?.kick(&ctx.http, target)?;
+    let case = open_case(ctx, msg, CaseAction::Kick, target, &reason, None, None)?;
+    announce_case(ctx, msg, &case)?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(BAN_MEMBERS)]
+#[description = "Bans a user from the server, optionally for a while, e.g. `7d` or `12h`."]
+#[usage = "<user> [duration] [reason]"]
+fn ban(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let target = args.single::<UserId>()?;
+    let duration = duration_arg(&mut args);
+    let reason = reason_arg(&args);
+    check_target(ctx, msg, target)?;
+    let until = lift_at(duration)?;
+
+    let guild = msg.guild_id.failure()?;
+    let audit_reason = audit_log_reason(msg, &reason);
+    // Opening the case first keeps a temporary ban from being left without a
+    // job lifting it
+    let case = open_case(
+        ctx,
+        msg,
+        CaseAction::Ban,
+        target,
+        &reason,
+        duration,
+        Some((JobKind::Unban, until)),
+    )?;
+    if let Err(why) = guild.ban(&ctx.http, target, &(0u8, audit_reason.as_str())) {
+        withdraw_case(ctx, &case)?;
+        return Err(why.into());
+    }
+    announce_case(ctx, msg, &case)?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(BAN_MEMBERS)]
+#[description = "Bans and immediately unbans a member, deleting their messages of the last \
+                 week."]
+#[usage = "<user> [reason]"]
+fn softban(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let target = args.single::<UserId>()?;
+    let reason = reason_arg(&args);
+    check_target(ctx, msg, target)?;
+
+    let guild = msg.guild_id.failure()?;
+    let audit_reason = audit_log_reason(msg, &reason);
+    guild.ban(&ctx.http, target, &(7u8, audit_reason.as_str()))?;
+    guild.unban(&ctx.http, target)?;
+    let case = open_case(ctx, msg, CaseAction::Softban, target, &reason, None, None)?;
+    announce_case(ctx, msg, &case)?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(BAN_MEMBERS)]
+#[description = "Lifts the ban of a user."]
+#[usage = "<user> [reason]"]
+fn unban(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let target = args.single::<UserId>()?;
+    let reason = reason_arg(&args);
+
+    let guild = msg.guild_id.failure()?;
+    guild.unban(&ctx.http, target)?;
+    let case = open_case(
+        ctx,
+        msg,
+        CaseAction::Unban,
+        target,
+        &reason,
+        None,
+        Some((JobKind::Unban, None)),
+    )?;
+    announce_case(ctx, msg, &case)?;
+
+    Ok(())
+}
+
+#[command]
+#[aliases(timeout)]
+#[only_in(guilds)]
+#[required_permissions(MANAGE_ROLES)]
+#[description = "Gives a member the mute role of the server, optionally for a while, e.g. \
+                 `30m` or `1d`."]
+#[usage = "<user> [duration] [reason]"]
+fn mute(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let target = args.single::<UserId>()?;
+    let duration = duration_arg(&mut args);
+    let reason = reason_arg(&args);
+    check_target(ctx, msg, target)?;
+    let until = lift_at(duration)?;
+
+    let guild = msg.guild_id.failure()?;
+    let role = mute_role(ctx, guild)?;
+    let case = open_case(
+        ctx,
+        msg,
+        CaseAction::Mute,
+        target,
+        &reason,
+        duration,
+        Some((JobKind::Unmute, until)),
+    )?;
+    if let Err(why) = ctx.http.add_member_role(guild.0, target.0, role.0) {
+        withdraw_case(ctx, &case)?;
+        return Err(why.into());
+    }
+    announce_case(ctx, msg, &case)?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(MANAGE_ROLES)]
+#[description = "Takes the mute role of the server from a member."]
+#[usage = "<user> [reason]"]
+fn unmute(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let target = args.single::<UserId>()?;
+    let reason = reason_arg(&args);
+    check_target(ctx, msg, target)?;
+
+    let guild = msg.guild_id.failure()?;
+    let role = mute_role(ctx, guild)?;
+    ctx.http.remove_member_role(guild.0, target.0, role.0)?;
+    let case = open_case(
+        ctx,
+        msg,
+        CaseAction::Unmute,
+        target,
+        &reason,
+        None,
+        Some((JobKind::Unmute, None)),
+    )?;
+    announce_case(ctx, msg, &case)?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(KICK_MEMBERS)]
+#[description = "Warns a member, letting them know through a DM."]
+#[usage = "<user> [reason]"]
+fn warn(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let target = args.single::<UserId>()?;
+    let reason = reason_arg(&args);
+    check_target(ctx, msg, target)?;
+
+    let case = open_case(ctx, msg, CaseAction::Warn, target, &reason, None, None)?;
+    announce_case(ctx, msg, &case)?;
+
+    let guild_name = msg
+        .guild(&ctx.cache)
+        .map(|g| g.read().name.clone())
+        .unwrap_or_default();
+    let notified = target.create_dm_channel(&ctx.http).and_then(|dm| {
+        dm.say(
+            &ctx.http,
+            &format!(
+                "You have been warned in {}: {}",
+                guild_name,
+                case.reason
+                    .as_ref()
+                    .map(String::as_str)
+                    .unwrap_or("no reason given")
+            ),
+        )
+    });
+    if notified.is_err() {
+        msg.reply(&ctx, "The member couldn't be sent a DM about the warning.")?;
+    }
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(KICK_MEMBERS)]
+#[description = "Shows a moderation case of the server."]
+#[usage = "<number>"]
+fn case(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let number = args.single::<i32>()?;
+    let guild = msg.guild_id.failure()?;
+
+    let case = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        moderation::get(&pgconn, guild.0, number)?
+    };
+    match case {
+        Some(case) => msg.reply(&ctx, &format!("```\n{}\n```", case))?,
+        None => return Err(ModerationErrorKind::CaseNotFound(number).into()),
+    };
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(KICK_MEMBERS)]
+#[description = "Replaces the reason of a moderation case of the server."]
+#[usage = "<number> <reason>"]
+fn reason(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let number = args.single::<i32>()?;
+    let reason = match reason_arg(&args) {
+        Some(s) => s,
+        None => return Err(CommandUsageKind::ModerationReasonUsage.into()),
+    };
+    let guild = msg.guild_id.failure()?;
+
+    let case = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        moderation::set_reason(&pgconn, guild.0, number, &reason)?
+    };
+    let case = match case {
+        Some(s) => s,
+        None => return Err(ModerationErrorKind::CaseNotFound(number).into()),
+    };
+
+    msg.reply(&ctx, &format!("Updated the reason of case #{}.", number))?;
+    modlog::update(&ctx.http, &ctx.data, &case);
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(KICK_MEMBERS)]
+#[description = "Lists the latest moderation cases against a user in this server."]
+#[usage = "<user> [amount]"]
+fn cases(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let target = args.single::<UserId>()?;
+    let limit = if args.is_empty() {
+        10
+    } else {
+        args.single::<i64>()?.max(1).min(15)
+    };
+    let guild = msg.guild_id.failure()?;
+
+    let cases = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        moderation::against(&pgconn, guild.0, target.0, limit)?
+    };
+    if cases.is_empty() {
+        msg.reply(
+            &ctx,
+            &format!("There are no cases against {}.", target.mention()),
+        )?;
+        return Ok(());
+    }
+
+    let lines = cases
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n");
+    msg.reply(
+        &ctx,
+        &format!(
+            "The latest cases against {}:\n```\n{}\n```",
+            target.mention(),
+            lines
+        ),
+    )?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Sets the role muted members get, or unsets it with `off`."]
+#[usage = "<role|off>"]
+fn muterole(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let arg = args.single::<String>()?;
+    let role = if arg.eq_ignore_ascii_case("off") {
+        None
+    } else {
+        Some(arg.parse::<RoleId>()?)
+    };
+    let guild = msg.guild_id.failure()?;
+
+    let settings = ServerSettings::new(guild.0, &ctx.data)?;
+    {
+        let mut write = settings.write();
+        write.set_mute_role(role.map(|r| r.0), Actor::command(msg.author.id.0));
+        write.save()?;
+    }
+
+    msg.reply(
+        &ctx,
+        &match role {
+            Some(role) => format!("Muted members now get {}.", role.mention()),
+            None => String::from("Unset the mute role."),
+        },
+    )?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Sets the channel every moderation case is posted to, or stops posting them \
+                 with `off`."]
+#[usage = "<channel|off>"]
+fn modlog(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild_id.failure()?;
+    let arg = args.single::<String>()?;
+    let channel = if arg.eq_ignore_ascii_case("off") {
+        None
+    } else {
+        Some(guild_channel(ctx, guild, arg.parse::<ChannelId>()?)?)
+    };
+
+    let settings = ServerSettings::new(guild.0, &ctx.data)?;
+    {
+        let mut write = settings.write();
+        write.set_modlog_channel(channel.map(|c| c.0), Actor::command(msg.author.id.0));
+        write.save()?;
+    }
+
+    msg.reply(
+        &ctx,
+        &match channel {
+            Some(channel) => format!("Moderation cases are now posted to {}.", channel.mention()),
+            None => String::from("Moderation cases are no longer posted."),
+        },
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,265 @@
+use super::prelude::*;
+use crate::{
+    data::{
+        audit::{self, PendingChange},
+        moderation,
+        onboarding::{self, Onboarding, VerificationMethod, MAX_AUTO_ROLES},
+    },
+    onboarding as onboarder,
+};
+use serenity::utils::parse_role;
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Manages the roles given to members when they join, optionally after a delay. \
+If the server asks members to verify themselves with `gate`, they only get the roles once they \
+have."]
+#[usage = "[list], <add|remove> <role> or delay <duration|off>"]
+fn autorole(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild_id.failure()?;
+    let actor = Actor::command(msg.author.id.0);
+    let action = if args.is_empty() {
+        String::from("list")
+    } else {
+        args.single::<String>()?.to_lowercase()
+    };
+    let mut settings = Onboarding::new(guild.0, &ctx.data)?.read().clone();
+
+    let change = match action.as_str() {
+        "list" => {
+            msg.reply(&ctx, &status(&settings))?;
+            return Ok(());
+        }
+        "add" | "remove" => {
+            let arg = args.single::<String>()?;
+            let role = parse_role(&arg)
+                .or_else(|| arg.parse().ok())
+                .ok_or(CommandUsageKind::AutoroleUsage)? as i64;
+            let old = audit::list(&settings.auto_roles);
+            if action == "add" {
+                if settings.auto_roles.len() >= MAX_AUTO_ROLES {
+                    return Err(VerificationErrorKind::TooManyRoles(MAX_AUTO_ROLES).into());
+                }
+                if !settings.auto_roles.contains(&role) {
+                    settings.auto_roles.push(role);
+                }
+            } else {
+                settings.auto_roles.retain(|&r| r != role);
+            }
+            PendingChange::new(actor, "auto_roles", old, audit::list(&settings.auto_roles))
+        }
+        "delay" => {
+            let arg = args.single::<String>()?;
+            let delay = if arg.eq_ignore_ascii_case("off") {
+                None
+            } else {
+                let delay =
+                    moderation::parse_duration(&arg).ok_or(CommandUsageKind::AutoroleUsage)?;
+                Some(delay.as_secs() as i64)
+            };
+            let change = PendingChange::new(
+                actor,
+                "auto_role_delay_seconds",
+                audit::optional(&settings.role_delay_seconds),
+                audit::optional(&delay),
+            );
+            settings.role_delay_seconds = delay;
+            change
+        }
+        _ => return Err(CommandUsageKind::AutoroleUsage.into()),
+    };
+
+    save(ctx, &settings, change)?;
+    msg.reply(&ctx, &status(&settings))?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[required_permissions(ADMINISTRATOR)]
+#[description = "Manages how joining members verify themselves before getting the roles of \
+`autorole`: by reacting to the message they are asked with, by running `verify`, or by running \
+`verify` with the code they are shown. Members are asked in the given channel, or this one, and \
+kicked if they don't verify themselves within the timeout. `approve` lets a member in by hand."]
+#[usage = "[status], <reaction|command|captcha> [channel], off, timeout <duration|off> or \
+           approve <member>"]
+fn gate(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = msg.guild_id.failure()?;
+    let actor = Actor::command(msg.author.id.0);
+    let action = if args.is_empty() {
+        String::from("status")
+    } else {
+        args.single::<String>()?.to_lowercase()
+    };
+    let mut settings = Onboarding::new(guild.0, &ctx.data)?.read().clone();
+
+    let changes = match action.as_str() {
+        "status" => {
+            msg.reply(&ctx, &status(&settings))?;
+            return Ok(());
+        }
+        "approve" => {
+            let member = args.single::<UserId>()?;
+            if !onboarder::verify(ctx, guild, member)? {
+                return Err(VerificationErrorKind::MemberNotPending.into());
+            }
+            msg.reply(&ctx, &format!("{} was let in.", member.mention()))?;
+            return Ok(());
+        }
+        "off" => {
+            let change = PendingChange::new(
+                actor,
+                "verification",
+                audit::optional(&settings.verification),
+                "none",
+            );
+            settings.verification = None;
+            vec![change]
+        }
+        "timeout" => {
+            let arg = args.single::<String>()?;
+            let timeout = if arg.eq_ignore_ascii_case("off") {
+                None
+            } else {
+                let timeout =
+                    moderation::parse_duration(&arg).ok_or(CommandUsageKind::GateUsage)?;
+                Some(timeout.as_secs() as i64)
+            };
+            let change = PendingChange::new(
+                actor,
+                "verification_timeout_seconds",
+                audit::optional(&settings.verification_timeout_seconds),
+                audit::optional(&timeout),
+            );
+            settings.verification_timeout_seconds = timeout;
+            vec![change]
+        }
+        method => {
+            let method = method
+                .parse::<VerificationMethod>()
+                .map_err(|()| CommandUsageKind::GateUsage)?;
+            // Verifying would let members in without giving them anything
+            if settings.auto_roles.is_empty() {
+                return Err(VerificationErrorKind::NoRoles.into());
+            }
+            let channel = if args.is_empty() {
+                msg.channel_id
+            } else {
+                guild_channel(ctx, guild, args.single::<ChannelId>()?)?
+            };
+            let channel = Some(channel.0 as i64);
+            let changes = vec![
+                PendingChange::new(
+                    actor,
+                    "verification",
+                    audit::optional(&settings.verification),
+                    method,
+                ),
+                PendingChange::new(
+                    actor,
+                    "verification_channel",
+                    audit::optional(&settings.verification_channel_id),
+                    audit::optional(&channel),
+                ),
+            ];
+            settings.verification = Some(method.as_str().to_owned());
+            settings.verification_channel_id = channel;
+            changes
+        }
+    };
+
+    {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        onboarding::save(&pgconn, &settings, &changes)?;
+    }
+    Onboarding::evict(guild.0, &ctx.data)?;
+    msg.reply(&ctx, &status(&settings))?;
+
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[bucket = "verification"]
+#[description = "Verifies yourself after joining a server which asks you to, giving you access \
+                 to it. If you were shown a code, run this with the code."]
+#[usage = "[code]"]
+fn verify(ctx: &mut Context, msg: &Message, args: Args) -> CommandResult {
+    let guild = msg.guild_id.failure()?;
+    let pending = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        onboarding::pending(&pgconn, guild.0, msg.author.id.0)?
+    };
+    let pending = pending.ok_or(VerificationErrorKind::NotPending)?;
+    match pending.method() {
+        VerificationMethod::Reaction => {
+            return Err(VerificationErrorKind::ReactionRequired.into());
+        }
+        VerificationMethod::Command => {}
+        VerificationMethod::Captcha => {
+            let answer = pending.answer.as_ref().failure()?;
+            if !onboarder::answers(answer, args.rest()) {
+                return Err(VerificationErrorKind::WrongAnswer.into());
+            }
+        }
+    }
+
+    if !onboarder::verify(ctx, guild, msg.author.id)? {
+        return Err(VerificationErrorKind::NotPending.into());
+    }
+    // The answer has done its job, and only clutters the channel
+    let _ = msg.delete(&ctx);
+
+    Ok(())
+}
+
+/// Saves the settings of the server, recording a change.
+fn save(ctx: &Context, settings: &Onboarding, change: PendingChange) -> Result<()> {
+    {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        onboarding::save(&pgconn, settings, &[change])?;
+    }
+    Onboarding::evict(settings.guild_id as u64, &ctx.data)
+}
+
+/// Describes the roles joining members get and how they verify themselves.
+fn status(settings: &Onboarding) -> String {
+    let mut status = if settings.auto_roles.is_empty() {
+        String::from("Joining members don't get any roles.")
+    } else {
+        let roles = settings
+            .auto_roles
+            .iter()
+            .map(|&r| RoleId(r as u64).mention())
+            .collect::<Vec<_>>()
+            .join(", ");
+        match settings.role_delay() {
+            Some(delay) => format!(
+                "Joining members get {} after {}.",
+                roles,
+                moderation::format_duration(delay)
+            ),
+            None => format!("Joining members get {}.", roles),
+        }
+    };
+    match (settings.verification(), settings.verification_channel_id) {
+        (Some(method), Some(channel)) => {
+            status.push_str(&format!(
+                "\nThey verify themselves by `{}` in {} first",
+                method,
+                ChannelId(channel as u64).mention()
+            ));
+            match settings.verification_timeout() {
+                Some(timeout) => status.push_str(&format!(
+                    ", and are kicked if they don't within {}.",
+                    moderation::format_duration(timeout)
+                )),
+                None => status.push('.'),
+            }
+        }
+        _ => status.push_str("\nThey don't have to verify themselves."),
+    }
+    status
+}
@@ -1,4 +1,8 @@
 use super::prelude::*;
+use crate::data::{
+    moderation,
+    scheduledjobs::{self, JobKind},
+};
 
 #[command]
 #[owners_only]
@@ -11,8 +15,20 @@ fn quit(ctx: &mut Context, msg: &Message) -> CommandResult {
 
 #[command]
 #[owners_only]
+#[description = "Toggles the blacklisting of servers or users, or blacklists them for a while, \
+                 e.g. `7d`."]
+#[usage = "<server: true|false> [duration] <IDs...>"]
 fn blacklist(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
     let server = args.single::<bool>()?;
+    let duration = args.current().and_then(moderation::parse_duration);
+    if duration.is_some() {
+        args.advance();
+    }
+    let until = match duration.map(scheduledjobs::due_in) {
+        Some(Some(at)) => Some(at),
+        Some(None) => return Err(ModerationErrorKind::DurationTooLong.into()),
+        None => None,
+    };
 
     let mut ids = vec![];
     while args.remaining() >= 1 {
@@ -22,29 +38,55 @@ fn blacklist(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult
         return Err(CommandUsageKind::DeveloperBlacklistNoIds.into());
     }
 
-    if server {
-        for id in &ids {
+    let actor = Actor::command(msg.author.id.0);
+    for id in &ids {
+        let blacklisted = if server {
             let setting = ServerSettings::new(*id, &ctx.data)?;
             let mut write = setting.write();
-            let blacklisted = !*(write.blacklisted());
-            write.set_blacklisted(blacklisted);
-        }
-    } else {
-        for id in &ids {
+            let blacklisted = until.is_some() || !*(write.blacklisted());
+            write.set_blacklisted(blacklisted, actor);
+            write.save()?;
+            blacklisted
+        } else {
             let setting = UserSettings::new(*id, &ctx.data)?;
             let mut write = setting.write();
-            let blacklisted = !*(write.blacklisted());
-            write.set_blacklisted(blacklisted);
+            let blacklisted = until.is_some() || !*(write.blacklisted());
+            write.set_blacklisted(blacklisted, actor);
+            write.save()?;
+            blacklisted
+        };
+
+        // Jobs lifting server blacklists run on the shard of the server.
+        let (job, guild) = if server {
+            (JobKind::UnblacklistServer, *id)
+        } else {
+            (JobKind::UnblacklistUser, scheduledjobs::NO_GUILD)
+        };
+        let pgconn = crate::database::connection(&ctx.data)?;
+        match until {
+            Some(at) if blacklisted => scheduledjobs::schedule(&pgconn, job, guild, *id, None, at)?,
+            _ => {
+                scheduledjobs::cancel(&pgconn, job, guild, *id)?;
+            }
         }
     }
 
     msg.reply(
         &ctx,
         &format!(
-            "Toggled blacklist on {} {} ID{}.",
+            "{} {} {} ID{}{}.",
+            if until.is_some() {
+                "Blacklisted"
+            } else {
+                "Toggled blacklist on"
+            },
             ids.len(),
             if server { "server" } else { "user" },
             if ids.len() == 1 { "" } else { "s" },
+            match duration {
+                Some(d) => format!(" for {}", moderation::format_duration(d)),
+                None => String::new(),
+            },
         ),
     )?;
 
@@ -53,53 +95,229 @@ fn blacklist(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult
 
 #[command]
 #[owners_only]
+#[description = "Evaluates ketos code in your session. The code may be given in code blocks, \
+which are run in order, as inline code, or as attached `.lisp` or `.ket` files, which are run \
+before the rest."]
+#[usage = "<code blocks|inline code|attached files>"]
 fn evaluate(ctx: &mut Context, msg: &Message) -> CommandResult {
-    use ketos::io::SharedWrite as _;
-    use std::rc::Rc;
+    use crate::scripting::owner;
 
-    let code_block: Vec<&str> = msg.content.splitn(3, "```").collect();
-    if code_block.len() != 3 {
-        return Err(CommandUsageKind::DeveloperEvaluateUsage.into());
-    }
-    let mut code_block = code_block[1];
-    if code_block.starts_with("lisp") {
-        code_block = code_block.trim_start_matches("lisp\n");
+    let code = owner::collect_code(msg)?;
+    owner::evaluate(ctx, msg, code)?;
+
+    Ok(())
+}
+
+#[command]
+#[owners_only]
+#[description = "Manages your ketos session. `reset` throws away its definitions, `bindings` \
+lists them, and `repl` toggles evaluating code blocks in this channel without a command."]
+#[usage = "<reset|bindings|repl>"]
+fn session(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    use crate::scripting::{ReplChannelsContainer, SessionsContainer};
+
+    match args.single::<String>()?.to_lowercase().as_str() {
+        "reset" => {
+            let removed = ctx
+                .data
+                .write()
+                .get_mut::<SessionsContainer>()
+                .failure()?
+                .remove(&msg.author.id);
+            msg.reply(
+                &ctx,
+                if removed.is_some() {
+                    "Your session has been reset."
+                } else {
+                    "You don't have a session to reset."
+                },
+            )?;
+        }
+        "bindings" => {
+            let data = ctx.data.read();
+            let sessions = data.get::<SessionsContainer>().failure()?;
+            let session = match sessions.get(&msg.author.id) {
+                Some(s) => s,
+                None => {
+                    msg.reply(&ctx, "You don't have a session.")?;
+                    return Ok(());
+                }
+            };
+
+            let http = Arc::clone(&ctx.http);
+            let (channel, author) = (msg.channel_id, msg.author.id);
+            session.bindings(move |bindings| {
+                let _ = channel.say(
+                    &http,
+                    &if bindings.is_empty() {
+                        format!("{}: Your session has no definitions.", author.mention())
+                    } else {
+                        format!(
+                            "{}: Your session has these definitions:\n```lisp\n{}\n```",
+                            author.mention(),
+                            bindings.join("\n")
+                        )
+                    },
+                );
+            })?;
+        }
+        "repl" => {
+            let enabled = {
+                let mut data = ctx.data.write();
+                let channels = data.get_mut::<ReplChannelsContainer>().failure()?;
+                if channels.remove(&msg.channel_id) {
+                    false
+                } else {
+                    channels.insert(msg.channel_id);
+                    true
+                }
+            };
+            msg.reply(
+                &ctx,
+                if enabled {
+                    "Code blocks sent by owners in this channel are now evaluated."
+                } else {
+                    "Code blocks in this channel are no longer evaluated."
+                },
+            )?;
+        }
+        _ => return Err(CommandUsageKind::DeveloperSessionUsage.into()),
     }
-    debug!("Entire code to run with ketos:\n{}", code_block);
-
-    let output_writer = Rc::new(crate::ketoswritewrapper::KetosWriteWrapper::bytearray());
-    let interpreter = ketos::Builder::new()
-        .io(Rc::new(ketos::GlobalIo::new(
-            Rc::clone(&output_writer) as Rc<dyn ketos::SharedWrite>,
-            Rc::clone(&output_writer) as Rc<dyn ketos::SharedWrite>,
-        )))
-        .finish();
-
-    let compiled = interpreter.compile_exprs(code_block)?;
-    debug!("Compiled: {:?}", compiled);
-
-    let output = interpreter
-        .execute_program(compiled)
-        .map(|ref v| interpreter.format_value(v))
-        .map_err(|ref e| format!("{:?}", e));
-    let output = match output {
-        Ok(s) => s,
-        Err(s) => s,
-    };
 
-    drop(interpreter); // drop the other Rcs
-    output_writer.flush()?;
+    Ok(())
+}
 
-    let writer = Rc::try_unwrap(output_writer).ok().failure()?;
-    let prints = writer.as_string()?;
+#[command]
+#[owners_only]
+fn discard(ctx: &mut Context, msg: &Message) -> CommandResult {
+    use crate::scripting::EvaluationsContainer;
+    use std::sync::atomic::Ordering;
+
+    // ketos can't be interrupted, so a running evaluation is left to run out its
+    // time limit; only its result is thrown away.
+    let discarded = {
+        let mut data = ctx.data.write();
+        let running = data.get_mut::<EvaluationsContainer>().failure()?;
+        let mine = running
+            .iter()
+            .filter(|(_, r)| r.author == msg.author.id)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in &mine {
+            if let Some(r) = running.remove(id) {
+                r.discarded.store(true, Ordering::SeqCst);
+            }
+        }
+        mine.len()
+    };
 
     msg.reply(
         &ctx,
         &format!(
-            "Interpreter returned: {}\nInterpreter printed:\n{}",
-            output, prints
+            "Discarded {} evaluation{}; their results won't be sent.",
+            discarded,
+            if discarded == 1 { "" } else { "s" }
         ),
     )?;
 
     Ok(())
 }
+
+#[command]
+#[owners_only]
+fn cache(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    use crate::data::{ServerSettingsContainer, SettingsCache, UserSettingsContainer};
+
+    fn describe<V>(name: &str, cache: &SettingsCache<V>) -> String {
+        format!(
+            "{}: {} entries (capacity {}, TTL {}s{}), {:.1}% hit ratio ({} hits, {} misses)",
+            name,
+            cache.len(),
+            match cache.capacity() {
+                0 => String::from("unbounded"),
+                n => n.to_string(),
+            },
+            cache.ttl().as_secs(),
+            if cache.negative_caching() {
+                ", negative caching"
+            } else {
+                ""
+            },
+            cache.hit_ratio() * 100.0,
+            cache.hits(),
+            cache.misses(),
+        )
+    }
+
+    if args.is_empty() {
+        let data = ctx.data.read();
+        let server = data.get::<ServerSettingsContainer>().failure()?;
+        let user = data.get::<UserSettingsContainer>().failure()?;
+        msg.reply(
+            &ctx,
+            &format!(
+                "\n{}\n{}",
+                describe("Server settings", server),
+                describe("User settings", user),
+            ),
+        )?;
+        return Ok(());
+    }
+
+    if args.single::<String>()? != "clear" {
+        return Err(CommandUsageKind::DeveloperCacheUsage.into());
+    }
+    let server = match args.single::<String>()?.as_str() {
+        "server" => true,
+        "user" => false,
+        _ => return Err(CommandUsageKind::DeveloperCacheUsage.into()),
+    };
+    let id = if args.is_empty() {
+        None
+    } else {
+        Some(args.single::<u64>()?)
+    };
+
+    // Evicted settings are dropped once the share map is unlocked, as dropping
+    // them may save them
+    let (servers, users) = {
+        let mut data = ctx.data.write();
+        match (server, id) {
+            (true, Some(id)) => {
+                let servers = data.get_mut::<ServerSettingsContainer>().failure()?;
+                (servers.remove(&id).into_iter().collect(), Vec::new())
+            }
+            (true, None) => (
+                data.get_mut::<ServerSettingsContainer>().failure()?.clear(),
+                Vec::new(),
+            ),
+            (false, Some(id)) => {
+                let users = data.get_mut::<UserSettingsContainer>().failure()?;
+                (Vec::new(), users.remove(&id).into_iter().collect())
+            }
+            (false, None) => (
+                Vec::new(),
+                data.get_mut::<UserSettingsContainer>().failure()?.clear(),
+            ),
+        }
+    };
+    drop(servers);
+    drop(users);
+
+    msg.reply(
+        &ctx,
+        &match id {
+            Some(id) => format!(
+                "Evicted {} {} from the cache.",
+                if server { "server" } else { "user" },
+                id
+            ),
+            None => format!(
+                "Cleared the {} settings cache.",
+                if server { "server" } else { "user" }
+            ),
+        },
+    )?;
+
+    Ok(())
+}
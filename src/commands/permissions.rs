@@ -0,0 +1,34 @@
+use super::prelude::*;
+use crate::data::OwnerContainer;
+
+/// Whether the user is one of the owners of the bot.
+pub fn is_owner(ctx: &Context, user: UserId) -> bool {
+    ctx.data
+        .read()
+        .get::<OwnerContainer>()
+        .map(|s| s.contains(&user))
+        .unwrap_or(false)
+}
+
+/// Whether the author of the message has all the given permissions in the guild
+/// the message was sent in.
+pub fn has_guild_permissions(ctx: &Context, msg: &Message, permissions: Permissions) -> bool {
+    match msg.guild(&ctx.cache) {
+        Some(guild) => guild
+            .read()
+            .member_permissions(msg.author.id)
+            .contains(permissions),
+        None => false,
+    }
+}
+
+/// Checks that the channel is one of the server, so its settings can't make the
+/// bot post to the channels of other servers.
+pub fn guild_channel(ctx: &Context, guild: GuildId, channel: ChannelId) -> Result<ChannelId> {
+    let guild = guild.to_guild_cached(&ctx.cache).failure()?;
+    if guild.read().channels.contains_key(&channel) {
+        Ok(channel)
+    } else {
+        Err(PermissionErrorKind::ForeignChannel(channel.0).into())
+    }
+}
@@ -0,0 +1,99 @@
+use super::prelude::*;
+use crate::data::userdata;
+
+#[command]
+#[description = "Sends you everything the bot stores about you as a JSON file."]
+#[bucket = "privacy"]
+fn mydata(ctx: &mut Context, msg: &Message) -> CommandResult {
+    let export = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        userdata::export(&pgconn, msg.author.id.0)?
+    };
+    let json = serde_json::to_vec_pretty(&export)?;
+
+    let dm = msg.author.create_dm_channel(&ctx)?;
+    dm.id
+        .send_files(&ctx.http, vec![(&json[..], "asami-data.json")], |m| {
+            m.content("Here is everything Asami stores about you.")
+        })?;
+
+    if msg.guild_id.is_some() {
+        msg.reply(&ctx, "I've sent you a DM with your data.")?;
+    }
+
+    Ok(())
+}
+
+#[command]
+#[description = "Deletes everything the bot stores about you. \
+                 Run it once, then again with `confirm` to go through with it."]
+#[usage = "[confirm]"]
+fn forgetme(ctx: &mut Context, msg: &Message, mut args: Args) -> CommandResult {
+    use crate::data::{PendingDeletionsContainer, UserSettingsContainer};
+    use diesel::Connection;
+    use std::time::{Duration, Instant};
+
+    const CONFIRMATION_WINDOW: Duration = Duration::from_secs(60);
+
+    let confirmed = !args.is_empty() && args.single::<String>()?.eq_ignore_ascii_case("confirm");
+    if !confirmed {
+        ctx.data
+            .write()
+            .get_mut::<PendingDeletionsContainer>()
+            .failure()?
+            .insert(msg.author.id, Instant::now());
+        msg.reply(
+            &ctx,
+            &format!(
+                "This deletes everything Asami stores about you, and can't be undone. \
+                 Run `a!forgetme confirm` within {} seconds to go through with it.",
+                CONFIRMATION_WINDOW.as_secs()
+            ),
+        )?;
+        return Ok(());
+    }
+
+    let requested = ctx
+        .data
+        .write()
+        .get_mut::<PendingDeletionsContainer>()
+        .failure()?
+        .remove(&msg.author.id);
+    match requested {
+        Some(at) if at.elapsed() <= CONFIRMATION_WINDOW => {}
+        _ => return Err(CommandUsageKind::ForgetMeUnconfirmed.into()),
+    }
+
+    let outcome = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        pgconn.transaction::<_, diesel::result::Error, _>(|| {
+            userdata::forget(&pgconn, msg.author.id.0)
+        })?
+    };
+    if !outcome.kept_blacklist {
+        // Dropping settings may save them, which needs the share map
+        let removed = ctx
+            .data
+            .write()
+            .get_mut::<UserSettingsContainer>()
+            .failure()?
+            .remove(&msg.author.id.0);
+        drop(removed);
+    }
+
+    let mut reply = String::from(if outcome.kept_blacklist {
+        "Your data has been deleted, except for your blacklist which is still in effect."
+    } else {
+        "Your data has been deleted."
+    });
+    if outcome.kept_cases > 0 {
+        reply.push_str(&format!(
+            " The {} moderation cases against you were kept, as they belong to the records \
+             of their servers.",
+            outcome.kept_cases
+        ));
+    }
+    msg.reply(&ctx, &reply)?;
+
+    Ok(())
+}
@@ -1,2 +1,15 @@
 pub const COLLECTION_USER_SETTINGS: &'static str = "settings_user";
 pub const COLLECTION_SERVER_SETTINGS: &'static str = "settings_server";
+
+/// The version of the database layout in `scheme.rs`, bumped whenever a table or
+/// column is added, changed or removed.
+pub const SCHEMA_VERSION: u32 = 13;
+
+/// The maximum amount of characters in a Discord message.
+pub const MESSAGE_LENGTH_LIMIT: usize = 2000;
+
+/// The maximum amount of characters in the value of a field of an embed.
+pub const EMBED_FIELD_LENGTH_LIMIT: usize = 1024;
+
+/// The prefix commands are invoked with.
+pub const PREFIX: &str = "a!";
@@ -0,0 +1,244 @@
+use super::{
+    audit::{self, AuditTarget, PendingChange},
+    get_or_load, SettingsCache,
+};
+use crate::{prelude::*, scheme::raid_protection};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use lru_time_cache::LruCache;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use typemap::Key as TypeMapKey;
+
+/// The raid protection of every server which was looked up recently, by server
+/// ID.
+pub struct RaidProtectionContainer;
+
+impl TypeMapKey for RaidProtectionContainer {
+    type Value = SettingsCache<RaidProtection>;
+}
+
+/// The recent joins of every server, to recognise raids.
+pub struct JoinTrackerContainer;
+
+impl TypeMapKey for JoinTrackerContainer {
+    type Value = Mutex<JoinTracker>;
+}
+
+/// What is done once a raid is detected. The server is alerted in every case.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RaidResponse {
+    /// Only alert the server.
+    Alert,
+    /// Keep everyone from sending messages in the channels of the server.
+    Lockdown,
+    /// Raise the verification level of the server to the highest.
+    Verification,
+    /// Kick the raiders, and everyone joining until the raid is over.
+    Kick,
+}
+
+impl RaidResponse {
+    pub const ALL: [RaidResponse; 4] = [
+        RaidResponse::Alert,
+        RaidResponse::Lockdown,
+        RaidResponse::Verification,
+        RaidResponse::Kick,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RaidResponse::Alert => "alert",
+            RaidResponse::Lockdown => "lockdown",
+            RaidResponse::Verification => "verification",
+            RaidResponse::Kick => "kick",
+        }
+    }
+}
+
+impl FromStr for RaidResponse {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        RaidResponse::ALL
+            .iter()
+            .cloned()
+            .find(|r| r.as_str().eq_ignore_ascii_case(s))
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for RaidResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How a server is protected from raids, and the raid it is in if any.
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Insertable)]
+#[table_name = "raid_protection"]
+pub struct RaidProtection {
+    pub guild_id: i64,
+    pub enabled: bool,
+    pub join_limit: i32,
+    pub join_window_seconds: i32,
+    pub similar_limit: i32,
+    pub min_account_age_seconds: Option<i64>,
+    pub response: String,
+    pub raid_started_at: Option<DateTime<Utc>>,
+    pub locked_channels: Vec<i64>,
+    pub previous_verification_level: Option<i16>,
+}
+
+impl RaidProtection {
+    /// The protection of a server which hasn't set it up, which is disabled.
+    pub fn default_for(guild: u64) -> Self {
+        RaidProtection {
+            guild_id: guild as i64,
+            enabled: false,
+            join_limit: 10,
+            join_window_seconds: 10,
+            similar_limit: 3,
+            min_account_age_seconds: None,
+            response: RaidResponse::Alert.as_str().to_owned(),
+            raid_started_at: None,
+            locked_channels: Vec::new(),
+            previous_verification_level: None,
+        }
+    }
+
+    /// Gets the protection of a server, looking it up if it isn't cached.
+    pub fn new(guild: u64, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Result<Arc<RwLock<Self>>> {
+        get_or_load::<RaidProtectionContainer, _>(sharemap, guild, || {
+            let pgconn = crate::database::connection(sharemap)?;
+            let protection = find(&pgconn, guild)?;
+            Ok(protection.unwrap_or_else(|| RaidProtection::default_for(guild)))
+        })
+    }
+
+    /// Drops the cached protection of a server, so changes to it are picked up.
+    pub fn evict(guild: u64, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Result<()> {
+        sharemap
+            .write()
+            .get_mut::<RaidProtectionContainer>()
+            .failure()?
+            .remove(&guild);
+        Ok(())
+    }
+
+    /// What is done once a raid is detected, falling back to only alerting.
+    pub fn response(&self) -> RaidResponse {
+        self.response.parse().unwrap_or(RaidResponse::Alert)
+    }
+
+    pub fn join_window(&self) -> Duration {
+        Duration::from_secs(self.join_window_seconds.max(1) as u64)
+    }
+
+    pub fn min_account_age(&self) -> Option<Duration> {
+        self.min_account_age_seconds
+            .map(|s| Duration::from_secs(s.max(0) as u64))
+    }
+}
+
+/// A member who joined a server recently.
+#[derive(Clone, Debug)]
+pub struct RecentJoin {
+    pub at: Instant,
+    pub user_id: u64,
+    pub account_created_at: DateTime<Utc>,
+    /// The hash of the avatar of the member, if it isn't a default one.
+    pub avatar: Option<String>,
+    pub name: String,
+}
+
+/// The members who joined servers within their join windows, by server ID.
+pub struct JoinTracker {
+    recent: LruCache<u64, VecDeque<RecentJoin>>,
+}
+
+impl JoinTracker {
+    /// Tracks up to the given amount of servers at once, or any amount for 0.
+    pub fn new(capacity: usize, max_window: Duration) -> Self {
+        let recent = match capacity {
+            0 => LruCache::with_expiry_duration(max_window),
+            capacity => LruCache::with_expiry_duration_and_capacity(max_window, capacity),
+        };
+        JoinTracker { recent }
+    }
+
+    /// Records a join, returning every join of the server within the window,
+    /// including this one.
+    pub fn record(&mut self, guild: u64, join: RecentJoin, window: Duration) -> Vec<RecentJoin> {
+        let now = join.at;
+        let mut joins = self.recent.remove(&guild).unwrap_or_default();
+        while joins
+            .front()
+            .map(|j| now.duration_since(j.at) > window)
+            .unwrap_or(false)
+        {
+            joins.pop_front();
+        }
+        joins.push_back(join);
+        let recent = joins.iter().cloned().collect();
+        self.recent.insert(guild, joins);
+        recent
+    }
+
+    /// Forgets the joins of a server, so a raid isn't detected twice.
+    pub fn clear(&mut self, guild: u64) {
+        self.recent.remove(&guild);
+    }
+}
+
+pub fn find(conn: &PgConnection, guild: u64) -> QueryResult<Option<RaidProtection>> {
+    use crate::scheme::raid_protection::dsl::*;
+
+    raid_protection
+        .filter(guild_id.eq(guild as i64))
+        .first(conn)
+        .optional()
+}
+
+/// Saves the protection of a server, recording the changes made to it.
+pub fn save(
+    conn: &PgConnection,
+    protection: &RaidProtection,
+    changes: &[PendingChange],
+) -> QueryResult<()> {
+    use crate::scheme::raid_protection::dsl::*;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::insert_into(raid_protection)
+            .values(protection)
+            .on_conflict(guild_id)
+            .do_update()
+            .set((
+                enabled.eq(protection.enabled),
+                join_limit.eq(protection.join_limit),
+                join_window_seconds.eq(protection.join_window_seconds),
+                similar_limit.eq(protection.similar_limit),
+                min_account_age_seconds.eq(protection.min_account_age_seconds),
+                response.eq(&protection.response),
+                raid_started_at.eq(protection.raid_started_at),
+                locked_channels.eq(&protection.locked_channels),
+                previous_verification_level.eq(protection.previous_verification_level),
+            ))
+            .execute(conn)?;
+        audit::record(
+            conn,
+            AuditTarget::Server,
+            protection.guild_id as u64,
+            changes,
+        )
+    })
+}
+
+guild_rows!(raid_protection);
@@ -0,0 +1,235 @@
+use crate::prelude::*;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+use std::fmt;
+
+/// What kind of settings an audit entry is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditTarget {
+    User,
+    Server,
+}
+
+impl AuditTarget {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditTarget::User => "user",
+            AuditTarget::Server => "server",
+        }
+    }
+}
+
+/// Through what a change was made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditSource {
+    Command,
+    Cli,
+    Event,
+    Script,
+    Scheduler,
+}
+
+impl AuditSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditSource::Command => "command",
+            AuditSource::Cli => "cli",
+            AuditSource::Event => "event",
+            AuditSource::Script => "script",
+            AuditSource::Scheduler => "scheduler",
+        }
+    }
+}
+
+/// Who made a change, and through what.
+#[derive(Clone, Copy, Debug)]
+pub struct Actor {
+    pub id: Option<u64>,
+    pub source: AuditSource,
+}
+
+impl Actor {
+    pub fn command(id: u64) -> Self {
+        Actor {
+            id: Some(id),
+            source: AuditSource::Command,
+        }
+    }
+
+    pub fn cli() -> Self {
+        Actor {
+            id: None,
+            source: AuditSource::Cli,
+        }
+    }
+
+    pub fn script(id: u64) -> Self {
+        Actor {
+            id: Some(id),
+            source: AuditSource::Script,
+        }
+    }
+
+    pub fn event() -> Self {
+        Actor {
+            id: None,
+            source: AuditSource::Event,
+        }
+    }
+
+    pub fn scheduler() -> Self {
+        Actor {
+            id: None,
+            source: AuditSource::Scheduler,
+        }
+    }
+}
+
+/// A change which is yet to be written along with the settings it was made to.
+#[derive(Debug)]
+pub struct PendingChange {
+    pub actor: Actor,
+    pub field: &'static str,
+    pub old_value: String,
+    pub new_value: String,
+    /// When the change was made, which may be well before it is written.
+    pub made_at: DateTime<Utc>,
+}
+
+impl PendingChange {
+    pub fn new(
+        actor: Actor,
+        field: &'static str,
+        old: impl fmt::Display,
+        new: impl fmt::Display,
+    ) -> Self {
+        PendingChange {
+            actor,
+            field,
+            old_value: old.to_string(),
+            new_value: new.to_string(),
+            made_at: Utc::now(),
+        }
+    }
+}
+
+/// Formats a list of values for the audit log.
+pub fn list<T: fmt::Display>(values: &[T]) -> String {
+    if values.is_empty() {
+        return String::from("none");
+    }
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Formats an optional value for the audit log.
+pub fn optional<T: fmt::Display>(value: &Option<T>) -> String {
+    match value {
+        Some(v) => v.to_string(),
+        None => String::from("none"),
+    }
+}
+
+#[derive(Debug, Queryable, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub target_kind: String,
+    pub target_id: i64,
+    pub actor_id: Option<i64>,
+    pub source: String,
+    pub field: String,
+    pub old_value: String,
+    pub new_value: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{} {} {}: {} -> {} by {} ({})",
+            self.id,
+            self.created_at.format("%Y-%m-%d %H:%M:%S"),
+            self.field,
+            self.old_value,
+            self.new_value,
+            self.actor_id
+                .map(|a| a.to_string())
+                .unwrap_or_else(|| String::from("nobody")),
+            self.source,
+        )
+    }
+}
+
+/// Appends the changes made to the given target to the audit log.
+pub fn record(
+    conn: &PgConnection,
+    kind: AuditTarget,
+    target: u64,
+    changes: &[PendingChange],
+) -> QueryResult<()> {
+    use crate::scheme::settings_audit::dsl::*;
+
+    for change in changes {
+        diesel::insert_into(settings_audit)
+            .values((
+                target_kind.eq(kind.as_str()),
+                target_id.eq(target as i64),
+                actor_id.eq(change.actor.id.map(|a| a as i64)),
+                source.eq(change.actor.source.as_str()),
+                field.eq(change.field),
+                old_value.eq(&change.old_value),
+                new_value.eq(&change.new_value),
+                created_at.eq(change.made_at),
+            ))
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Gets the latest entries about the given target, newest first.
+pub fn history(
+    conn: &PgConnection,
+    kind: AuditTarget,
+    target: u64,
+    limit: i64,
+) -> QueryResult<Vec<AuditEntry>> {
+    use crate::scheme::settings_audit::dsl::*;
+
+    settings_audit
+        .filter(target_kind.eq(kind.as_str()))
+        .filter(target_id.eq(target as i64))
+        .order(id.desc())
+        .limit(limit)
+        .load(conn)
+}
+
+/// Gets every entry about the given target, oldest first.
+pub fn all_about(
+    conn: &PgConnection,
+    kind: AuditTarget,
+    target: u64,
+) -> QueryResult<Vec<AuditEntry>> {
+    use crate::scheme::settings_audit::dsl::*;
+
+    settings_audit
+        .filter(target_kind.eq(kind.as_str()))
+        .filter(target_id.eq(target as i64))
+        .order(id.asc())
+        .load(conn)
+}
+
+/// Gets every entry of changes made by the given user, oldest first.
+pub fn all_by(conn: &PgConnection, actor: u64) -> QueryResult<Vec<AuditEntry>> {
+    use crate::scheme::settings_audit::dsl::*;
+
+    settings_audit
+        .filter(actor_id.eq(actor as i64))
+        .order(id.asc())
+        .load(conn)
+}
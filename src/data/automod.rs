@@ -0,0 +1,610 @@
+use super::{
+    audit::{self, Actor, AuditTarget, PendingChange},
+    get_or_load, SettingsCache,
+};
+use crate::{prelude::*, scheme::automod_rules};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use lru_time_cache::LruCache;
+use parking_lot::{Mutex, RwLock};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serenity::model::channel::Message;
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use typemap::Key as TypeMapKey;
+
+/// The largest a compiled pattern may be, in bytes, so patterns can't use up the
+/// memory of the bot.
+const PATTERN_SIZE_LIMIT: usize = 1 << 16;
+
+/// What links inviting to servers look like.
+const INVITE_PATTERN: &str = r"(?i)(discord\.(gg|io|me|li)|discord(app)?\.com/invite)/[a-z0-9-]+";
+
+/// The least amount of letters a message needs before its capitals count.
+const CAPS_MIN_LETTERS: usize = 10;
+
+/// The automod rules of every server which were looked up recently, by server
+/// ID.
+pub struct AutomodContainer;
+
+impl TypeMapKey for AutomodContainer {
+    type Value = SettingsCache<GuildRules>;
+}
+
+/// The recent messages of members, to recognise them sending the same message
+/// over and over.
+///
+/// It has a lock of its own, so recording every message only needs the share
+/// map locked for reading.
+pub struct SpamTrackerContainer;
+
+impl TypeMapKey for SpamTrackerContainer {
+    type Value = Mutex<SpamTracker>;
+}
+
+/// What automod rules look for in messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuleKind {
+    /// Banned words and regular expressions.
+    Words,
+    /// Links inviting to other servers.
+    Invites,
+    /// Messages mentioning many users and roles.
+    Mentions,
+    /// Messages mostly written in capitals.
+    Caps,
+    /// Letters with piles of combining marks on them.
+    Zalgo,
+    /// The same message being sent over and over.
+    Spam,
+}
+
+impl RuleKind {
+    pub const ALL: [RuleKind; 6] = [
+        RuleKind::Words,
+        RuleKind::Invites,
+        RuleKind::Mentions,
+        RuleKind::Caps,
+        RuleKind::Zalgo,
+        RuleKind::Spam,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RuleKind::Words => "words",
+            RuleKind::Invites => "invites",
+            RuleKind::Mentions => "mentions",
+            RuleKind::Caps => "caps",
+            RuleKind::Zalgo => "zalgo",
+            RuleKind::Spam => "spam",
+        }
+    }
+
+    /// The limit of the rule if the server didn't choose one, if it has one.
+    pub fn default_threshold(self) -> Option<u32> {
+        match self {
+            RuleKind::Words | RuleKind::Invites => None,
+            // Mentions in a single message
+            RuleKind::Mentions => Some(5),
+            // Percent of the letters of a message
+            RuleKind::Caps => Some(70),
+            // Combining marks on a single letter
+            RuleKind::Zalgo => Some(4),
+            // Identical messages within the spam window
+            RuleKind::Spam => Some(4),
+        }
+    }
+}
+
+impl FromStr for RuleKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        RuleKind::ALL
+            .iter()
+            .cloned()
+            .find(|k| k.as_str().eq_ignore_ascii_case(s))
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for RuleKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What is done to the author of a message breaking a rule, from the mildest to
+/// the harshest. The message is deleted in every case.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RuleAction {
+    Delete,
+    Warn,
+    Timeout,
+    Kick,
+}
+
+impl RuleAction {
+    pub const ALL: [RuleAction; 4] = [
+        RuleAction::Delete,
+        RuleAction::Warn,
+        RuleAction::Timeout,
+        RuleAction::Kick,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RuleAction::Delete => "delete",
+            RuleAction::Warn => "warn",
+            RuleAction::Timeout => "timeout",
+            RuleAction::Kick => "kick",
+        }
+    }
+}
+
+impl FromStr for RuleAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        RuleAction::ALL
+            .iter()
+            .cloned()
+            .find(|a| a.as_str().eq_ignore_ascii_case(s))
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A rule messages in a server are automatically moderated by.
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Insertable)]
+#[table_name = "automod_rules"]
+pub struct AutomodRule {
+    pub guild_id: i64,
+    pub kind: String,
+    pub action: String,
+    pub patterns: Vec<String>,
+    pub threshold: Option<i32>,
+    pub timeout_seconds: Option<i64>,
+    pub exempt_roles: Vec<i64>,
+    pub exempt_channels: Vec<i64>,
+    pub author_id: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl AutomodRule {
+    /// A rule of the server which deletes the messages breaking it.
+    pub fn new(guild: u64, kind: RuleKind) -> Self {
+        AutomodRule {
+            guild_id: guild as i64,
+            kind: kind.as_str().to_owned(),
+            action: RuleAction::Delete.as_str().to_owned(),
+            patterns: Vec::new(),
+            threshold: None,
+            timeout_seconds: None,
+            exempt_roles: Vec::new(),
+            exempt_channels: Vec::new(),
+            author_id: None,
+            updated_at: Utc::now(),
+        }
+    }
+
+    /// The limit of the rule, falling back to the default of its kind.
+    pub fn effective_threshold(&self) -> Option<u32> {
+        match self.threshold {
+            Some(s) => Some(s.max(0) as u32),
+            None => self.kind.parse().ok().and_then(RuleKind::default_threshold),
+        }
+    }
+}
+
+/// A rule of a server, ready to check messages against.
+#[derive(Debug)]
+pub struct CompiledRule {
+    pub kind: RuleKind,
+    pub action: RuleAction,
+    pub patterns: Vec<Regex>,
+    pub threshold: u32,
+    pub timeout: Option<Duration>,
+    pub exempt_roles: Vec<u64>,
+    pub exempt_channels: Vec<u64>,
+}
+
+impl CompiledRule {
+    /// Compiles a stored rule, or `None` if it is of an unknown kind or action.
+    ///
+    /// Patterns are checked when they're added, so those which don't compile
+    /// anymore are only logged and skipped.
+    pub fn new(rule: &AutomodRule) -> Option<Self> {
+        let kind = rule.kind.parse::<RuleKind>().ok()?;
+        let action = rule.action.parse::<RuleAction>().ok()?;
+        let patterns = match kind {
+            RuleKind::Words => rule
+                .patterns
+                .iter()
+                .filter_map(|p| match compile_pattern(p) {
+                    Ok(s) => Some(s),
+                    Err(e) => {
+                        warn!("Skipping the pattern `{}` of {}: {}", p, rule.guild_id, e);
+                        None
+                    }
+                })
+                .collect(),
+            RuleKind::Invites => vec![Regex::new(INVITE_PATTERN).ok()?],
+            _ => Vec::new(),
+        };
+        Some(CompiledRule {
+            kind,
+            action,
+            patterns,
+            threshold: rule.effective_threshold().unwrap_or(0),
+            timeout: rule
+                .timeout_seconds
+                .map(|s| Duration::from_secs(s.max(0) as u64)),
+            exempt_roles: rule.exempt_roles.iter().map(|&r| r as u64).collect(),
+            exempt_channels: rule.exempt_channels.iter().map(|&c| c as u64).collect(),
+        })
+    }
+
+    /// Whether the rule doesn't apply to a member with the roles in the channel.
+    pub fn exempts(&self, channel: u64, roles: &[u64]) -> bool {
+        self.exempt_channels.contains(&channel)
+            || roles.iter().any(|r| self.exempt_roles.contains(r))
+    }
+
+    /// Checks the message against the rule, returning why it breaks it if it
+    /// does. Spam is recognised by the spam tracker instead.
+    pub fn violation(&self, msg: &Message) -> Option<String> {
+        let content = msg.content.as_str();
+        match self.kind {
+            RuleKind::Words => self
+                .patterns
+                .iter()
+                .find(|p| p.is_match(content))
+                .map(|_| String::from("used a banned word")),
+            RuleKind::Invites => self
+                .patterns
+                .iter()
+                .find(|p| p.is_match(content))
+                .map(|_| String::from("posted an invite link")),
+            RuleKind::Mentions => {
+                let mentions = msg.mentions.len()
+                    + msg.mention_roles.len()
+                    + if msg.mention_everyone { 1 } else { 0 };
+                if mentions >= self.threshold as usize {
+                    Some(format!("mentioned {} users and roles", mentions))
+                } else {
+                    None
+                }
+            }
+            RuleKind::Caps => {
+                let letters = content.chars().filter(|c| c.is_alphabetic()).count();
+                let capitals = content.chars().filter(|c| c.is_uppercase()).count();
+                let limit = letters * self.threshold as usize;
+                if letters >= CAPS_MIN_LETTERS && capitals * 100 >= limit {
+                    Some(format!("wrote {}% in capitals", capitals * 100 / letters))
+                } else {
+                    None
+                }
+            }
+            RuleKind::Zalgo => {
+                if longest_combining_run(content) >= self.threshold as usize {
+                    Some(String::from("used zalgo text"))
+                } else {
+                    None
+                }
+            }
+            RuleKind::Spam => None,
+        }
+    }
+}
+
+/// The compiled rules of a single server.
+#[derive(Debug, Default)]
+pub struct GuildRules {
+    pub rules: Vec<CompiledRule>,
+}
+
+impl GuildRules {
+    /// Gets the rules of a server, looking them up if they aren't cached.
+    pub fn new(guild: u64, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Result<Arc<RwLock<Self>>> {
+        get_or_load::<AutomodContainer, _>(sharemap, guild, || {
+            let pgconn = crate::database::connection(sharemap)?;
+            let rules = list(&pgconn, guild)?
+                .iter()
+                .filter_map(CompiledRule::new)
+                .collect();
+            Ok(GuildRules { rules })
+        })
+    }
+
+    /// Drops the cached rules of a server, so changes to them are picked up.
+    pub fn evict(guild: u64, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Result<()> {
+        sharemap
+            .write()
+            .get_mut::<AutomodContainer>()
+            .failure()?
+            .remove(&guild);
+        Ok(())
+    }
+}
+
+/// The messages members sent within the spam window, by server and member.
+pub struct SpamTracker {
+    recent: LruCache<(u64, u64), VecDeque<(Instant, String)>>,
+    window: Duration,
+}
+
+impl SpamTracker {
+    pub fn new(window: Duration) -> Self {
+        SpamTracker {
+            recent: LruCache::with_expiry_duration(window),
+            window,
+        }
+    }
+
+    /// Records a message of a member, returning how many times they sent it
+    /// within the window, including this time.
+    pub fn record(&mut self, guild: u64, user: u64, content: &str) -> usize {
+        let now = Instant::now();
+        let window = self.window;
+        let content = content.trim().to_lowercase();
+        let mut messages = self.recent.remove(&(guild, user)).unwrap_or_default();
+        while messages
+            .front()
+            .map(|(at, _)| now.duration_since(*at) > window)
+            .unwrap_or(false)
+        {
+            messages.pop_front();
+        }
+        messages.push_back((now, content.clone()));
+        let count = messages.iter().filter(|(_, c)| *c == content).count();
+        self.recent.insert((guild, user), messages);
+        count
+    }
+
+    /// Forgets the messages of a member, so the same spam isn't punished twice.
+    pub fn clear(&mut self, guild: u64, user: u64) {
+        self.recent.remove(&(guild, user));
+    }
+}
+
+/// Compiles a banned word, or a regular expression if it's between slashes.
+///
+/// Both are matched regardless of case, and words only as a whole.
+pub fn compile_pattern(pattern: &str) -> std::result::Result<Regex, regex::Error> {
+    let source = if pattern.len() > 2 && pattern.starts_with('/') && pattern.ends_with('/') {
+        pattern[1..pattern.len() - 1].to_owned()
+    } else {
+        let word = |c: Option<char>| c.map(|c| c.is_alphanumeric() || c == '_').unwrap_or(false);
+        format!(
+            "{}{}{}",
+            if word(pattern.chars().next()) {
+                r"\b"
+            } else {
+                ""
+            },
+            regex::escape(pattern),
+            if word(pattern.chars().last()) {
+                r"\b"
+            } else {
+                ""
+            },
+        )
+    };
+    RegexBuilder::new(&source)
+        .case_insensitive(true)
+        .size_limit(PATTERN_SIZE_LIMIT)
+        .build()
+}
+
+/// The most combining marks in a row in the text.
+fn longest_combining_run(text: &str) -> usize {
+    let combining = |c: char| match c as u32 {
+        0x0300..=0x036f | 0x1ab0..=0x1aff | 0x1dc0..=0x1dff | 0x20d0..=0x20ff | 0xfe20..=0xfe2f => {
+            true
+        }
+        _ => false,
+    };
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        if combining(c) {
+            run += 1;
+            longest = longest.max(run);
+        } else {
+            run = 0;
+        }
+    }
+    longest
+}
+
+pub fn find(conn: &PgConnection, guild: u64, rule: RuleKind) -> QueryResult<Option<AutomodRule>> {
+    use crate::scheme::automod_rules::dsl::*;
+
+    automod_rules
+        .filter(guild_id.eq(guild as i64))
+        .filter(kind.eq(rule.as_str()))
+        .first(conn)
+        .optional()
+}
+
+/// Every rule of a server, ordered by kind.
+pub fn list(conn: &PgConnection, guild: u64) -> QueryResult<Vec<AutomodRule>> {
+    use crate::scheme::automod_rules::dsl::*;
+
+    automod_rules
+        .filter(guild_id.eq(guild as i64))
+        .order(kind.asc())
+        .load(conn)
+}
+
+/// Saves a rule of a server, replacing its previous version.
+pub fn save(conn: &PgConnection, rule: &AutomodRule, actor: Actor) -> QueryResult<()> {
+    use crate::scheme::automod_rules::dsl::*;
+
+    let row = AutomodRule {
+        author_id: actor.id.map(|a| a as i64),
+        updated_at: Utc::now(),
+        ..rule.clone()
+    };
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::insert_into(automod_rules)
+            .values(&row)
+            .on_conflict((guild_id, kind))
+            .do_update()
+            .set((
+                action.eq(&row.action),
+                patterns.eq(&row.patterns),
+                threshold.eq(row.threshold),
+                timeout_seconds.eq(row.timeout_seconds),
+                exempt_roles.eq(&row.exempt_roles),
+                exempt_channels.eq(&row.exempt_channels),
+                author_id.eq(row.author_id),
+                updated_at.eq(row.updated_at),
+            ))
+            .execute(conn)?;
+        audit::record(
+            conn,
+            AuditTarget::Server,
+            row.guild_id as u64,
+            &[PendingChange::new(
+                actor,
+                "automod_rule_set",
+                "",
+                format!("{}: {}", row.kind, row.action),
+            )],
+        )
+    })
+}
+
+/// Removes a rule of a server, returning whether there was one.
+pub fn remove(conn: &PgConnection, guild: u64, rule: RuleKind, actor: Actor) -> QueryResult<bool> {
+    use crate::scheme::automod_rules::dsl::*;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let deleted = diesel::delete(
+            automod_rules
+                .filter(guild_id.eq(guild as i64))
+                .filter(kind.eq(rule.as_str())),
+        )
+        .execute(conn)?;
+        if deleted == 0 {
+            return Ok(false);
+        }
+        audit::record(
+            conn,
+            AuditTarget::Server,
+            guild,
+            &[PendingChange::new(actor, "automod_rule_removed", rule, "")],
+        )?;
+        Ok(true)
+    })
+}
+
+/// Makes the rules of a server those given, only touching the rules which
+/// differ.
+pub fn replace_all(
+    conn: &PgConnection,
+    guild: u64,
+    rules: &[AutomodRule],
+    actor: Actor,
+) -> QueryResult<()> {
+    let same = |a: &AutomodRule, b: &AutomodRule| {
+        a.action == b.action
+            && a.patterns == b.patterns
+            && a.threshold == b.threshold
+            && a.timeout_seconds == b.timeout_seconds
+            && a.exempt_roles == b.exempt_roles
+            && a.exempt_channels == b.exempt_channels
+    };
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let current = list(conn, guild)?;
+        for rule in &current {
+            if !rules.iter().any(|r| r.kind == rule.kind) {
+                if let Ok(rule) = rule.kind.parse() {
+                    remove(conn, guild, rule, actor)?;
+                }
+            }
+        }
+        for rule in rules {
+            match current.iter().find(|r| r.kind == rule.kind) {
+                Some(r) if same(r, rule) => {}
+                _ => save(conn, rule, actor)?,
+            }
+        }
+        Ok(())
+    })
+}
+
+guild_rows!(automod_rules => AutomodRule, author_id, order by kind);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_only_match_whole_words() {
+        let pattern = compile_pattern("cat").unwrap();
+        assert!(pattern.is_match("a cat sat there"));
+        assert!(pattern.is_match("CAT!"));
+        assert!(!pattern.is_match("concatenate"));
+        assert!(!pattern.is_match("cats"));
+    }
+
+    #[test]
+    fn words_only_need_boundaries_at_word_characters() {
+        let pattern = compile_pattern("c++").unwrap();
+        assert!(pattern.is_match("I write c++ for fun"));
+        assert!(pattern.is_match("c++."));
+        assert!(!pattern.is_match("abc++"));
+    }
+
+    #[test]
+    fn words_are_matched_literally() {
+        let pattern = compile_pattern("a.b").unwrap();
+        assert!(pattern.is_match("a.b"));
+        assert!(!pattern.is_match("axb"));
+        // Too short to be a regular expression
+        assert!(compile_pattern("//").unwrap().is_match("see //"));
+    }
+
+    #[test]
+    fn regexes_match_anywhere() {
+        let pattern = compile_pattern("/cat/").unwrap();
+        assert!(pattern.is_match("concatenate"));
+        let pattern = compile_pattern("/^hi/").unwrap();
+        assert!(pattern.is_match("Hi there"));
+        assert!(!pattern.is_match("oh hi"));
+    }
+
+    #[test]
+    fn rejects_invalid_regexes() {
+        assert!(compile_pattern("/(/").is_err());
+        assert!(compile_pattern("(").is_ok());
+    }
+
+    #[test]
+    fn counts_the_longest_run_of_combining_marks() {
+        assert_eq!(longest_combining_run(""), 0);
+        assert_eq!(longest_combining_run("hello"), 0);
+        assert_eq!(longest_combining_run("caf\u{301}e"), 1);
+        assert_eq!(
+            longest_combining_run("z\u{300}\u{301}\u{302}a\u{36f}\u{1dc0}"),
+            3
+        );
+        // Marks of separate letters don't add up
+        assert_eq!(longest_combining_run("a\u{300}\u{301}b\u{302}\u{303}"), 2);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::{config::ResilienceConfiguration, prelude::*};
+use parking_lot::Mutex;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use typemap::Key as TypeMapKey;
+
+pub struct CircuitBreakerContainer;
+
+impl TypeMapKey for CircuitBreakerContainer {
+    type Value = Arc<Mutex<CircuitBreaker>>;
+}
+
+/// Keeps track of failing database lookups, and stops trying for a while once
+/// too many of them have failed in a row.
+pub struct CircuitBreaker {
+    config: ResilienceConfiguration,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: ResilienceConfiguration) -> Self {
+        CircuitBreaker {
+            config,
+            consecutive_failures: 0,
+            opened_at: None,
+        }
+    }
+
+    pub fn config(&self) -> &ResilienceConfiguration {
+        &self.config
+    }
+
+    /// Whether a lookup may be attempted right now.
+    ///
+    /// Once the cooldown has passed, lookups are let through again; a single
+    /// failure then reopens the breaker.
+    pub fn allow(&self) -> bool {
+        match self.opened_at {
+            None => true,
+            Some(opened) => {
+                opened.elapsed() >= Duration::from_secs(*self.config.breaker_cooldown_seconds())
+            }
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.opened_at.is_some()
+    }
+
+    pub fn record_success(&mut self) {
+        if self.opened_at.is_some() {
+            info!("The database is reachable again; closing the circuit breaker.");
+        }
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.opened_at.is_some() || self.consecutive_failures >= *self.config.breaker_threshold()
+        {
+            if self.opened_at.is_none() {
+                warn!(
+                    "{} database lookups failed in a row; opening the circuit breaker.",
+                    self.consecutive_failures
+                );
+            }
+            self.opened_at = Some(Instant::now());
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use crate::config::Configuration;
+use std::sync::Arc;
+use typemap::Key as TypeMapKey;
+
+pub struct ConfigurationContainer;
+
+impl TypeMapKey for ConfigurationContainer {
+    type Value = Arc<Configuration>;
+}
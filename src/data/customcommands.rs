@@ -0,0 +1,183 @@
+use super::audit::{self, Actor, AuditTarget, PendingChange};
+use crate::{prelude::*, scheme::custom_commands};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A command defined by the admins of a server, whose body is a ketos script.
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Insertable)]
+#[table_name = "custom_commands"]
+pub struct CustomCommand {
+    pub guild_id: i64,
+    pub name: String,
+    pub source: String,
+    pub author_id: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+pub fn find(conn: &PgConnection, guild: u64, command: &str) -> QueryResult<Option<CustomCommand>> {
+    use crate::scheme::custom_commands::dsl::*;
+
+    custom_commands
+        .filter(guild_id.eq(guild as i64))
+        .filter(name.eq(command))
+        .first(conn)
+        .optional()
+}
+
+/// Every command of a server, ordered by name.
+pub fn list(conn: &PgConnection, guild: u64) -> QueryResult<Vec<CustomCommand>> {
+    use crate::scheme::custom_commands::dsl::*;
+
+    custom_commands
+        .filter(guild_id.eq(guild as i64))
+        .order(name.asc())
+        .load(conn)
+}
+
+pub fn count(conn: &PgConnection, guild: u64) -> QueryResult<i64> {
+    use crate::scheme::custom_commands::dsl::*;
+
+    custom_commands
+        .filter(guild_id.eq(guild as i64))
+        .count()
+        .get_result(conn)
+}
+
+/// Adds a command to a server. Fails if the server already has a command of the
+/// same name.
+pub fn create(
+    conn: &PgConnection,
+    guild: u64,
+    command: &str,
+    code: &str,
+    actor: Actor,
+) -> QueryResult<()> {
+    use crate::scheme::custom_commands::dsl::*;
+
+    let now = Utc::now();
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::insert_into(custom_commands)
+            .values(&CustomCommand {
+                guild_id: guild as i64,
+                name: command.to_owned(),
+                source: code.to_owned(),
+                author_id: actor.id.map(|a| a as i64),
+                created_at: now,
+                updated_at: now,
+            })
+            .execute(conn)?;
+        audit::record(
+            conn,
+            AuditTarget::Server,
+            guild,
+            &[PendingChange::new(
+                actor,
+                "custom_command_created",
+                "",
+                command,
+            )],
+        )
+    })
+}
+
+/// Replaces the source of a command, returning whether it existed.
+pub fn edit(
+    conn: &PgConnection,
+    guild: u64,
+    command: &str,
+    code: &str,
+    actor: Actor,
+) -> QueryResult<bool> {
+    use crate::scheme::custom_commands::dsl::*;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let updated = diesel::update(
+            custom_commands
+                .filter(guild_id.eq(guild as i64))
+                .filter(name.eq(command)),
+        )
+        .set((
+            source.eq(code),
+            author_id.eq(actor.id.map(|a| a as i64)),
+            updated_at.eq(Utc::now()),
+        ))
+        .execute(conn)?;
+        if updated == 0 {
+            return Ok(false);
+        }
+        audit::record(
+            conn,
+            AuditTarget::Server,
+            guild,
+            &[PendingChange::new(
+                actor,
+                "custom_command_edited",
+                "",
+                command,
+            )],
+        )?;
+        Ok(true)
+    })
+}
+
+/// Removes a command from a server, returning whether it existed.
+pub fn delete(conn: &PgConnection, guild: u64, command: &str, actor: Actor) -> QueryResult<bool> {
+    use crate::scheme::custom_commands::dsl::*;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let deleted = diesel::delete(
+            custom_commands
+                .filter(guild_id.eq(guild as i64))
+                .filter(name.eq(command)),
+        )
+        .execute(conn)?;
+        if deleted == 0 {
+            return Ok(false);
+        }
+        audit::record(
+            conn,
+            AuditTarget::Server,
+            guild,
+            &[PendingChange::new(
+                actor,
+                "custom_command_deleted",
+                command,
+                "",
+            )],
+        )?;
+        Ok(true)
+    })
+}
+
+/// Makes the commands of a server those given by name, only touching the commands
+/// which differ.
+pub fn replace_all(
+    conn: &PgConnection,
+    guild: u64,
+    commands: &BTreeMap<String, String>,
+    actor: Actor,
+) -> QueryResult<()> {
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let current = list(conn, guild)?;
+        for command in &current {
+            if !commands.contains_key(&command.name) {
+                delete(conn, guild, &command.name, actor)?;
+            }
+        }
+        for (command, code) in commands {
+            match current.iter().find(|c| &c.name == command) {
+                Some(c) if &c.source == code => {}
+                Some(_) => {
+                    edit(conn, guild, command, code, actor)?;
+                }
+                None => create(conn, guild, command, code, actor)?,
+            }
+        }
+        Ok(())
+    })
+}
+
+guild_rows!(custom_commands => CustomCommand, author_id, order by name);
@@ -0,0 +1,216 @@
+use super::{
+    audit::{self, Actor, AuditTarget, PendingChange},
+    get_or_load, SettingsCache,
+};
+use crate::{prelude::*, scheme::event_hooks};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+use typemap::Key as TypeMapKey;
+
+/// The server ID under which hooks which run in every server are stored.
+pub const GLOBAL: u64 = 0;
+
+/// The hooks of every server which were looked up recently, by server ID.
+pub struct EventHooksContainer;
+
+impl TypeMapKey for EventHooksContainer {
+    type Value = SettingsCache<GuildHooks>;
+}
+
+/// The events hooks can be registered for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HookEvent {
+    MemberJoin,
+    MemberLeave,
+    Message,
+    ReactionAdd,
+}
+
+impl HookEvent {
+    pub const ALL: [HookEvent; 4] = [
+        HookEvent::MemberJoin,
+        HookEvent::MemberLeave,
+        HookEvent::Message,
+        HookEvent::ReactionAdd,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HookEvent::MemberJoin => "member-join",
+            HookEvent::MemberLeave => "member-leave",
+            HookEvent::Message => "message",
+            HookEvent::ReactionAdd => "reaction-add",
+        }
+    }
+}
+
+impl FromStr for HookEvent {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        HookEvent::ALL
+            .iter()
+            .cloned()
+            .find(|e| e.as_str().eq_ignore_ascii_case(s))
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A ketos script run whenever an event happens in a server.
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Insertable)]
+#[table_name = "event_hooks"]
+pub struct EventHook {
+    pub guild_id: i64,
+    pub event: String,
+    pub source: String,
+    pub author_id: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// The sources of the hooks of a single server, by event.
+#[derive(Debug, Default)]
+pub struct GuildHooks {
+    pub sources: HashMap<HookEvent, Arc<String>>,
+}
+
+impl GuildHooks {
+    /// Gets the hooks of a server, looking them up if they aren't cached.
+    pub fn new(guild: u64, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Result<Arc<RwLock<Self>>> {
+        get_or_load::<EventHooksContainer, _>(sharemap, guild, || {
+            let pgconn = crate::database::connection(sharemap)?;
+            let sources = list(&pgconn, guild)?
+                .into_iter()
+                .filter_map(|h| Some((h.event.parse().ok()?, Arc::new(h.source))))
+                .collect();
+            Ok(GuildHooks { sources })
+        })
+    }
+
+    /// Drops the cached hooks of a server, so changes to them are picked up.
+    pub fn evict(guild: u64, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Result<()> {
+        sharemap
+            .write()
+            .get_mut::<EventHooksContainer>()
+            .failure()?
+            .remove(&guild);
+        Ok(())
+    }
+}
+
+pub fn find(conn: &PgConnection, guild: u64, hook: HookEvent) -> QueryResult<Option<EventHook>> {
+    use crate::scheme::event_hooks::dsl::*;
+
+    event_hooks
+        .filter(guild_id.eq(guild as i64))
+        .filter(event.eq(hook.as_str()))
+        .first(conn)
+        .optional()
+}
+
+/// Every hook of a server, ordered by event.
+pub fn list(conn: &PgConnection, guild: u64) -> QueryResult<Vec<EventHook>> {
+    use crate::scheme::event_hooks::dsl::*;
+
+    event_hooks
+        .filter(guild_id.eq(guild as i64))
+        .order(event.asc())
+        .load(conn)
+}
+
+/// Registers the hook of a server for an event, replacing any previous one.
+pub fn set(
+    conn: &PgConnection,
+    guild: u64,
+    hook: HookEvent,
+    code: &str,
+    actor: Actor,
+) -> QueryResult<()> {
+    use crate::scheme::event_hooks::dsl::*;
+
+    let row = EventHook {
+        guild_id: guild as i64,
+        event: hook.as_str().to_owned(),
+        source: code.to_owned(),
+        author_id: actor.id.map(|a| a as i64),
+        updated_at: Utc::now(),
+    };
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::insert_into(event_hooks)
+            .values(&row)
+            .on_conflict((guild_id, event))
+            .do_update()
+            .set((
+                source.eq(&row.source),
+                author_id.eq(row.author_id),
+                updated_at.eq(row.updated_at),
+            ))
+            .execute(conn)?;
+        audit::record(
+            conn,
+            AuditTarget::Server,
+            guild,
+            &[PendingChange::new(actor, "event_hook_set", "", hook)],
+        )
+    })
+}
+
+/// Removes the hook of a server for an event, returning whether there was one.
+pub fn remove(conn: &PgConnection, guild: u64, hook: HookEvent, actor: Actor) -> QueryResult<bool> {
+    use crate::scheme::event_hooks::dsl::*;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let deleted = diesel::delete(
+            event_hooks
+                .filter(guild_id.eq(guild as i64))
+                .filter(event.eq(hook.as_str())),
+        )
+        .execute(conn)?;
+        if deleted == 0 {
+            return Ok(false);
+        }
+        audit::record(
+            conn,
+            AuditTarget::Server,
+            guild,
+            &[PendingChange::new(actor, "event_hook_removed", hook, "")],
+        )?;
+        Ok(true)
+    })
+}
+
+/// Makes the hooks of a server those given, only touching the hooks which
+/// differ.
+pub fn replace_all(
+    conn: &PgConnection,
+    guild: u64,
+    hooks: &[(HookEvent, &str)],
+    actor: Actor,
+) -> QueryResult<()> {
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let current = list(conn, guild)?;
+        for hook in HookEvent::ALL.iter().cloned() {
+            let code = hooks.iter().find(|(e, _)| *e == hook).map(|(_, c)| *c);
+            let existing = current.iter().find(|h| h.event == hook.as_str());
+            match (existing, code) {
+                (Some(h), Some(code)) if h.source == code => {}
+                (_, Some(code)) => set(conn, guild, hook, code, actor)?,
+                (Some(_), None) => {
+                    remove(conn, guild, hook, actor)?;
+                }
+                (None, None) => {}
+            }
+        }
+        Ok(())
+    })
+}
+
+guild_rows!(event_hooks => EventHook, author_id, order by event);
@@ -0,0 +1,219 @@
+use super::{
+    audit::{self, Actor, AuditTarget, PendingChange},
+    get_or_load, SettingsCache,
+};
+use crate::{prelude::*, scheme::greetings};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+use typemap::Key as TypeMapKey;
+
+/// The longest a template may be, in characters, leaving room for what its
+/// placeholders are replaced with.
+pub const TEMPLATE_LENGTH_LIMIT: usize = 1000;
+
+/// The greetings of every server which were looked up recently, by server ID.
+pub struct GreetingsContainer;
+
+impl TypeMapKey for GreetingsContainer {
+    type Value = SettingsCache<GuildGreetings>;
+}
+
+/// When greetings are sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GreetingKind {
+    /// When a member joins.
+    Welcome,
+    /// When a member leaves.
+    Farewell,
+}
+
+impl GreetingKind {
+    pub const ALL: [GreetingKind; 2] = [GreetingKind::Welcome, GreetingKind::Farewell];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GreetingKind::Welcome => "welcome",
+            GreetingKind::Farewell => "farewell",
+        }
+    }
+}
+
+impl FromStr for GreetingKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        GreetingKind::ALL
+            .iter()
+            .cloned()
+            .find(|k| k.as_str().eq_ignore_ascii_case(s))
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for GreetingKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A message a server sends when members join or leave.
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Insertable)]
+#[table_name = "greetings"]
+pub struct Greeting {
+    pub guild_id: i64,
+    pub kind: String,
+    pub channel_id: Option<i64>,
+    pub template: String,
+    pub embed: bool,
+    pub author_id: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// The greetings of a single server, by kind.
+#[derive(Debug, Default)]
+pub struct GuildGreetings {
+    pub greetings: HashMap<GreetingKind, Greeting>,
+}
+
+impl GuildGreetings {
+    /// Gets the greetings of a server, looking them up if they aren't cached.
+    pub fn new(guild: u64, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Result<Arc<RwLock<Self>>> {
+        get_or_load::<GreetingsContainer, _>(sharemap, guild, || {
+            let pgconn = crate::database::connection(sharemap)?;
+            let greetings = list(&pgconn, guild)?
+                .into_iter()
+                .filter_map(|g| Some((g.kind.parse().ok()?, g)))
+                .collect();
+            Ok(GuildGreetings { greetings })
+        })
+    }
+
+    /// Drops the cached greetings of a server, so changes to them are picked up.
+    pub fn evict(guild: u64, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Result<()> {
+        sharemap
+            .write()
+            .get_mut::<GreetingsContainer>()
+            .failure()?
+            .remove(&guild);
+        Ok(())
+    }
+}
+
+pub fn find(
+    conn: &PgConnection,
+    guild: u64,
+    greeting: GreetingKind,
+) -> QueryResult<Option<Greeting>> {
+    use crate::scheme::greetings::dsl::*;
+
+    greetings
+        .filter(guild_id.eq(guild as i64))
+        .filter(kind.eq(greeting.as_str()))
+        .first(conn)
+        .optional()
+}
+
+/// Every greeting of a server, ordered by kind.
+pub fn list(conn: &PgConnection, guild: u64) -> QueryResult<Vec<Greeting>> {
+    use crate::scheme::greetings::dsl::*;
+
+    greetings
+        .filter(guild_id.eq(guild as i64))
+        .order(kind.asc())
+        .load(conn)
+}
+
+/// Saves a greeting of a server, replacing its previous version.
+pub fn save(conn: &PgConnection, greeting: &Greeting, actor: Actor) -> QueryResult<()> {
+    use crate::scheme::greetings::dsl::*;
+
+    let row = Greeting {
+        author_id: actor.id.map(|a| a as i64),
+        updated_at: Utc::now(),
+        ..greeting.clone()
+    };
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::insert_into(greetings)
+            .values(&row)
+            .on_conflict((guild_id, kind))
+            .do_update()
+            .set((
+                channel_id.eq(row.channel_id),
+                template.eq(&row.template),
+                embed.eq(row.embed),
+                author_id.eq(row.author_id),
+                updated_at.eq(row.updated_at),
+            ))
+            .execute(conn)?;
+        audit::record(
+            conn,
+            AuditTarget::Server,
+            row.guild_id as u64,
+            &[PendingChange::new(actor, "greeting_set", "", &row.kind)],
+        )
+    })
+}
+
+/// Removes a greeting of a server, returning whether there was one.
+pub fn remove(
+    conn: &PgConnection,
+    guild: u64,
+    greeting: GreetingKind,
+    actor: Actor,
+) -> QueryResult<bool> {
+    use crate::scheme::greetings::dsl::*;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let deleted = diesel::delete(
+            greetings
+                .filter(guild_id.eq(guild as i64))
+                .filter(kind.eq(greeting.as_str())),
+        )
+        .execute(conn)?;
+        if deleted == 0 {
+            return Ok(false);
+        }
+        audit::record(
+            conn,
+            AuditTarget::Server,
+            guild,
+            &[PendingChange::new(actor, "greeting_removed", greeting, "")],
+        )?;
+        Ok(true)
+    })
+}
+
+/// Makes the greetings of a server those given, only touching the greetings
+/// which differ.
+pub fn replace_all(
+    conn: &PgConnection,
+    guild: u64,
+    replacements: &[Greeting],
+    actor: Actor,
+) -> QueryResult<()> {
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let current = list(conn, guild)?;
+        for greeting in &current {
+            if !replacements.iter().any(|g| g.kind == greeting.kind) {
+                if let Ok(greeting) = greeting.kind.parse() {
+                    remove(conn, guild, greeting, actor)?;
+                }
+            }
+        }
+        for greeting in replacements {
+            match current.iter().find(|g| g.kind == greeting.kind) {
+                Some(g)
+                    if g.channel_id == greeting.channel_id
+                        && g.template == greeting.template
+                        && g.embed == greeting.embed => {}
+                _ => save(conn, greeting, actor)?,
+            }
+        }
+        Ok(())
+    })
+}
+
+guild_rows!(greetings => Greeting, author_id, order by kind);
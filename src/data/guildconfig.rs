@@ -0,0 +1,837 @@
+use super::{
+    antiraid::{self, RaidProtection},
+    audit::{self, Actor, PendingChange},
+    automod::{self, AutomodRule, GuildRules},
+    customcommands,
+    eventhooks::{self, GuildHooks, HookEvent},
+    greetings::{self, Greeting, GuildGreetings},
+    onboarding::{self, Onboarding},
+    ServerSettings,
+};
+use crate::prelude::*;
+use chrono::Utc;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use serenity::model::{
+    guild::Guild,
+    id::{ChannelId, GuildId, RoleId},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+/// The version of the guild configuration document, bumped whenever a field is
+/// changed or removed.
+pub const GUILD_CONFIG_VERSION: u32 = 1;
+
+/// The longest a value is shown in a diff, in characters.
+const DIFF_VALUE_LENGTH: usize = 60;
+
+/// All configuration of a guild, as exported and imported by admins.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GuildConfig {
+    pub version: u32,
+    /// The name of the guild the document was exported from, for reference.
+    #[serde(default)]
+    pub guild_name: String,
+    #[serde(default)]
+    pub settings: GuildSettings,
+}
+
+/// The settings of a guild which may be carried over to another guild.
+///
+/// Sections which are `None` weren't in the document they were read from, and
+/// importing them leaves those sections of the guild as they are.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct GuildSettings {
+    pub hooks_enabled: bool,
+    pub hook_error_channel: Option<Reference>,
+    pub mute_role: Option<Reference>,
+    pub modlog_channel: Option<Reference>,
+    pub message_log_channel: Option<Reference>,
+    pub message_log_ignored: Vec<Reference>,
+    /// The code of every custom command, by name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_commands: Option<BTreeMap<String, String>>,
+    /// The code of every hook, by event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hooks: Option<BTreeMap<String, String>>,
+    /// Every automod rule, by what it looks for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub automod: Option<BTreeMap<String, AutomodRuleSettings>>,
+    /// How the guild is protected from raids.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raid_protection: Option<RaidProtectionSettings>,
+    /// The messages sent when members join or leave, by when they're sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub greetings: Option<BTreeMap<String, GreetingSettings>>,
+    /// The roles given to joining members, and how they verify themselves.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub onboarding: Option<OnboardingSettings>,
+}
+
+impl Default for GuildSettings {
+    fn default() -> Self {
+        GuildSettings {
+            hooks_enabled: true,
+            hook_error_channel: None,
+            mute_role: None,
+            modlog_channel: None,
+            message_log_channel: None,
+            message_log_ignored: Vec::new(),
+            custom_commands: None,
+            hooks: None,
+            automod: None,
+            raid_protection: None,
+            greetings: None,
+            onboarding: None,
+        }
+    }
+}
+
+/// An automod rule of a guild, without who last changed it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AutomodRuleSettings {
+    pub action: String,
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    pub threshold: Option<i32>,
+    pub timeout_seconds: Option<i64>,
+    #[serde(default)]
+    pub exempt_roles: Vec<Reference>,
+    #[serde(default)]
+    pub exempt_channels: Vec<Reference>,
+}
+
+/// How a guild is protected from raids, without the raid it is in.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RaidProtectionSettings {
+    pub enabled: bool,
+    pub join_limit: i32,
+    pub join_window_seconds: i32,
+    pub similar_limit: i32,
+    pub min_account_age_seconds: Option<i64>,
+    pub response: String,
+}
+
+/// A message a guild sends when members join or leave, without who last changed
+/// it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GreetingSettings {
+    /// The channel the message is sent to, or `None` to send it to the member.
+    pub channel: Option<Reference>,
+    pub template: String,
+    #[serde(default)]
+    pub embed: bool,
+}
+
+/// The roles a guild gives joining members, and how they verify themselves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OnboardingSettings {
+    #[serde(default)]
+    pub auto_roles: Vec<Reference>,
+    pub role_delay_seconds: Option<i64>,
+    pub verification: Option<String>,
+    pub verification_channel: Option<Reference>,
+    pub verification_timeout_seconds: Option<i64>,
+}
+
+/// A channel or role referenced by the configuration, along with its name so it
+/// can be found again in another guild.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub id: u64,
+    pub name: String,
+}
+
+/// The format a configuration document is written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DocumentFormat {
+    Json,
+    Toml,
+}
+
+impl DocumentFormat {
+    /// Picks the format from the extension of a file name, defaulting to JSON.
+    pub fn from_file_name(name: &str) -> Self {
+        if name.to_lowercase().ends_with(".toml") {
+            DocumentFormat::Toml
+        } else {
+            DocumentFormat::Json
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            DocumentFormat::Json => "json",
+            DocumentFormat::Toml => "toml",
+        }
+    }
+}
+
+impl GuildConfig {
+    /// Gathers the current configuration of a guild.
+    pub fn capture(guild: &Guild, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Result<Self> {
+        let (custom_commands, hooks, rules, greetings) = {
+            let pgconn = crate::database::connection(sharemap)?;
+            let custom_commands = customcommands::list(&pgconn, guild.id.0)?
+                .into_iter()
+                .map(|c| (c.name, c.source))
+                .collect();
+            let hooks = eventhooks::list(&pgconn, guild.id.0)?
+                .into_iter()
+                .map(|h| (h.event, h.source))
+                .collect();
+            let rules = automod::list(&pgconn, guild.id.0)?;
+            let greetings = greetings::list(&pgconn, guild.id.0)?
+                .into_iter()
+                .map(|g| {
+                    let settings = GreetingSettings {
+                        channel: g
+                            .channel_id
+                            .map(|id| channel_reference(guild, ChannelId(id as u64))),
+                        template: g.template,
+                        embed: g.embed,
+                    };
+                    (g.kind, settings)
+                })
+                .collect();
+            (custom_commands, hooks, rules, greetings)
+        };
+        let protection = RaidProtection::new(guild.id.0, sharemap)?.read().clone();
+        let onboarding = Onboarding::new(guild.id.0, sharemap)?.read().clone();
+        let settings = ServerSettings::new(guild.id.0, sharemap)?;
+        let settings = settings.read();
+
+        Ok(GuildConfig {
+            version: GUILD_CONFIG_VERSION,
+            guild_name: guild.name.clone(),
+            settings: GuildSettings {
+                hooks_enabled: *settings.hooks_enabled(),
+                hook_error_channel: settings
+                    .hook_error_channel()
+                    .as_ref()
+                    .map(|&id| channel_reference(guild, ChannelId(id))),
+                mute_role: settings
+                    .mute_role()
+                    .as_ref()
+                    .map(|&id| role_reference(guild, RoleId(id))),
+                modlog_channel: settings
+                    .modlog_channel()
+                    .as_ref()
+                    .map(|&id| channel_reference(guild, ChannelId(id))),
+                message_log_channel: settings
+                    .message_log_channel()
+                    .as_ref()
+                    .map(|&id| channel_reference(guild, ChannelId(id))),
+                message_log_ignored: settings
+                    .message_log_ignored()
+                    .iter()
+                    .map(|&id| channel_reference(guild, ChannelId(id)))
+                    .collect(),
+                custom_commands: Some(custom_commands),
+                hooks: Some(hooks),
+                automod: Some(
+                    rules
+                        .into_iter()
+                        .map(|r| {
+                            let settings = AutomodRuleSettings {
+                                action: r.action,
+                                patterns: r.patterns,
+                                threshold: r.threshold,
+                                timeout_seconds: r.timeout_seconds,
+                                exempt_roles: r
+                                    .exempt_roles
+                                    .iter()
+                                    .map(|&id| role_reference(guild, RoleId(id as u64)))
+                                    .collect(),
+                                exempt_channels: r
+                                    .exempt_channels
+                                    .iter()
+                                    .map(|&id| channel_reference(guild, ChannelId(id as u64)))
+                                    .collect(),
+                            };
+                            (r.kind, settings)
+                        })
+                        .collect(),
+                ),
+                raid_protection: Some(RaidProtectionSettings {
+                    enabled: protection.enabled,
+                    join_limit: protection.join_limit,
+                    join_window_seconds: protection.join_window_seconds,
+                    similar_limit: protection.similar_limit,
+                    min_account_age_seconds: protection.min_account_age_seconds,
+                    response: protection.response,
+                }),
+                greetings: Some(greetings),
+                onboarding: Some(OnboardingSettings {
+                    auto_roles: onboarding
+                        .auto_roles
+                        .iter()
+                        .map(|&id| role_reference(guild, RoleId(id as u64)))
+                        .collect(),
+                    role_delay_seconds: onboarding.role_delay_seconds,
+                    verification: onboarding.verification,
+                    verification_channel: onboarding
+                        .verification_channel_id
+                        .map(|id| channel_reference(guild, ChannelId(id as u64))),
+                    verification_timeout_seconds: onboarding.verification_timeout_seconds,
+                }),
+            },
+        })
+    }
+
+    pub fn serialize(&self, format: DocumentFormat) -> Result<String> {
+        Ok(match format {
+            DocumentFormat::Json => serde_json::to_string_pretty(self)?,
+            // TOML needs plain values written before tables, which only its own
+            // values take care of.
+            DocumentFormat::Toml => toml::to_string_pretty(&toml::Value::try_from(self)?)?,
+        })
+    }
+
+    pub fn deserialize(content: &str, format: DocumentFormat) -> Result<Self> {
+        let config: GuildConfig = match format {
+            DocumentFormat::Json => serde_json::from_str(content)?,
+            DocumentFormat::Toml => toml::from_str(content)?,
+        };
+        if config.version > GUILD_CONFIG_VERSION {
+            return Err(StdErrorKind::StringValue(format!(
+                "The document is of version {}, but only up to {} is supported.",
+                config.version, GUILD_CONFIG_VERSION
+            ))
+            .into());
+        }
+        Ok(config)
+    }
+}
+
+/// Finds channels and roles of an imported configuration in the guild it is
+/// imported into, by ID if it exists and by name otherwise.
+pub struct Remapper {
+    /// The names of the channels of the guild, by ID.
+    channels: HashMap<u64, String>,
+    /// The names of the roles of the guild, by ID.
+    roles: HashMap<u64, String>,
+    /// Every reference which couldn't be found.
+    pub unresolved: Vec<String>,
+}
+
+impl Remapper {
+    pub fn new(guild: &Guild) -> Self {
+        Remapper::with_names(
+            guild
+                .channels
+                .iter()
+                .map(|(id, c)| (id.0, c.read().name.clone()))
+                .collect(),
+            guild
+                .roles
+                .iter()
+                .map(|(id, r)| (id.0, r.name.clone()))
+                .collect(),
+        )
+    }
+
+    fn with_names(channels: HashMap<u64, String>, roles: HashMap<u64, String>) -> Self {
+        Remapper {
+            channels,
+            roles,
+            unresolved: Vec::new(),
+        }
+    }
+
+    pub fn channel(&mut self, reference: &Reference) -> Option<Reference> {
+        let found = find(&self.channels, reference);
+        if found.is_none() {
+            self.unresolved.push(format!("channel #{}", reference.name));
+        }
+        found
+    }
+
+    pub fn role(&mut self, reference: &Reference) -> Option<Reference> {
+        let found = find(&self.roles, reference);
+        if found.is_none() {
+            self.unresolved.push(format!("role @{}", reference.name));
+        }
+        found
+    }
+}
+
+/// Finds what a reference points to among the names by ID, by its ID if it is
+/// there and by its name otherwise.
+fn find(names: &HashMap<u64, String>, reference: &Reference) -> Option<Reference> {
+    if names.contains_key(&reference.id) {
+        return Some(reference.clone());
+    }
+    names
+        .iter()
+        .find(|(_, name)| **name == reference.name)
+        .map(|(&id, name)| Reference {
+            id,
+            name: name.clone(),
+        })
+}
+
+impl GuildSettings {
+    /// Points every channel and role of the settings to those of the guild the
+    /// remapper was made for.
+    pub fn remap(self, remapper: &mut Remapper) -> Self {
+        GuildSettings {
+            hook_error_channel: self.hook_error_channel.and_then(|c| remapper.channel(&c)),
+            mute_role: self.mute_role.and_then(|r| remapper.role(&r)),
+            modlog_channel: self.modlog_channel.and_then(|c| remapper.channel(&c)),
+            message_log_channel: self.message_log_channel.and_then(|c| remapper.channel(&c)),
+            message_log_ignored: self
+                .message_log_ignored
+                .iter()
+                .filter_map(|c| remapper.channel(c))
+                .collect(),
+            automod: self.automod.map(|rules| {
+                rules
+                    .into_iter()
+                    .map(|(kind, rule)| {
+                        let rule = AutomodRuleSettings {
+                            exempt_roles: rule
+                                .exempt_roles
+                                .iter()
+                                .filter_map(|r| remapper.role(r))
+                                .collect(),
+                            exempt_channels: rule
+                                .exempt_channels
+                                .iter()
+                                .filter_map(|c| remapper.channel(c))
+                                .collect(),
+                            ..rule
+                        };
+                        (kind, rule)
+                    })
+                    .collect()
+            }),
+            // Greetings are left out rather than sent to members instead of to a
+            // channel which can't be found.
+            greetings: self.greetings.map(|greetings| {
+                greetings
+                    .into_iter()
+                    .filter_map(|(kind, greeting)| {
+                        let channel = match &greeting.channel {
+                            Some(c) => Some(remapper.channel(c)?),
+                            None => None,
+                        };
+                        Some((
+                            kind,
+                            GreetingSettings {
+                                channel,
+                                ..greeting
+                            },
+                        ))
+                    })
+                    .collect()
+            }),
+            onboarding: self.onboarding.map(|onboarding| {
+                let auto_roles = onboarding
+                    .auto_roles
+                    .iter()
+                    .filter_map(|r| remapper.role(r))
+                    .collect::<Vec<_>>();
+                let verification_channel = onboarding
+                    .verification_channel
+                    .and_then(|c| remapper.channel(&c));
+                // Members can't be gated without a channel to ask them in, nor
+                // let in without roles to give them.
+                let verification = if verification_channel.is_none() || auto_roles.is_empty() {
+                    None
+                } else {
+                    onboarding.verification
+                };
+                OnboardingSettings {
+                    auto_roles,
+                    verification,
+                    verification_channel,
+                    ..onboarding
+                }
+            }),
+            ..self
+        }
+    }
+
+    /// Lists the differences between two sets of settings, one line per setting.
+    ///
+    /// Sections the other settings don't have are left out, as importing them
+    /// leaves those sections as they are.
+    pub fn diff(&self, other: &GuildSettings) -> Result<Vec<String>> {
+        let old = serde_json::to_value(self)?;
+        let new = serde_json::to_value(other)?;
+        let mut changes = Vec::new();
+        if let (JsonValue::Object(old), JsonValue::Object(new)) = (old, new) {
+            for (key, value) in &new {
+                diff_value(
+                    key,
+                    old.get(key).unwrap_or(&JsonValue::Null),
+                    value,
+                    &mut changes,
+                );
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Replaces the configuration of a guild with these settings.
+    pub fn apply(
+        &self,
+        guild: GuildId,
+        sharemap: &Arc<RwLock<typemap::ShareMap>>,
+        actor: Actor,
+    ) -> Result<()> {
+        {
+            let settings = ServerSettings::new(guild.0, sharemap)?;
+            let mut write = settings.write();
+            write.set_hooks_enabled(self.hooks_enabled, actor);
+            write.set_hook_error_channel(self.hook_error_channel.as_ref().map(|c| c.id), actor);
+            write.set_mute_role(self.mute_role.as_ref().map(|r| r.id), actor);
+            write.set_modlog_channel(self.modlog_channel.as_ref().map(|c| c.id), actor);
+            write.set_message_log_channel(self.message_log_channel.as_ref().map(|c| c.id), actor);
+            write.set_message_log_ignored(
+                self.message_log_ignored.iter().map(|c| c.id).collect(),
+                actor,
+            );
+            write.save()?;
+        }
+
+        if let Some(commands) = &self.custom_commands {
+            let pgconn = crate::database::connection(sharemap)?;
+            customcommands::replace_all(&pgconn, guild.0, commands, actor)?;
+        }
+        if let Some(hooks) = &self.hooks {
+            // Events which don't exist are turned down before importing
+            let hooks = hooks
+                .iter()
+                .filter_map(|(event, code)| Some((event.parse().ok()?, code.as_str())))
+                .collect::<Vec<(HookEvent, &str)>>();
+            {
+                let pgconn = crate::database::connection(sharemap)?;
+                eventhooks::replace_all(&pgconn, guild.0, &hooks, actor)?;
+            }
+            GuildHooks::evict(guild.0, sharemap)?;
+        }
+        if let Some(rules) = &self.automod {
+            let rules = rules
+                .iter()
+                .map(|(kind, rule)| AutomodRule {
+                    guild_id: guild.0 as i64,
+                    kind: kind.clone(),
+                    action: rule.action.clone(),
+                    patterns: rule.patterns.clone(),
+                    threshold: rule.threshold,
+                    timeout_seconds: rule.timeout_seconds,
+                    exempt_roles: rule.exempt_roles.iter().map(|r| r.id as i64).collect(),
+                    exempt_channels: rule.exempt_channels.iter().map(|c| c.id as i64).collect(),
+                    author_id: None,
+                    updated_at: Utc::now(),
+                })
+                .collect::<Vec<_>>();
+            {
+                let pgconn = crate::database::connection(sharemap)?;
+                automod::replace_all(&pgconn, guild.0, &rules, actor)?;
+            }
+            GuildRules::evict(guild.0, sharemap)?;
+        }
+        if let Some(imported) = &self.raid_protection {
+            let current = RaidProtection::new(guild.0, sharemap)?.read().clone();
+            let protection = RaidProtection {
+                enabled: imported.enabled,
+                join_limit: imported.join_limit,
+                join_window_seconds: imported.join_window_seconds,
+                similar_limit: imported.similar_limit,
+                min_account_age_seconds: imported.min_account_age_seconds,
+                response: imported.response.clone(),
+                ..current.clone()
+            };
+            let changes = vec![
+                PendingChange::new(
+                    actor,
+                    "raid_protection",
+                    current.enabled,
+                    protection.enabled,
+                ),
+                PendingChange::new(
+                    actor,
+                    "raid_join_limit",
+                    format!("{} in {}s", current.join_limit, current.join_window_seconds),
+                    format!(
+                        "{} in {}s",
+                        protection.join_limit, protection.join_window_seconds
+                    ),
+                ),
+                PendingChange::new(
+                    actor,
+                    "raid_similar_limit",
+                    current.similar_limit,
+                    protection.similar_limit,
+                ),
+                PendingChange::new(
+                    actor,
+                    "raid_min_account_age_seconds",
+                    audit::optional(&current.min_account_age_seconds),
+                    audit::optional(&protection.min_account_age_seconds),
+                ),
+                PendingChange::new(
+                    actor,
+                    "raid_response",
+                    &current.response,
+                    &protection.response,
+                ),
+            ]
+            .into_iter()
+            .filter(|c| c.old_value != c.new_value)
+            .collect::<Vec<_>>();
+            if !changes.is_empty() {
+                {
+                    let pgconn = crate::database::connection(sharemap)?;
+                    antiraid::save(&pgconn, &protection, &changes)?;
+                }
+                RaidProtection::evict(guild.0, sharemap)?;
+            }
+        }
+        if let Some(greetings) = &self.greetings {
+            let rows = greetings
+                .iter()
+                .map(|(kind, greeting)| Greeting {
+                    guild_id: guild.0 as i64,
+                    kind: kind.clone(),
+                    channel_id: greeting.channel.as_ref().map(|c| c.id as i64),
+                    template: greeting.template.clone(),
+                    embed: greeting.embed,
+                    author_id: None,
+                    updated_at: Utc::now(),
+                })
+                .collect::<Vec<_>>();
+            {
+                let pgconn = crate::database::connection(sharemap)?;
+                greetings::replace_all(&pgconn, guild.0, &rows, actor)?;
+            }
+            GuildGreetings::evict(guild.0, sharemap)?;
+        }
+        if let Some(imported) = &self.onboarding {
+            let current = Onboarding::new(guild.0, sharemap)?.read().clone();
+            let settings = Onboarding {
+                guild_id: guild.0 as i64,
+                auto_roles: imported.auto_roles.iter().map(|r| r.id as i64).collect(),
+                role_delay_seconds: imported.role_delay_seconds,
+                verification: imported.verification.clone(),
+                verification_channel_id: imported
+                    .verification_channel
+                    .as_ref()
+                    .map(|c| c.id as i64),
+                verification_timeout_seconds: imported.verification_timeout_seconds,
+            };
+            let changes = vec![
+                PendingChange::new(
+                    actor,
+                    "auto_roles",
+                    audit::list(&current.auto_roles),
+                    audit::list(&settings.auto_roles),
+                ),
+                PendingChange::new(
+                    actor,
+                    "auto_role_delay_seconds",
+                    audit::optional(&current.role_delay_seconds),
+                    audit::optional(&settings.role_delay_seconds),
+                ),
+                PendingChange::new(
+                    actor,
+                    "verification",
+                    audit::optional(&current.verification),
+                    audit::optional(&settings.verification),
+                ),
+                PendingChange::new(
+                    actor,
+                    "verification_channel",
+                    audit::optional(&current.verification_channel_id),
+                    audit::optional(&settings.verification_channel_id),
+                ),
+                PendingChange::new(
+                    actor,
+                    "verification_timeout_seconds",
+                    audit::optional(&current.verification_timeout_seconds),
+                    audit::optional(&settings.verification_timeout_seconds),
+                ),
+            ]
+            .into_iter()
+            .filter(|c| c.old_value != c.new_value)
+            .collect::<Vec<_>>();
+            if !changes.is_empty() {
+                {
+                    let pgconn = crate::database::connection(sharemap)?;
+                    onboarding::save(&pgconn, &settings, &changes)?;
+                }
+                Onboarding::evict(guild.0, sharemap)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lists the differences between two values, going into objects so only what
+/// changed within them is listed.
+fn diff_value(path: &str, old: &JsonValue, new: &JsonValue, changes: &mut Vec<String>) {
+    match (old, new) {
+        (JsonValue::Object(old), JsonValue::Object(new)) => {
+            let added = new.keys().filter(|key| !old.contains_key(*key));
+            for key in old.keys().chain(added) {
+                diff_value(
+                    &format!("{}.{}", path, key),
+                    old.get(key).unwrap_or(&JsonValue::Null),
+                    new.get(key).unwrap_or(&JsonValue::Null),
+                    changes,
+                );
+            }
+        }
+        (old, new) if old != new => {
+            changes.push(format!("{}: {} -> {}", path, shorten(old), shorten(new)));
+        }
+        _ => {}
+    }
+}
+
+/// Shows a value for a diff, cut off if it's too long to be read at a glance.
+fn shorten(value: &JsonValue) -> String {
+    let shown = value.to_string();
+    if shown.chars().count() <= DIFF_VALUE_LENGTH {
+        return shown;
+    }
+    let mut shown = shown
+        .chars()
+        .take(DIFF_VALUE_LENGTH - 1)
+        .collect::<String>();
+    shown.push('…');
+    shown
+}
+
+/// A reference to a channel of the guild, named after it if it still exists.
+fn channel_reference(guild: &Guild, id: ChannelId) -> Reference {
+    Reference {
+        id: id.0,
+        name: guild
+            .channels
+            .get(&id)
+            .map(|c| c.read().name.clone())
+            .unwrap_or_default(),
+    }
+}
+
+/// A reference to a role of the guild, named after it if it still exists.
+fn role_reference(guild: &Guild, id: RoleId) -> Reference {
+    Reference {
+        id: id.0,
+        name: guild
+            .roles
+            .get(&id)
+            .map(|r| r.name.clone())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn reference(id: u64, name: &str) -> Reference {
+        Reference {
+            id,
+            name: name.to_owned(),
+        }
+    }
+
+    fn remapper() -> Remapper {
+        Remapper::with_names(
+            vec![(1, String::from("general")), (2, String::from("modlog"))]
+                .into_iter()
+                .collect(),
+            vec![(3, String::from("Muted"))].into_iter().collect(),
+        )
+    }
+
+    fn diff(old: JsonValue, new: JsonValue) -> Vec<String> {
+        let mut changes = Vec::new();
+        diff_value("settings", &old, &new, &mut changes);
+        changes
+    }
+
+    #[test]
+    fn picks_the_format_by_extension() {
+        assert_eq!(
+            DocumentFormat::from_file_name("a.TOML"),
+            DocumentFormat::Toml
+        );
+        assert_eq!(
+            DocumentFormat::from_file_name("a.json"),
+            DocumentFormat::Json
+        );
+        assert_eq!(DocumentFormat::from_file_name("a"), DocumentFormat::Json);
+    }
+
+    #[test]
+    fn rejects_newer_documents() {
+        let newer = format!("{{\"version\": {}}}", GUILD_CONFIG_VERSION + 1);
+        assert!(GuildConfig::deserialize(&newer, DocumentFormat::Json).is_err());
+        let current = format!("version = {}", GUILD_CONFIG_VERSION);
+        assert!(GuildConfig::deserialize(&current, DocumentFormat::Toml).is_ok());
+    }
+
+    #[test]
+    fn lists_only_what_changed_within_objects() {
+        let old = json!({"a": 1, "b": {"c": true, "d": "x"}});
+        let new = json!({"a": 1, "b": {"c": false, "d": "x"}, "e": [1]});
+        assert_eq!(
+            diff(old, new),
+            ["settings.b.c: true -> false", "settings.e: null -> [1]"]
+        );
+        assert!(diff(json!({"a": 1}), json!({"a": 1})).is_empty());
+        // Objects replacing plain values are listed whole
+        assert_eq!(
+            diff(json!(1), json!({"a": 1})),
+            ["settings: 1 -> {\"a\":1}"]
+        );
+    }
+
+    #[test]
+    fn shortens_long_values() {
+        assert_eq!(shorten(&json!("short")), "\"short\"");
+        let long = shorten(&json!("é".repeat(100)));
+        assert_eq!(long.chars().count(), DIFF_VALUE_LENGTH);
+        assert!(long.ends_with('…'));
+    }
+
+    #[test]
+    fn remaps_references_by_id_then_name() {
+        let mut remapper = remapper();
+        // The ID wins over a stale name
+        assert_eq!(
+            remapper.channel(&reference(1, "old-name")),
+            Some(reference(1, "old-name"))
+        );
+        assert_eq!(
+            remapper.channel(&reference(9, "modlog")),
+            Some(reference(2, "modlog"))
+        );
+        assert_eq!(
+            remapper.role(&reference(9, "Muted")),
+            Some(reference(3, "Muted"))
+        );
+        assert!(remapper.unresolved.is_empty());
+    }
+
+    #[test]
+    fn records_unresolved_references() {
+        let mut remapper = remapper();
+        assert_eq!(remapper.channel(&reference(9, "gone")), None);
+        // Channels and roles are looked for separately
+        assert_eq!(remapper.role(&reference(1, "general")), None);
+        assert_eq!(remapper.unresolved, ["channel #gone", "role @general"]);
+    }
+}
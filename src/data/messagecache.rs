@@ -0,0 +1,69 @@
+use crate::config::MessageCacheConfiguration;
+use chrono::{DateTime, Utc};
+use lru_time_cache::LruCache;
+use parking_lot::Mutex;
+use serenity::model::{channel::Message, id::GuildId};
+use std::time::Duration;
+use typemap::Key as TypeMapKey;
+
+/// The recent messages of servers which log edited and deleted messages.
+///
+/// It has a lock of its own, so remembering every message only needs the share
+/// map locked for reading.
+pub struct MessageCacheContainer;
+
+impl TypeMapKey for MessageCacheContainer {
+    type Value = Mutex<MessageCache>;
+}
+
+/// What is remembered about a message to log it once it is edited or deleted.
+#[derive(Clone, Debug)]
+pub struct CachedMessage {
+    pub guild_id: u64,
+    pub channel_id: u64,
+    pub author_id: u64,
+    pub author_tag: String,
+    pub content: String,
+    /// The file names of the attachments of the message.
+    pub attachments: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl CachedMessage {
+    pub fn new(guild: GuildId, msg: &Message) -> Self {
+        CachedMessage {
+            guild_id: guild.0,
+            channel_id: msg.channel_id.0,
+            author_id: msg.author.id.0,
+            author_tag: msg.author.tag(),
+            content: msg.content.clone(),
+            attachments: msg.attachments.iter().map(|a| a.filename.clone()).collect(),
+            created_at: msg.timestamp.with_timezone(&Utc),
+        }
+    }
+}
+
+/// An LRU cache of recent messages by ID.
+pub struct MessageCache {
+    cache: LruCache<u64, CachedMessage>,
+}
+
+impl MessageCache {
+    pub fn new(config: &MessageCacheConfiguration) -> Self {
+        let ttl = Duration::from_secs(*config.ttl_seconds());
+        let cache = match *config.capacity() {
+            0 => LruCache::with_expiry_duration(ttl),
+            capacity => LruCache::with_expiry_duration_and_capacity(ttl, capacity),
+        };
+        MessageCache { cache }
+    }
+
+    pub fn insert(&mut self, id: u64, message: CachedMessage) {
+        self.cache.insert(id, message);
+    }
+
+    /// Takes a message out of the cache, e.g. because it was deleted.
+    pub fn remove(&mut self, id: &u64) -> Option<CachedMessage> {
+        self.cache.remove(id)
+    }
+}
@@ -1,11 +1,77 @@
+/// Defines the functions the tables of server data share: `delete_all` for
+/// every table keyed by `guild_id`, and `authored_by` and `forget_author` for
+/// those which record the user who last changed a row.
+macro_rules! guild_rows {
+    ($($table:ident),+) => {
+        /// Removes every row of a server without recording it.
+        ///
+        /// This is meant for purging the data of a server, which is recorded
+        /// itself.
+        pub fn delete_all(conn: &PgConnection, guild: u64) -> QueryResult<usize> {
+            let mut deleted = 0;
+            $(
+                deleted += {
+                    use crate::scheme::$table::dsl::*;
+
+                    diesel::delete($table.filter(guild_id.eq(guild as i64))).execute(conn)?
+                };
+            )+
+            Ok(deleted)
+        }
+    };
+    ($table:ident => $row:ty, $author:ident, order by $order:ident) => {
+        guild_rows!($table);
+
+        /// Every row the user last changed, in any server.
+        pub fn authored_by(conn: &PgConnection, user: u64) -> QueryResult<Vec<$row>> {
+            use crate::scheme::$table::dsl::*;
+
+            $table
+                .filter($author.eq(user as i64))
+                .order((guild_id.asc(), $order.asc()))
+                .load(conn)
+        }
+
+        /// Keeps the rows the user changed, as they belong to their servers, but
+        /// no longer attributes them to the user, who asked to be forgotten.
+        pub fn forget_author(conn: &PgConnection, user: u64) -> QueryResult<usize> {
+            use crate::scheme::$table::dsl::*;
+
+            diesel::update($table.filter($author.eq(user as i64)))
+                .set($author.eq(None::<i64>))
+                .execute(conn)
+        }
+    };
+}
+
+pub mod antiraid;
+pub mod audit;
+pub mod automod;
+mod circuitbreaker;
+mod configurationcontainer;
+pub mod customcommands;
+pub mod eventhooks;
+pub mod greetings;
+pub mod guildconfig;
+pub mod messagecache;
+pub mod moderation;
+pub mod onboarding;
 mod ownercontainer;
+mod pendingdeletions;
 mod postgresqlcontainer;
+pub mod scheduledjobs;
 mod serenityshardmanagercontainer;
 mod serversettings;
+mod settingscache;
+pub mod userdata;
 mod usersettings;
 
+pub use self::circuitbreaker::{CircuitBreaker, CircuitBreakerContainer};
+pub use self::configurationcontainer::ConfigurationContainer;
 pub use self::ownercontainer::OwnerContainer;
+pub use self::pendingdeletions::PendingDeletionsContainer;
 pub use self::postgresqlcontainer::PostgreSqlContainer;
 pub use self::serenityshardmanagercontainer::ShardManagerContainer;
 pub use self::serversettings::{ServerSettings, ServerSettingsContainer};
+pub use self::settingscache::{get_or_load, SettingsCache};
 pub use self::usersettings::{UserSettings, UserSettingsContainer};
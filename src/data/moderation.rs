@@ -0,0 +1,386 @@
+use super::scheduledjobs;
+use crate::{prelude::*, scheme::moderation_cases};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, time::Duration};
+
+/// The moderation actions cases are opened for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaseAction {
+    Kick,
+    Ban,
+    Softban,
+    Unban,
+    Mute,
+    Unmute,
+    Warn,
+}
+
+impl CaseAction {
+    pub const ALL: [CaseAction; 7] = [
+        CaseAction::Kick,
+        CaseAction::Ban,
+        CaseAction::Softban,
+        CaseAction::Unban,
+        CaseAction::Mute,
+        CaseAction::Unmute,
+        CaseAction::Warn,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CaseAction::Kick => "kick",
+            CaseAction::Ban => "ban",
+            CaseAction::Softban => "softban",
+            CaseAction::Unban => "unban",
+            CaseAction::Mute => "mute",
+            CaseAction::Unmute => "unmute",
+            CaseAction::Warn => "warn",
+        }
+    }
+}
+
+impl FromStr for CaseAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        CaseAction::ALL
+            .iter()
+            .cloned()
+            .find(|a| a.as_str() == s)
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for CaseAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A moderation action taken in a server.
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Insertable)]
+#[table_name = "moderation_cases"]
+pub struct Case {
+    pub guild_id: i64,
+    pub case_number: i32,
+    pub action: String,
+    pub target_id: i64,
+    pub moderator_id: Option<i64>,
+    pub reason: Option<String>,
+    pub duration_seconds: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub modlog_message_id: Option<i64>,
+}
+
+impl Case {
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration_seconds.map(|s| Duration::from_secs(s as u64))
+    }
+}
+
+impl fmt::Display for Case {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{} {} | {} | {} against {}",
+            self.case_number,
+            self.created_at.format("%Y-%m-%d %H:%M:%S"),
+            self.action,
+            match self.moderator_id {
+                Some(id) => format!("by {}", id),
+                None => String::from("automatically"),
+            },
+            self.target_id,
+        )?;
+        if let Some(duration) = self.duration() {
+            write!(f, " for {}", format_duration(duration))?;
+        }
+        write!(
+            f,
+            ": {}",
+            self.reason
+                .as_ref()
+                .map(String::as_str)
+                .unwrap_or("no reason given")
+        )
+    }
+}
+
+/// How many times opening a case is tried while other cases of the server are
+/// opened at the same time.
+const OPEN_ATTEMPTS: usize = 5;
+
+/// Opens a case with the next number of the server.
+///
+/// Cases opened at the same time would get the same number, so the case is
+/// opened again with the next one if its number was taken in the meantime.
+pub fn open(
+    conn: &PgConnection,
+    guild: u64,
+    action: CaseAction,
+    target: u64,
+    moderator: Option<u64>,
+    reason: Option<&str>,
+    duration: Option<Duration>,
+) -> QueryResult<Case> {
+    use diesel::result::{DatabaseErrorKind as QueryErrorKind, Error as QueryError};
+
+    let mut attempt = 1;
+    loop {
+        match try_open(conn, guild, action, target, moderator, reason, duration) {
+            Err(QueryError::DatabaseError(QueryErrorKind::UniqueViolation, _))
+                if attempt < OPEN_ATTEMPTS =>
+            {
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn try_open(
+    conn: &PgConnection,
+    guild: u64,
+    action: CaseAction,
+    target: u64,
+    moderator: Option<u64>,
+    reason: Option<&str>,
+    duration: Option<Duration>,
+) -> QueryResult<Case> {
+    use crate::scheme::moderation_cases::dsl::*;
+    use diesel::dsl::max;
+
+    // The savepoint lets callers' transactions go on after a conflict
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let last = moderation_cases
+            .filter(guild_id.eq(guild as i64))
+            .select(max(case_number))
+            .first::<Option<i32>>(conn)?;
+        let now = Utc::now();
+        diesel::insert_into(moderation_cases)
+            .values(&Case {
+                guild_id: guild as i64,
+                case_number: last.unwrap_or(0) + 1,
+                action: action.as_str().to_owned(),
+                target_id: target as i64,
+                moderator_id: moderator.map(|m| m as i64),
+                reason: reason.map(String::from),
+                duration_seconds: duration.map(|d| d.as_secs() as i64),
+                created_at: now,
+                updated_at: now,
+                modlog_message_id: None,
+            })
+            .get_result(conn)
+    })
+}
+
+pub fn get(conn: &PgConnection, guild: u64, number: i32) -> QueryResult<Option<Case>> {
+    use crate::scheme::moderation_cases::dsl::*;
+
+    moderation_cases
+        .filter(guild_id.eq(guild as i64))
+        .filter(case_number.eq(number))
+        .first(conn)
+        .optional()
+}
+
+/// Replaces the reason of a case, returning the updated case if it exists.
+pub fn set_reason(
+    conn: &PgConnection,
+    guild: u64,
+    number: i32,
+    new: &str,
+) -> QueryResult<Option<Case>> {
+    use crate::scheme::moderation_cases::dsl::*;
+
+    diesel::update(
+        moderation_cases
+            .filter(guild_id.eq(guild as i64))
+            .filter(case_number.eq(number)),
+    )
+    .set((reason.eq(new), updated_at.eq(Utc::now())))
+    .get_result(conn)
+    .optional()
+}
+
+/// Remembers the message a case was posted as in the modlog channel.
+pub fn set_modlog_message(
+    conn: &PgConnection,
+    guild: u64,
+    number: i32,
+    message: u64,
+) -> QueryResult<()> {
+    use crate::scheme::moderation_cases::dsl::*;
+
+    diesel::update(
+        moderation_cases
+            .filter(guild_id.eq(guild as i64))
+            .filter(case_number.eq(number)),
+    )
+    .set(modlog_message_id.eq(message as i64))
+    .execute(conn)
+    .map(|_| ())
+}
+
+/// Deletes a case together with the job which would undo its action, because
+/// the action couldn't be taken after all.
+pub fn withdraw(conn: &PgConnection, case: &Case) -> QueryResult<()> {
+    use crate::scheme::moderation_cases::dsl::*;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        scheduledjobs::cancel_case(conn, case.guild_id as u64, case.case_number)?;
+        diesel::delete(
+            moderation_cases
+                .filter(guild_id.eq(case.guild_id))
+                .filter(case_number.eq(case.case_number)),
+        )
+        .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// The latest cases against a user in a server, newest first.
+pub fn against(conn: &PgConnection, guild: u64, user: u64, limit: i64) -> QueryResult<Vec<Case>> {
+    use crate::scheme::moderation_cases::dsl::*;
+
+    moderation_cases
+        .filter(guild_id.eq(guild as i64))
+        .filter(target_id.eq(user as i64))
+        .order(case_number.desc())
+        .limit(limit)
+        .load(conn)
+}
+
+/// Every case against the user, in any server.
+pub fn all_against(conn: &PgConnection, user: u64) -> QueryResult<Vec<Case>> {
+    use crate::scheme::moderation_cases::dsl::*;
+
+    moderation_cases
+        .filter(target_id.eq(user as i64))
+        .order((guild_id.asc(), case_number.asc()))
+        .load(conn)
+}
+
+guild_rows!(moderation_cases => Case, moderator_id, order by case_number);
+
+/// Parses durations such as `30m`, `12h` or `1w2d`.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 60 * 60 * 24,
+            'w' => 60 * 60 * 24 * 7,
+            _ => return None,
+        };
+        let value = number.parse::<u64>().ok()?;
+        total = total.checked_add(value.checked_mul(unit)?)?;
+        number.clear();
+    }
+    if !number.is_empty() || total == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(total))
+}
+
+/// Formats a duration in the largest units which fit, e.g. `1d 2h`.
+pub fn format_duration(duration: Duration) -> String {
+    const UNITS: &[(u64, &str)] = &[
+        (60 * 60 * 24 * 7, "w"),
+        (60 * 60 * 24, "d"),
+        (60 * 60, "h"),
+        (60, "m"),
+        (1, "s"),
+    ];
+
+    let mut left = duration.as_secs();
+    let mut parts = Vec::new();
+    for &(size, unit) in UNITS {
+        if left >= size {
+            parts.push(format!("{}{}", left / size, unit));
+            left %= size;
+        }
+    }
+    if parts.is_empty() {
+        String::from("0s")
+    } else {
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_units() {
+        assert_eq!(parse_duration("45s"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(
+            parse_duration("12H"),
+            Some(Duration::from_secs(12 * 60 * 60))
+        );
+        assert_eq!(
+            parse_duration("2w"),
+            Some(Duration::from_secs(2 * 7 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn parses_mixed_units() {
+        assert_eq!(
+            parse_duration("1w2d"),
+            Some(Duration::from_secs(9 * 24 * 60 * 60))
+        );
+        assert_eq!(
+            parse_duration("1h30m15s"),
+            Some(Duration::from_secs(60 * 60 + 30 * 60 + 15))
+        );
+        // Units may repeat and come in any order
+        assert_eq!(
+            parse_duration("30m1h30m"),
+            Some(Duration::from_secs(2 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_durations() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("30"), None);
+        assert_eq!(parse_duration("1h30"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("1y"), None);
+        assert_eq!(parse_duration("1h 30m"), None);
+        assert_eq!(parse_duration("0s"), None);
+        assert_eq!(parse_duration("99999999999999999999w"), None);
+    }
+
+    #[test]
+    fn formats_in_largest_units() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(format_duration(Duration::from_secs(59)), "59s");
+        assert_eq!(format_duration(Duration::from_secs(90 * 60)), "1h 30m");
+        assert_eq!(
+            format_duration(Duration::from_secs(8 * 24 * 60 * 60 + 2 * 60 * 60 + 5)),
+            "1w 1d 2h 5s"
+        );
+    }
+
+    #[test]
+    fn formatted_durations_parse_back() {
+        for &secs in &[1, 61, 3600, 86_399, 694_861] {
+            let formatted = format_duration(Duration::from_secs(secs)).replace(' ', "");
+            assert_eq!(parse_duration(&formatted), Some(Duration::from_secs(secs)));
+        }
+    }
+}
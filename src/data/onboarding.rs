@@ -0,0 +1,263 @@
+use super::{
+    audit::{self, AuditTarget, PendingChange},
+    get_or_load, SettingsCache,
+};
+use crate::{
+    prelude::*,
+    scheme::{onboarding, pending_verifications},
+};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+use typemap::Key as TypeMapKey;
+
+/// The most roles a server may give joining members.
+pub const MAX_AUTO_ROLES: usize = 10;
+
+/// The onboarding settings of every server which were looked up recently, by
+/// server ID.
+pub struct OnboardingContainer;
+
+impl TypeMapKey for OnboardingContainer {
+    type Value = SettingsCache<Onboarding>;
+}
+
+/// How members verify themselves before getting the roles of a server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerificationMethod {
+    /// Reacting to the message they are asked with.
+    Reaction,
+    /// Running the `verify` command.
+    Command,
+    /// Running the `verify` command with the code they are shown.
+    Captcha,
+}
+
+impl VerificationMethod {
+    pub const ALL: [VerificationMethod; 3] = [
+        VerificationMethod::Reaction,
+        VerificationMethod::Command,
+        VerificationMethod::Captcha,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VerificationMethod::Reaction => "reaction",
+            VerificationMethod::Command => "command",
+            VerificationMethod::Captcha => "captcha",
+        }
+    }
+}
+
+impl FromStr for VerificationMethod {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        VerificationMethod::ALL
+            .iter()
+            .cloned()
+            .find(|m| m.as_str().eq_ignore_ascii_case(s))
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for VerificationMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The roles a server gives joining members, and how they verify themselves.
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Insertable)]
+#[table_name = "onboarding"]
+pub struct Onboarding {
+    pub guild_id: i64,
+    pub auto_roles: Vec<i64>,
+    pub role_delay_seconds: Option<i64>,
+    pub verification: Option<String>,
+    pub verification_channel_id: Option<i64>,
+    pub verification_timeout_seconds: Option<i64>,
+}
+
+impl Onboarding {
+    /// The settings of a server which hasn't set any up, which gives no roles.
+    pub fn default_for(guild: u64) -> Self {
+        Onboarding {
+            guild_id: guild as i64,
+            auto_roles: Vec::new(),
+            role_delay_seconds: None,
+            verification: None,
+            verification_channel_id: None,
+            verification_timeout_seconds: None,
+        }
+    }
+
+    /// Gets the settings of a server, looking them up if they aren't cached.
+    pub fn new(guild: u64, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Result<Arc<RwLock<Self>>> {
+        get_or_load::<OnboardingContainer, _>(sharemap, guild, || {
+            let pgconn = crate::database::connection(sharemap)?;
+            let onboarding = find(&pgconn, guild)?;
+            Ok(onboarding.unwrap_or_else(|| Onboarding::default_for(guild)))
+        })
+    }
+
+    /// Drops the cached settings of a server, so changes to them are picked up.
+    pub fn evict(guild: u64, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Result<()> {
+        sharemap
+            .write()
+            .get_mut::<OnboardingContainer>()
+            .failure()?
+            .remove(&guild);
+        Ok(())
+    }
+
+    /// How members verify themselves, if they have to. Settings naming a method
+    /// which doesn't exist don't gate members.
+    pub fn verification(&self) -> Option<VerificationMethod> {
+        self.verification.as_ref()?.parse().ok()
+    }
+
+    pub fn role_delay(&self) -> Option<Duration> {
+        self.role_delay_seconds
+            .map(|s| Duration::from_secs(s.max(0) as u64))
+    }
+
+    pub fn verification_timeout(&self) -> Option<Duration> {
+        self.verification_timeout_seconds
+            .map(|s| Duration::from_secs(s.max(0) as u64))
+    }
+}
+
+/// A member who was asked to verify themselves and hasn't done so yet.
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Insertable)]
+#[table_name = "pending_verifications"]
+pub struct PendingVerification {
+    pub guild_id: i64,
+    pub user_id: i64,
+    pub method: String,
+    pub channel_id: i64,
+    pub message_id: Option<i64>,
+    pub answer: Option<String>,
+    pub joined_at: DateTime<Utc>,
+}
+
+impl PendingVerification {
+    /// How the member has to verify themselves, falling back to the command.
+    pub fn method(&self) -> VerificationMethod {
+        self.method.parse().unwrap_or(VerificationMethod::Command)
+    }
+}
+
+pub fn find(conn: &PgConnection, guild: u64) -> QueryResult<Option<Onboarding>> {
+    use crate::scheme::onboarding::dsl::*;
+
+    onboarding
+        .filter(guild_id.eq(guild as i64))
+        .first(conn)
+        .optional()
+}
+
+/// Saves the settings of a server, recording the changes made to them.
+pub fn save(
+    conn: &PgConnection,
+    settings: &Onboarding,
+    changes: &[PendingChange],
+) -> QueryResult<()> {
+    use crate::scheme::onboarding::dsl::*;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::insert_into(onboarding)
+            .values(settings)
+            .on_conflict(guild_id)
+            .do_update()
+            .set((
+                auto_roles.eq(&settings.auto_roles),
+                role_delay_seconds.eq(settings.role_delay_seconds),
+                verification.eq(&settings.verification),
+                verification_channel_id.eq(settings.verification_channel_id),
+                verification_timeout_seconds.eq(settings.verification_timeout_seconds),
+            ))
+            .execute(conn)?;
+        audit::record(conn, AuditTarget::Server, settings.guild_id as u64, changes)
+    })
+}
+
+guild_rows!(pending_verifications, onboarding);
+
+/// The verification the member still has to pass in the server, if any.
+pub fn pending(
+    conn: &PgConnection,
+    guild: u64,
+    user: u64,
+) -> QueryResult<Option<PendingVerification>> {
+    use crate::scheme::pending_verifications::dsl::*;
+
+    pending_verifications
+        .filter(guild_id.eq(guild as i64))
+        .filter(user_id.eq(user as i64))
+        .first(conn)
+        .optional()
+}
+
+/// The verification a member was asked for with the message, if any.
+pub fn pending_by_message(
+    conn: &PgConnection,
+    message: u64,
+) -> QueryResult<Option<PendingVerification>> {
+    use crate::scheme::pending_verifications::dsl::*;
+
+    pending_verifications
+        .filter(message_id.eq(message as i64))
+        .first(conn)
+        .optional()
+}
+
+/// Adds a verification a member has to pass, replacing the one they had to pass
+/// if they joined before.
+pub fn add_pending(conn: &PgConnection, verification: &PendingVerification) -> QueryResult<()> {
+    use crate::scheme::pending_verifications::dsl::*;
+
+    diesel::insert_into(pending_verifications)
+        .values(verification)
+        .on_conflict((guild_id, user_id))
+        .do_update()
+        .set((
+            method.eq(&verification.method),
+            channel_id.eq(verification.channel_id),
+            message_id.eq(verification.message_id),
+            answer.eq(&verification.answer),
+            joined_at.eq(verification.joined_at),
+        ))
+        .execute(conn)
+        .map(|_| ())
+}
+
+/// Removes the verification the member had to pass, returning it if there was
+/// one.
+pub fn remove_pending(
+    conn: &PgConnection,
+    guild: u64,
+    user: u64,
+) -> QueryResult<Option<PendingVerification>> {
+    use crate::scheme::pending_verifications::dsl::*;
+
+    diesel::delete(
+        pending_verifications
+            .filter(guild_id.eq(guild as i64))
+            .filter(user_id.eq(user as i64)),
+    )
+    .get_result(conn)
+    .optional()
+}
+
+/// Every verification the user still has to pass, in any server.
+pub fn all_pending_for(conn: &PgConnection, user: u64) -> QueryResult<Vec<PendingVerification>> {
+    use crate::scheme::pending_verifications::dsl::*;
+
+    pending_verifications
+        .filter(user_id.eq(user as i64))
+        .order(guild_id.asc())
+        .load(conn)
+}
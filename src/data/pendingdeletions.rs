@@ -0,0 +1,10 @@
+use serenity::model::id::UserId;
+use std::{collections::HashMap, time::Instant};
+use typemap::Key as TypeMapKey;
+
+/// Users who asked for their data to be deleted, and when they asked.
+pub struct PendingDeletionsContainer;
+
+impl TypeMapKey for PendingDeletionsContainer {
+    type Value = HashMap<UserId, Instant>;
+}
@@ -0,0 +1,256 @@
+use crate::{prelude::*, scheme::scheduled_jobs};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::{dsl::sql, prelude::*, sql_types::Bool};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, time::Duration};
+
+/// The server ID jobs which don't belong to a server are stored under. They are
+/// run by whichever process runs the first shard.
+pub const NO_GUILD: u64 = 0;
+
+/// The kinds of work which may be scheduled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobKind {
+    Unban,
+    Unmute,
+    UnblacklistUser,
+    UnblacklistServer,
+    AddRoles,
+    KickUnverified,
+}
+
+impl JobKind {
+    pub const ALL: [JobKind; 6] = [
+        JobKind::Unban,
+        JobKind::Unmute,
+        JobKind::UnblacklistUser,
+        JobKind::UnblacklistServer,
+        JobKind::AddRoles,
+        JobKind::KickUnverified,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobKind::Unban => "unban",
+            JobKind::Unmute => "unmute",
+            JobKind::UnblacklistUser => "unblacklist-user",
+            JobKind::UnblacklistServer => "unblacklist-server",
+            JobKind::AddRoles => "add-roles",
+            JobKind::KickUnverified => "kick-unverified",
+        }
+    }
+}
+
+impl FromStr for JobKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        JobKind::ALL
+            .iter()
+            .cloned()
+            .find(|k| k.as_str() == s)
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for JobKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Work to be done once it is due.
+#[derive(Serialize, Deserialize, Debug, Clone, Queryable, Insertable)]
+#[table_name = "scheduled_jobs"]
+pub struct Job {
+    pub id: i64,
+    pub kind: String,
+    pub guild_id: i64,
+    pub target_id: i64,
+    pub case_number: Option<i32>,
+    pub run_at: DateTime<Utc>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The time a job due after the given duration runs at, if it isn't too far off
+/// to be represented.
+pub fn due_in(duration: Duration) -> Option<DateTime<Utc>> {
+    Utc::now().checked_add_signed(ChronoDuration::from_std(duration).ok()?)
+}
+
+/// Schedules a job, replacing any pending job of the same kind for the same
+/// target in the server.
+pub fn schedule(
+    conn: &PgConnection,
+    job: JobKind,
+    guild: u64,
+    target: u64,
+    case: Option<i32>,
+    at: DateTime<Utc>,
+) -> QueryResult<()> {
+    use crate::scheme::scheduled_jobs::dsl::*;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        cancel(conn, job, guild, target)?;
+        diesel::insert_into(scheduled_jobs)
+            .values((
+                kind.eq(job.as_str()),
+                guild_id.eq(guild as i64),
+                target_id.eq(target as i64),
+                case_number.eq(case),
+                run_at.eq(at),
+                attempts.eq(0),
+                created_at.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Drops the pending jobs of a kind for a target in the server, e.g. because a
+/// temporary ban was lifted by hand.
+pub fn cancel(conn: &PgConnection, job: JobKind, guild: u64, target: u64) -> QueryResult<usize> {
+    use crate::scheme::scheduled_jobs::dsl::*;
+
+    diesel::delete(
+        scheduled_jobs
+            .filter(kind.eq(job.as_str()))
+            .filter(guild_id.eq(guild as i64))
+            .filter(target_id.eq(target as i64)),
+    )
+    .execute(conn)
+}
+
+/// Drops the pending jobs which would undo the action of a case.
+pub fn cancel_case(conn: &PgConnection, guild: u64, case: i32) -> QueryResult<usize> {
+    use crate::scheme::scheduled_jobs::dsl::*;
+
+    diesel::delete(
+        scheduled_jobs
+            .filter(guild_id.eq(guild as i64))
+            .filter(case_number.eq(case)),
+    )
+    .execute(conn)
+}
+
+/// Claims and returns due jobs of servers on the given shards, oldest first, by
+/// moving them to the end of their lease.
+///
+/// Jobs being claimed by other processes are skipped. A claimed job which is
+/// neither removed nor rescheduled before its lease is over is due again.
+pub fn claim_due(
+    conn: &PgConnection,
+    shards: &[u64],
+    shard_count: u64,
+    limit: i64,
+    leased_until: DateTime<Utc>,
+) -> QueryResult<Vec<Job>> {
+    use crate::scheme::scheduled_jobs::dsl::*;
+
+    if shards.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let on_shards = on_shards(shards, shard_count);
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let jobs = scheduled_jobs
+            .filter(run_at.le(Utc::now()))
+            .filter(sql::<Bool>(&on_shards))
+            .order(run_at.asc())
+            .limit(limit)
+            .for_update()
+            .skip_locked()
+            .load::<Job>(conn)?;
+        let ids = jobs.iter().map(|j| j.id).collect::<Vec<_>>();
+        diesel::update(scheduled_jobs.filter(id.eq_any(ids)))
+            .set(run_at.eq(leased_until))
+            .execute(conn)?;
+        Ok(jobs)
+    })
+}
+
+/// The SQL condition matching the jobs of servers on the given shards.
+///
+/// The shard of a server is derived from the timestamp part of its ID.
+fn on_shards(shards: &[u64], shard_count: u64) -> String {
+    format!(
+        "((guild_id >> 22) % {}) IN ({})",
+        shard_count.max(1),
+        shards
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// Removes a job, once it was done or given up on.
+pub fn remove(conn: &PgConnection, job: i64) -> QueryResult<()> {
+    use crate::scheme::scheduled_jobs::dsl::*;
+
+    diesel::delete(scheduled_jobs.filter(id.eq(job)))
+        .execute(conn)
+        .map(|_| ())
+}
+
+/// Records a failed attempt at a job and moves it to the given time.
+pub fn retry_at(conn: &PgConnection, job: i64, at: DateTime<Utc>, error: &str) -> QueryResult<()> {
+    use crate::scheme::scheduled_jobs::dsl::*;
+
+    diesel::update(scheduled_jobs.filter(id.eq(job)))
+        .set((
+            run_at.eq(at),
+            attempts.eq(attempts + 1),
+            last_error.eq(error),
+        ))
+        .execute(conn)
+        .map(|_| ())
+}
+
+guild_rows!(scheduled_jobs);
+
+/// Every pending job done to the user, in any server.
+pub fn all_targeting(conn: &PgConnection, user: u64) -> QueryResult<Vec<Job>> {
+    use crate::scheme::scheduled_jobs::dsl::*;
+
+    scheduled_jobs
+        .filter(target_id.eq(user as i64))
+        .filter(kind.ne(JobKind::UnblacklistServer.as_str()))
+        .order(run_at.asc())
+        .load(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_kinds_round_trip() {
+        for kind in JobKind::ALL.iter() {
+            assert_eq!(kind.as_str().parse::<JobKind>(), Ok(*kind));
+        }
+        assert_eq!("unknown".parse::<JobKind>(), Err(()));
+    }
+
+    #[test]
+    fn matches_jobs_on_the_given_shards() {
+        assert_eq!(on_shards(&[0], 1), "((guild_id >> 22) % 1) IN (0)");
+        assert_eq!(on_shards(&[1, 3], 4), "((guild_id >> 22) % 4) IN (1, 3)");
+    }
+
+    #[test]
+    fn treats_no_shard_count_as_one() {
+        assert_eq!(on_shards(&[0], 0), "((guild_id >> 22) % 1) IN (0)");
+    }
+
+    #[test]
+    fn jobs_are_due_after_the_duration() {
+        let before = Utc::now();
+        let at = due_in(Duration::from_secs(60)).unwrap();
+        assert!(at >= before + ChronoDuration::seconds(60));
+        assert!(at <= Utc::now() + ChronoDuration::seconds(60));
+        assert_eq!(due_in(Duration::from_secs(u64::max_value())), None);
+    }
+}
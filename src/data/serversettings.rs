@@ -1,8 +1,12 @@
 #![allow(dead_code)]
 
+use super::{
+    audit::{self, Actor, AuditTarget, PendingChange},
+    get_or_load, SettingsCache,
+};
 use crate::prelude::*;
+use chrono::{DateTime, Utc};
 use getset::Getters;
-use lru_time_cache::LruCache;
 use parking_lot::RwLock;
 use serde::Serialize;
 use std::sync::Arc;
@@ -11,7 +15,7 @@ use typemap::Key as TypeMapKey;
 pub struct ServerSettingsContainer;
 
 impl TypeMapKey for ServerSettingsContainer {
-    type Value = LruCache<u64, Arc<RwLock<ServerSettings>>>;
+    type Value = SettingsCache<ServerSettings>;
 }
 
 #[derive(Getters, Serialize)]
@@ -19,77 +23,175 @@ impl TypeMapKey for ServerSettingsContainer {
 pub struct ServerSettings {
     server_id: u64,
     blacklisted: bool,
+    departed_at: Option<DateTime<Utc>>,
+    hooks_enabled: bool,
+    hook_error_channel: Option<u64>,
+    mute_role: Option<u64>,
+    modlog_channel: Option<u64>,
+    message_log_channel: Option<u64>,
+    message_log_ignored: Vec<u64>,
 
     #[serde(skip)]
     modified: bool,
     #[serde(skip)]
+    pending_changes: Vec<PendingChange>,
+    #[serde(skip)]
     serenity_data: Arc<RwLock<typemap::ShareMap>>,
 }
 
+/// A row of the `server_settings` table, in the order of its columns.
+type ServerSettingsRow = (
+    i64,
+    bool,
+    Option<DateTime<Utc>>,
+    bool,
+    Option<i64>,
+    Option<i64>,
+    Option<i64>,
+    Option<i64>,
+    Vec<i64>,
+);
+
 impl ServerSettings {
+    fn from_row(row: ServerSettingsRow, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Self {
+        let (
+            server_id,
+            blacklisted,
+            departed_at,
+            hooks_enabled,
+            hook_error_channel,
+            mute_role,
+            modlog_channel,
+            message_log_channel,
+            message_log_ignored,
+        ) = row;
+        ServerSettings {
+            server_id: server_id as u64,
+            blacklisted,
+            departed_at,
+            hooks_enabled,
+            hook_error_channel: hook_error_channel.map(|c| c as u64),
+            mute_role: mute_role.map(|r| r as u64),
+            modlog_channel: modlog_channel.map(|c| c as u64),
+            message_log_channel: message_log_channel.map(|c| c as u64),
+            message_log_ignored: message_log_ignored.into_iter().map(|c| c as u64).collect(),
+
+            modified: false,
+            pending_changes: Vec::new(),
+            serenity_data: Arc::clone(sharemap),
+        }
+    }
+
+    fn default_for(server_id: u64, sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Self {
+        ServerSettings {
+            server_id,
+            blacklisted: false,
+            departed_at: None,
+            hooks_enabled: true,
+            hook_error_channel: None,
+            mute_role: None,
+            modlog_channel: None,
+            message_log_channel: None,
+            message_log_ignored: Vec::new(),
+
+            modified: false,
+            pending_changes: Vec::new(),
+            serenity_data: Arc::clone(sharemap),
+        }
+    }
+
     pub fn new(
         server_id: u64,
         sharemap: &Arc<RwLock<typemap::ShareMap>>,
     ) -> Result<Arc<RwLock<Self>>> {
-        let sharemap = Arc::clone(sharemap);
-        let mut read = sharemap.write();
-        {
-            let cache = read.get_mut::<ServerSettingsContainer>().failure()?;
-            if let Some(s) = cache.get(&server_id) {
-                return Ok(Arc::clone(s));
-            }
-        }
-
-        {
+        get_or_load::<ServerSettingsContainer, _>(sharemap, server_id, || {
             use crate::scheme::server_settings::dsl::*;
             use diesel::prelude::*;
 
-            let pgpool = read.get::<PostgreSqlContainer>().failure()?;
-            let pgconn = pgpool.get()?;
+            let pgconn = crate::database::connection(sharemap)?;
 
             let mut setting = server_settings
                 .filter(id.eq(server_id as i64))
                 .limit(1)
-                .load::<(i64, bool)>(&pgconn)?;
-            let setting = setting.pop();
-
-            if let Some(s) = setting {
-                let (server_id, blacklist) = s;
-                let server_id = server_id as u64;
-
-                let settings = Arc::new(RwLock::new(ServerSettings {
-                    server_id,
-                    blacklisted: blacklist,
+                .load::<ServerSettingsRow>(&pgconn)?;
+            if let Some(s) = setting.pop() {
+                return Ok(ServerSettings::from_row(s, sharemap));
+            }
 
-                    modified: false,
-                    serenity_data: Arc::clone(&sharemap),
-                }));
+            let negative_caching = sharemap
+                .read()
+                .get::<ServerSettingsContainer>()
+                .failure()?
+                .negative_caching();
+            let mut settings = ServerSettings::default_for(server_id, sharemap);
+            if !negative_caching {
+                // Write the row right away rather than once something is changed.
+                settings.modified = true;
+                settings.save()?;
+            }
+            Ok(settings)
+        })
+    }
 
-                let cache = read.get_mut::<ServerSettingsContainer>().failure()?;
-                cache.insert(server_id, Arc::clone(&settings));
+    /// Loads the settings of every given server in a single query and puts them
+    /// into the cache, skipping those which are already cached.
+    ///
+    /// Returns the amount of servers which were cached.
+    pub fn preload(server_ids: &[u64], sharemap: &Arc<RwLock<typemap::ShareMap>>) -> Result<usize> {
+        use crate::scheme::server_settings::dsl::*;
+        use diesel::prelude::*;
+        use std::collections::HashSet;
 
-                return Ok(settings);
-            }
+        if server_ids.is_empty() {
+            return Ok(0);
         }
 
-        drop(read);
+        let rows = {
+            let pgconn = crate::database::connection(sharemap)?;
+            server_settings
+                .filter(id.eq_any(server_ids.iter().map(|&s| s as i64).collect::<Vec<_>>()))
+                .load::<ServerSettingsRow>(&pgconn)?
+        };
 
-        let mut settings = ServerSettings {
-            server_id,
-            blacklisted: false,
+        let mut write = sharemap.write();
+        let cache = write.get_mut::<ServerSettingsContainer>().failure()?;
+        let mut found = HashSet::with_capacity(rows.len());
+        let mut cached = 0;
+        let mut evicted = Vec::new();
+        for row in rows {
+            let server_id = row.0 as u64;
+            found.insert(server_id);
+            if cache.contains(&server_id) {
+                continue;
+            }
 
-            modified: true,
-            serenity_data: Arc::clone(&sharemap),
-        };
-        settings.save()?;
-        let arced = Arc::new(RwLock::new(settings));
+            evicted.extend(cache.insert(
+                server_id,
+                Arc::new(RwLock::new(ServerSettings::from_row(row, sharemap))),
+            ));
+            cached += 1;
+        }
 
-        let mut read = sharemap.write();
-        let cache = read.get_mut::<ServerSettingsContainer>().failure()?;
+        if cache.negative_caching() {
+            for &server_id in server_ids {
+                if found.contains(&server_id) || cache.contains(&server_id) {
+                    continue;
+                }
 
-        cache.insert(server_id, Arc::clone(&arced));
+                evicted.extend(cache.insert(
+                    server_id,
+                    Arc::new(RwLock::new(ServerSettings::default_for(
+                        server_id, sharemap,
+                    ))),
+                ));
+                cached += 1;
+            }
+        }
+        // Dropping settings may save them, which needs the share map
+        drop(write);
+        drop(evicted);
 
-        Ok(arced)
+        Ok(cached)
     }
 
     pub fn save(&mut self) -> Result<()> {
@@ -100,46 +202,200 @@ impl ServerSettings {
             return Ok(());
         }
 
-        let read = self.serenity_data.read();
-        let connpool = read.get::<PostgreSqlContainer>().failure()?;
-        let pgconn = connpool.get()?;
-        insert_into(server_settings)
-            .values((
-                id.eq(self.server_id as i64),
-                blacklisted.eq(self.blacklisted),
-            ))
-            .on_conflict(id)
-            .do_update()
-            .set(blacklisted.eq(self.blacklisted))
-            .execute(&pgconn)?;
-
+        let ignored = self
+            .message_log_ignored
+            .iter()
+            .map(|&c| c as i64)
+            .collect::<Vec<_>>();
+        let pgconn = crate::database::connection(&self.serenity_data)?;
+        pgconn.transaction::<_, diesel::result::Error, _>(|| {
+            insert_into(server_settings)
+                .values((
+                    id.eq(self.server_id as i64),
+                    blacklisted.eq(self.blacklisted),
+                    departed_at.eq(self.departed_at),
+                    hooks_enabled.eq(self.hooks_enabled),
+                    hook_error_channel.eq(self.hook_error_channel.map(|c| c as i64)),
+                    mute_role.eq(self.mute_role.map(|r| r as i64)),
+                    modlog_channel.eq(self.modlog_channel.map(|c| c as i64)),
+                    message_log_channel.eq(self.message_log_channel.map(|c| c as i64)),
+                    message_log_ignored.eq(&ignored),
+                ))
+                .on_conflict(id)
+                .do_update()
+                .set((
+                    blacklisted.eq(self.blacklisted),
+                    departed_at.eq(self.departed_at),
+                    hooks_enabled.eq(self.hooks_enabled),
+                    hook_error_channel.eq(self.hook_error_channel.map(|c| c as i64)),
+                    mute_role.eq(self.mute_role.map(|r| r as i64)),
+                    modlog_channel.eq(self.modlog_channel.map(|c| c as i64)),
+                    message_log_channel.eq(self.message_log_channel.map(|c| c as i64)),
+                    message_log_ignored.eq(&ignored),
+                ))
+                .execute(&pgconn)?;
+            audit::record(
+                &pgconn,
+                AuditTarget::Server,
+                self.server_id,
+                &self.pending_changes,
+            )
+        })?;
+
+        self.pending_changes.clear();
         self.modified = false;
         Ok(())
     }
 
-    pub fn set_blacklisted(&mut self, new: bool) {
+    pub fn set_blacklisted(&mut self, new: bool, actor: Actor) {
+        if self.blacklisted == new {
+            return;
+        }
+        self.pending_changes.push(PendingChange::new(
+            actor,
+            "blacklisted",
+            self.blacklisted,
+            new,
+        ));
         self.modified = true;
         self.blacklisted = new;
     }
 
-    pub fn delete(mut self) -> Result<()> {
+    /// Marks the server as departed at the given time, or as present again.
+    pub fn set_departed_at(&mut self, new: Option<DateTime<Utc>>, actor: Actor) {
+        if self.departed_at == new {
+            return;
+        }
+        self.pending_changes.push(PendingChange::new(
+            actor,
+            "departed_at",
+            audit::optional(&self.departed_at),
+            audit::optional(&new),
+        ));
+        self.modified = true;
+        self.departed_at = new;
+    }
+
+    pub fn set_hooks_enabled(&mut self, new: bool, actor: Actor) {
+        if self.hooks_enabled == new {
+            return;
+        }
+        self.pending_changes.push(PendingChange::new(
+            actor,
+            "hooks_enabled",
+            self.hooks_enabled,
+            new,
+        ));
+        self.modified = true;
+        self.hooks_enabled = new;
+    }
+
+    /// Sets the channel errors of event hooks are reported to, or stops
+    /// reporting them.
+    pub fn set_hook_error_channel(&mut self, new: Option<u64>, actor: Actor) {
+        if self.hook_error_channel == new {
+            return;
+        }
+        self.pending_changes.push(PendingChange::new(
+            actor,
+            "hook_error_channel",
+            audit::optional(&self.hook_error_channel),
+            audit::optional(&new),
+        ));
+        self.modified = true;
+        self.hook_error_channel = new;
+    }
+
+    /// Sets the role given to muted members, or unsets it.
+    pub fn set_mute_role(&mut self, new: Option<u64>, actor: Actor) {
+        if self.mute_role == new {
+            return;
+        }
+        self.pending_changes.push(PendingChange::new(
+            actor,
+            "mute_role",
+            audit::optional(&self.mute_role),
+            audit::optional(&new),
+        ));
+        self.modified = true;
+        self.mute_role = new;
+    }
+
+    /// Sets the channel moderation cases are posted to, or stops posting them.
+    pub fn set_modlog_channel(&mut self, new: Option<u64>, actor: Actor) {
+        if self.modlog_channel == new {
+            return;
+        }
+        self.pending_changes.push(PendingChange::new(
+            actor,
+            "modlog_channel",
+            audit::optional(&self.modlog_channel),
+            audit::optional(&new),
+        ));
+        self.modified = true;
+        self.modlog_channel = new;
+    }
+
+    /// Sets the channel edited and deleted messages are logged to, or stops
+    /// logging them.
+    pub fn set_message_log_channel(&mut self, new: Option<u64>, actor: Actor) {
+        if self.message_log_channel == new {
+            return;
+        }
+        self.pending_changes.push(PendingChange::new(
+            actor,
+            "message_log_channel",
+            audit::optional(&self.message_log_channel),
+            audit::optional(&new),
+        ));
+        self.modified = true;
+        self.message_log_channel = new;
+    }
+
+    /// Sets the channels whose messages aren't logged.
+    pub fn set_message_log_ignored(&mut self, mut new: Vec<u64>, actor: Actor) {
+        new.sort();
+        new.dedup();
+        if self.message_log_ignored == new {
+            return;
+        }
+        self.pending_changes.push(PendingChange::new(
+            actor,
+            "message_log_ignored",
+            audit::list(&self.message_log_ignored),
+            audit::list(&new),
+        ));
+        self.modified = true;
+        self.message_log_ignored = new;
+    }
+
+    pub fn delete(mut self, actor: Actor) -> Result<()> {
         use crate::scheme::server_settings::dsl::*;
         use diesel::{dsl::*, prelude::*};
 
         self.modified = false;
 
-        let mut read = self.serenity_data.write();
-
         {
-            let connpool = read.get::<PostgreSqlContainer>().failure()?;
-            let pgconn = connpool.get()?;
-            delete(server_settings.filter(id.eq(self.server_id as i64))).execute(&pgconn)?;
+            let pgconn = crate::database::connection(&self.serenity_data)?;
+            pgconn.transaction::<_, diesel::result::Error, _>(|| {
+                delete(server_settings.filter(id.eq(self.server_id as i64))).execute(&pgconn)?;
+                audit::record(
+                    &pgconn,
+                    AuditTarget::Server,
+                    self.server_id,
+                    &[PendingChange::new(actor, "deleted", false, true)],
+                )
+            })?;
         }
 
-        {
-            let cache = read.get_mut::<ServerSettingsContainer>().failure()?;
-            cache.remove(&self.server_id);
-        }
+        // Dropping settings may save them, which needs the share map
+        let evicted = self
+            .serenity_data
+            .write()
+            .get_mut::<ServerSettingsContainer>()
+            .failure()?
+            .remove(&self.server_id);
+        drop(evicted);
 
         Ok(())
     }
@@ -0,0 +1,238 @@
+use crate::{config::CacheConfiguration, prelude::*};
+use lru_time_cache::LruCache;
+use parking_lot::RwLock;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use typemap::{Key as TypeMapKey, ShareMap};
+
+/// An LRU cache of settings which keeps track of how well it performs.
+///
+/// Dropping settings may save them, which locks the share map the cache is in,
+/// so entries the cache lets go of are handed back to be dropped once the share
+/// map is unlocked again. Entries expire a TTL after they were inserted, and are
+/// only evicted by `insert`.
+pub struct SettingsCache<V> {
+    cache: LruCache<u64, Arc<RwLock<V>>>,
+    capacity: usize,
+    ttl: Duration,
+    negative_caching: bool,
+
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<V> SettingsCache<V> {
+    pub fn new(config: &CacheConfiguration) -> Self {
+        let capacity = *config.capacity();
+        let ttl = Duration::from_secs(*config.ttl_seconds());
+        SettingsCache {
+            cache: Self::make_cache(capacity, ttl),
+            capacity,
+            ttl,
+            negative_caching: *config.negative_caching(),
+
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn make_cache(capacity: usize, ttl: Duration) -> LruCache<u64, Arc<RwLock<V>>> {
+        if capacity == 0 {
+            LruCache::with_expiry_duration(ttl)
+        } else {
+            LruCache::with_expiry_duration_and_capacity(ttl, capacity)
+        }
+    }
+
+    /// Gets an entry, counting the lookup towards the hit ratio. This only needs
+    /// the share map locked for reading.
+    pub fn get(&self, key: &u64) -> Option<Arc<RwLock<V>>> {
+        // Peeking leaves expired entries for `insert` to hand back
+        let entry = self.cache.peek(key).map(Arc::clone);
+        let counter = if entry.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        entry
+    }
+
+    /// Checks whether an entry is cached without counting towards the hit ratio.
+    pub fn contains(&self, key: &u64) -> bool {
+        self.cache.contains_key(key)
+    }
+
+    /// Inserts an entry, returning the entries it replaced or evicted.
+    pub fn insert(&mut self, key: u64, value: Arc<RwLock<V>>) -> Vec<Arc<RwLock<V>>> {
+        let (replaced, evicted) = self.cache.notify_insert(key, value);
+        replaced
+            .into_iter()
+            .chain(evicted.into_iter().map(|(_, v)| v))
+            .collect()
+    }
+
+    pub fn remove(&mut self, key: &u64) -> Option<Arc<RwLock<V>>> {
+        self.cache.remove(key)
+    }
+
+    /// Removes every entry and resets the hit ratio, returning the entries.
+    pub fn clear(&mut self) -> Vec<Arc<RwLock<V>>> {
+        let cleared = self.cache.peek_iter().map(|(_, v)| Arc::clone(v)).collect();
+        self.cache = Self::make_cache(self.capacity, self.ttl);
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+        cleared
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn negative_caching(&self) -> bool {
+        self.negative_caching
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// The ratio of lookups which were found in the cache, between 0 and 1.
+    pub fn hit_ratio(&self) -> f64 {
+        let (hits, misses) = (self.hits(), self.misses());
+        if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        }
+    }
+}
+
+/// Gets an entry from the cache `K` in the share map, loading it with `load`
+/// and caching it if it isn't cached.
+///
+/// The share map isn't locked while the entry is loaded, so looking it up in the
+/// database doesn't hold up every other handler. If another handler cached the
+/// entry in the meantime, theirs is kept.
+pub fn get_or_load<K, V>(
+    sharemap: &Arc<RwLock<ShareMap>>,
+    key: u64,
+    load: impl FnOnce() -> Result<V>,
+) -> Result<Arc<RwLock<V>>>
+where
+    K: TypeMapKey<Value = SettingsCache<V>>,
+    V: Send + Sync + 'static,
+{
+    if let Some(s) = sharemap.read().get::<K>().failure()?.get(&key) {
+        return Ok(s);
+    }
+
+    let loaded = Arc::new(RwLock::new(load()?));
+    let mut write = sharemap.write();
+    let cache = write.get_mut::<K>().failure()?;
+    let (entry, unused) = match cache.cache.peek(&key) {
+        Some(s) => (Arc::clone(s), vec![loaded]),
+        None => (Arc::clone(&loaded), cache.insert(key, loaded)),
+    };
+    // Dropping settings may save them, which needs the share map
+    drop(write);
+    drop(unused);
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestCache;
+
+    impl TypeMapKey for TestCache {
+        type Value = SettingsCache<u32>;
+    }
+
+    fn cache(capacity: usize) -> SettingsCache<u32> {
+        let config = format!("capacity = {}\nttl_seconds = 60", capacity);
+        SettingsCache::new(&toml::from_str(&config).unwrap())
+    }
+
+    fn value(n: u32) -> Arc<RwLock<u32>> {
+        Arc::new(RwLock::new(n))
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let mut cache = cache(10);
+        assert!(cache.hit_ratio().abs() < std::f64::EPSILON);
+        assert!(cache.get(&1).is_none());
+        cache.insert(1, value(10));
+        assert_eq!(*cache.get(&1).unwrap().read(), 10);
+        assert_eq!(*cache.get(&1).unwrap().read(), 10);
+        assert_eq!((cache.hits(), cache.misses()), (2, 1));
+        assert!((cache.hit_ratio() - 2.0 / 3.0).abs() < std::f64::EPSILON);
+        // Checking for an entry isn't a lookup
+        assert!(cache.contains(&1));
+        assert!(!cache.contains(&2));
+        assert_eq!((cache.hits(), cache.misses()), (2, 1));
+    }
+
+    #[test]
+    fn hands_back_replaced_and_evicted_entries() {
+        let mut cache = cache(2);
+        assert!(cache.insert(1, value(10)).is_empty());
+        assert!(cache.insert(2, value(20)).is_empty());
+        let replaced = cache.insert(2, value(21));
+        assert_eq!(replaced.iter().map(|v| *v.read()).collect::<Vec<_>>(), [20]);
+        let evicted = cache.insert(3, value(30));
+        assert_eq!(evicted.iter().map(|v| *v.read()).collect::<Vec<_>>(), [10]);
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains(&1));
+    }
+
+    #[test]
+    fn clearing_hands_back_entries_and_resets_the_ratio() {
+        let mut cache = cache(0);
+        cache.insert(1, value(10));
+        cache.insert(2, value(20));
+        cache.get(&1);
+        let mut cleared = cache.clear().iter().map(|v| *v.read()).collect::<Vec<_>>();
+        cleared.sort();
+        assert_eq!(cleared, [10, 20]);
+        assert_eq!(cache.len(), 0);
+        assert_eq!((cache.hits(), cache.misses()), (0, 0));
+        assert_eq!(cache.capacity(), 0);
+    }
+
+    #[test]
+    fn loads_entries_once() {
+        let mut sharemap = ShareMap::custom();
+        sharemap.insert::<TestCache>(cache(10));
+        let sharemap = Arc::new(RwLock::new(sharemap));
+
+        let loaded = get_or_load::<TestCache, _>(&sharemap, 1, || Ok(10)).unwrap();
+        assert_eq!(*loaded.read(), 10);
+        let cached = get_or_load::<TestCache, _>(&sharemap, 1, || panic!("loaded twice")).unwrap();
+        assert!(Arc::ptr_eq(&loaded, &cached));
+        let failed = get_or_load::<TestCache, _>(&sharemap, 2, || {
+            Err(failure::err_msg("unreachable database"))
+        });
+        assert!(failed.is_err());
+        assert!(!sharemap.read().get::<TestCache>().unwrap().contains(&2));
+    }
+}
@@ -0,0 +1,216 @@
+use super::{
+    audit::{self, Actor, AuditEntry, AuditTarget, PendingChange},
+    automod, customcommands, eventhooks, greetings,
+    moderation::{self, Case},
+    onboarding::{self, PendingVerification},
+    scheduledjobs::{self, Job},
+};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+
+/// The version of the format of user data exports, bumped whenever a field is
+/// changed or removed.
+pub const EXPORT_FORMAT_VERSION: u32 = 1;
+
+/// Everything stored about a single user.
+#[derive(Debug, Serialize)]
+pub struct UserDataExport {
+    pub format_version: u32,
+    pub generated_at: DateTime<Utc>,
+    pub user_id: u64,
+    pub user_settings: Option<UserSettingsExport>,
+    pub settings_changes_about_user: Vec<AuditEntry>,
+    pub settings_changes_by_user: Vec<AuditEntry>,
+    pub custom_commands_defined: Vec<CustomCommandExport>,
+    pub event_hooks_registered: Vec<EventHookExport>,
+    pub automod_rules_changed: Vec<AutomodRuleExport>,
+    pub greetings_changed: Vec<GreetingExport>,
+    pub moderation_cases_against_user: Vec<Case>,
+    pub moderation_cases_by_user: Vec<Case>,
+    pub scheduled_jobs_targeting_user: Vec<Job>,
+    pub pending_verifications: Vec<PendingVerification>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserSettingsExport {
+    pub blacklisted: bool,
+}
+
+/// A custom command the user last defined. Its source belongs to the server, so
+/// only where it is and when it was defined are included.
+#[derive(Debug, Serialize)]
+pub struct CustomCommandExport {
+    pub guild_id: u64,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// An event hook the user last registered, with its source left out for the same
+/// reason.
+#[derive(Debug, Serialize)]
+pub struct EventHookExport {
+    pub guild_id: u64,
+    pub event: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// An automod rule the user last changed, with its banned words left out for the
+/// same reason.
+#[derive(Debug, Serialize)]
+pub struct AutomodRuleExport {
+    pub guild_id: u64,
+    pub kind: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A welcome or farewell message the user last changed, with its template left
+/// out for the same reason.
+#[derive(Debug, Serialize)]
+pub struct GreetingExport {
+    pub guild_id: u64,
+    pub kind: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Gathers everything stored about the user from every table.
+pub fn export(conn: &PgConnection, user: u64) -> QueryResult<UserDataExport> {
+    let user_settings = {
+        use crate::scheme::user_settings::dsl::*;
+
+        user_settings
+            .filter(id.eq(user as i64))
+            .select(blacklisted)
+            .first::<bool>(conn)
+            .optional()?
+            .map(|blacklisted| UserSettingsExport { blacklisted })
+    };
+
+    Ok(UserDataExport {
+        format_version: EXPORT_FORMAT_VERSION,
+        generated_at: Utc::now(),
+        user_id: user,
+        user_settings,
+        settings_changes_about_user: audit::all_about(conn, AuditTarget::User, user)?,
+        settings_changes_by_user: audit::all_by(conn, user)?,
+        custom_commands_defined: customcommands::authored_by(conn, user)?
+            .into_iter()
+            .map(|c| CustomCommandExport {
+                guild_id: c.guild_id as u64,
+                name: c.name,
+                created_at: c.created_at,
+                updated_at: c.updated_at,
+            })
+            .collect(),
+        event_hooks_registered: eventhooks::authored_by(conn, user)?
+            .into_iter()
+            .map(|h| EventHookExport {
+                guild_id: h.guild_id as u64,
+                event: h.event,
+                updated_at: h.updated_at,
+            })
+            .collect(),
+        automod_rules_changed: automod::authored_by(conn, user)?
+            .into_iter()
+            .map(|r| AutomodRuleExport {
+                guild_id: r.guild_id as u64,
+                kind: r.kind,
+                updated_at: r.updated_at,
+            })
+            .collect(),
+        greetings_changed: greetings::authored_by(conn, user)?
+            .into_iter()
+            .map(|g| GreetingExport {
+                guild_id: g.guild_id as u64,
+                kind: g.kind,
+                updated_at: g.updated_at,
+            })
+            .collect(),
+        moderation_cases_against_user: moderation::all_against(conn, user)?,
+        moderation_cases_by_user: moderation::authored_by(conn, user)?,
+        scheduled_jobs_targeting_user: scheduledjobs::all_targeting(conn, user)?,
+        pending_verifications: onboarding::all_pending_for(conn, user)?,
+    })
+}
+
+/// What was kept when deleting the data of a user.
+#[derive(Debug)]
+pub struct ForgetOutcome {
+    /// Whether the user is globally blacklisted, in which case the blacklist
+    /// and its history were kept.
+    pub kept_blacklist: bool,
+    /// How many moderation cases against the user were kept, as they are part
+    /// of the records of their servers.
+    pub kept_cases: usize,
+}
+
+/// Deletes everything stored about the user from every table, except for what
+/// is needed to keep an active global blacklist in place.
+///
+/// Changes the user made to the settings of others, the custom commands, event
+/// hooks, automod rules and greetings they defined and the moderation cases they
+/// opened are kept, but no longer attributed to them. Cases against the user are
+/// kept as they are, and so are the pending jobs which undo temporary actions
+/// against them and the verifications they still have to pass. The request
+/// itself is recorded in the audit trail.
+///
+/// This should be run in a transaction, and the cached settings of the user
+/// evicted afterwards.
+pub fn forget(conn: &PgConnection, user: u64) -> QueryResult<ForgetOutcome> {
+    let blacklist = {
+        use crate::scheme::user_settings::dsl::*;
+
+        let blacklist = user_settings
+            .filter(id.eq(user as i64))
+            .select(blacklisted)
+            .first::<bool>(conn)
+            .optional()?
+            .unwrap_or(false);
+        if !blacklist {
+            diesel::delete(user_settings.filter(id.eq(user as i64))).execute(conn)?;
+        }
+        blacklist
+    };
+
+    {
+        use crate::scheme::settings_audit::dsl::*;
+
+        let about_user = settings_audit
+            .filter(target_kind.eq(AuditTarget::User.as_str()))
+            .filter(target_id.eq(user as i64));
+        if blacklist {
+            diesel::delete(about_user.filter(field.ne("blacklisted"))).execute(conn)?;
+        } else {
+            diesel::delete(about_user).execute(conn)?;
+        }
+
+        diesel::update(settings_audit.filter(actor_id.eq(user as i64)))
+            .set(actor_id.eq(None::<i64>))
+            .execute(conn)?;
+    }
+
+    customcommands::forget_author(conn, user)?;
+    eventhooks::forget_author(conn, user)?;
+    automod::forget_author(conn, user)?;
+    greetings::forget_author(conn, user)?;
+    moderation::forget_author(conn, user)?;
+    let kept_cases = moderation::all_against(conn, user)?.len();
+
+    audit::record(
+        conn,
+        AuditTarget::User,
+        user,
+        &[PendingChange::new(
+            Actor::command(user),
+            "data",
+            "present",
+            "forgotten",
+        )],
+    )?;
+
+    Ok(ForgetOutcome {
+        kept_blacklist: blacklist,
+        kept_cases,
+    })
+}
@@ -1,8 +1,11 @@
 #![allow(dead_code)]
 
+use super::{
+    audit::{self, Actor, AuditTarget, PendingChange},
+    get_or_load, SettingsCache,
+};
 use crate::prelude::*;
 use getset::Getters;
-use lru_time_cache::LruCache;
 use parking_lot::RwLock;
 use serde::Serialize;
 use std::sync::Arc;
@@ -11,7 +14,7 @@ use typemap::Key as TypeMapKey;
 pub struct UserSettingsContainer;
 
 impl TypeMapKey for UserSettingsContainer {
-    type Value = LruCache<u64, Arc<RwLock<UserSettings>>>;
+    type Value = SettingsCache<UserSettings>;
 }
 
 #[derive(Getters, Serialize)]
@@ -23,76 +26,64 @@ pub struct UserSettings {
     #[serde(skip)]
     modified: bool,
     #[serde(skip)]
+    pending_changes: Vec<PendingChange>,
+    #[serde(skip)]
     serenity_data: Arc<RwLock<typemap::ShareMap>>,
 }
 
 impl UserSettings {
+    /// Gets the settings of a user, looking them up if they aren't cached.
+    ///
+    /// Users without a row get the defaults, which are only written once
+    /// something is changed. Every command looks up its author, so writing them
+    /// right away would store everyone who ever ran one, and bring back the
+    /// row of anyone who asked to be forgotten.
     pub fn new(
         user_id: u64,
         sharemap: &Arc<RwLock<typemap::ShareMap>>,
     ) -> Result<Arc<RwLock<Self>>> {
-        let sharemap = Arc::clone(sharemap);
-        let mut read = sharemap.write();
-        {
-            let cache = read.get_mut::<UserSettingsContainer>().failure()?;
-            if let Some(s) = cache.get(&user_id) {
-                return Ok(Arc::clone(s));
-            }
-        }
-
-        {
+        get_or_load::<UserSettingsContainer, _>(sharemap, user_id, || {
             use crate::scheme::user_settings::dsl::*;
             use diesel::prelude::*;
 
-            let pgpool = read.get::<PostgreSqlContainer>().failure()?;
-            let pgconn = pgpool.get()?;
+            let pgconn = crate::database::connection(sharemap)?;
 
             let mut setting = user_settings
                 .filter(id.eq(user_id as i64))
                 .limit(1)
                 .load::<(i64, bool)>(&pgconn)?;
-            let setting = setting.pop();
-
-            if let Some(s) = setting {
-                let (user_id, blacklist) = s;
-                let user_id = user_id as u64;
-
-                let settings = Arc::new(RwLock::new(UserSettings {
+            if let Some((_, blacklist)) = setting.pop() {
+                return Ok(UserSettings {
                     user_id,
                     blacklisted: blacklist,
 
                     modified: false,
-                    serenity_data: Arc::clone(&sharemap),
-                }));
-
-                let cache = read.get_mut::<UserSettingsContainer>().failure()?;
-                cache.insert(user_id, Arc::clone(&settings));
-
-                return Ok(settings);
+                    pending_changes: Vec::new(),
+                    serenity_data: Arc::clone(sharemap),
+                });
             }
-        }
-
-        drop(read);
-
-        let mut settings = UserSettings {
-            user_id,
-            blacklisted: false,
-
-            modified: true,
-            serenity_data: Arc::clone(&sharemap),
-        };
-        settings.save()?;
-        let arced = Arc::new(RwLock::new(settings));
-
-        let mut read = sharemap.write();
-        let cache = read.get_mut::<UserSettingsContainer>().failure()?;
 
-        cache.insert(user_id, Arc::clone(&arced));
+            Ok(UserSettings {
+                user_id,
+                blacklisted: false,
 
-        Ok(arced)
+                modified: false,
+                pending_changes: Vec::new(),
+                serenity_data: Arc::clone(sharemap),
+            })
+        })
     }
 
-    pub fn set_blacklisted(&mut self, new: bool) {
+    pub fn set_blacklisted(&mut self, new: bool, actor: Actor) {
+        if self.blacklisted == new {
+            return;
+        }
+        self.pending_changes.push(PendingChange::new(
+            actor,
+            "blacklisted",
+            self.blacklisted,
+            new,
+        ));
         self.modified = true;
         self.blacklisted = new;
     }
@@ -104,38 +95,54 @@ impl UserSettings {
             return Ok(());
         }
 
-        let read = self.serenity_data.read();
-        let connpool = read.get::<PostgreSqlContainer>().failure()?;
-        let pgconn = connpool.get()?;
-        insert_into(user_settings)
-            .values((id.eq(self.user_id as i64), blacklisted.eq(self.blacklisted)))
-            .on_conflict(id)
-            .do_update()
-            .set(blacklisted.eq(self.blacklisted))
-            .execute(&pgconn)?;
-
+        let pgconn = crate::database::connection(&self.serenity_data)?;
+        pgconn.transaction::<_, diesel::result::Error, _>(|| {
+            insert_into(user_settings)
+                .values((id.eq(self.user_id as i64), blacklisted.eq(self.blacklisted)))
+                .on_conflict(id)
+                .do_update()
+                .set(blacklisted.eq(self.blacklisted))
+                .execute(&pgconn)?;
+            audit::record(
+                &pgconn,
+                AuditTarget::User,
+                self.user_id,
+                &self.pending_changes,
+            )
+        })?;
+
+        self.pending_changes.clear();
         self.modified = false;
         Ok(())
     }
 
-    pub fn delete(mut self) -> Result<()> {
+    pub fn delete(mut self, actor: Actor) -> Result<()> {
         use crate::scheme::user_settings::dsl::*;
         use diesel::{dsl::*, prelude::*};
 
         self.modified = false;
 
-        let mut read = self.serenity_data.write();
-
         {
-            let connpool = read.get::<PostgreSqlContainer>().failure()?;
-            let pgconn = connpool.get()?;
-            delete(user_settings.filter(id.eq(self.user_id as i64))).execute(&pgconn)?;
+            let pgconn = crate::database::connection(&self.serenity_data)?;
+            pgconn.transaction::<_, diesel::result::Error, _>(|| {
+                delete(user_settings.filter(id.eq(self.user_id as i64))).execute(&pgconn)?;
+                audit::record(
+                    &pgconn,
+                    AuditTarget::User,
+                    self.user_id,
+                    &[PendingChange::new(actor, "deleted", false, true)],
+                )
+            })?;
         }
 
-        {
-            let cache = read.get_mut::<UserSettingsContainer>().failure()?;
-            cache.remove(&self.user_id);
-        }
+        // Dropping settings may save them, which needs the share map
+        let evicted = self
+            .serenity_data
+            .write()
+            .get_mut::<UserSettingsContainer>()
+            .failure()?
+            .remove(&self.user_id);
+        drop(evicted);
 
         Ok(())
     }
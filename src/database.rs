@@ -0,0 +1,142 @@
+use crate::{
+    config::{FailurePolicy, PoolConfiguration, ResilienceConfiguration},
+    data::CircuitBreakerContainer,
+    prelude::*,
+};
+use diesel::{connection::SimpleConnection, r2d2::CustomizeConnection, Connection};
+use parking_lot::RwLock;
+use std::{sync::Arc, thread, time::Duration};
+use typemap::ShareMap;
+
+pub type PgPooledConnection = PooledConnection<DieselConnectionManager<PgConnection>>;
+
+/// Sets the `statement_timeout` of every connection the pool hands out.
+#[derive(Debug)]
+struct StatementTimeout(u64);
+
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for StatementTimeout {
+    fn on_acquire(&self, conn: &mut PgConnection) -> std::result::Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(&format!("SET statement_timeout = {}", self.0))
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// Appends the TLS mode to the URL unless it already specifies one.
+pub fn connection_url(url: &str, config: &PoolConfiguration) -> String {
+    if url.contains("sslmode=") {
+        return url.to_owned();
+    }
+    format!(
+        "{}{}sslmode={}",
+        url,
+        if url.contains('?') { '&' } else { '?' },
+        config.tls_mode().sslmode()
+    )
+}
+
+/// Creates the connection pool without waiting for any connections to be
+/// established, so the bot can start while the database is down.
+pub fn create_pool(url: &str, config: &PoolConfiguration) -> Result<PgPool> {
+    let manager = DieselConnectionManager::new(connection_url(url, config));
+    let mut builder = Pool::builder()
+        .max_size(*config.max_size())
+        .min_idle(*config.min_idle())
+        .connection_timeout(Duration::from_secs(*config.connection_timeout_seconds()))
+        .max_lifetime(config.max_lifetime_seconds().map(Duration::from_secs));
+    if *config.statement_timeout_ms() != 0 {
+        builder = builder
+            .connection_customizer(Box::new(StatementTimeout(*config.statement_timeout_ms())));
+    }
+    Ok(builder.build_unchecked(manager))
+}
+
+/// Connects to the database once outside of the pool, so the exact reason it
+/// can't be reached ends up in the error.
+pub fn check_connection(url: &str, config: &PoolConfiguration) -> Result<()> {
+    let conn = PgConnection::establish(&connection_url(url, config))?;
+    conn.batch_execute("SELECT 1")?;
+    Ok(())
+}
+
+/// Gets a connection from the pool in the share map, retrying with a backoff
+/// and going through the circuit breaker.
+///
+/// The share map is only locked to take the pool out of it, so waiting on the
+/// database doesn't hold up every other handler. Callers mustn't have it locked
+/// themselves. While the breaker is open, this fails immediately instead of
+/// waiting on a database which is known to be down.
+pub fn connection(data: &Arc<RwLock<ShareMap>>) -> Result<PgPooledConnection> {
+    let (pool, breaker) = {
+        let data = data.read();
+        (
+            data.get::<PostgreSqlContainer>().failure()?.clone(),
+            Arc::clone(data.get::<CircuitBreakerContainer>().failure()?),
+        )
+    };
+
+    let (attempts, timeout, backoff) = {
+        let breaker = breaker.lock();
+        if !breaker.allow() {
+            return Err(DatabaseErrorKind::CircuitOpen.into());
+        }
+        let config = breaker.config();
+        (
+            (*config.connection_attempts()).max(1),
+            Duration::from_millis(*config.attempt_timeout_ms()),
+            Duration::from_millis(*config.retry_backoff_ms()),
+        )
+    };
+
+    let mut last_error = None;
+    for attempt in 0..attempts {
+        if attempt != 0 {
+            thread::sleep(backoff * 2u32.saturating_pow(attempt - 1));
+        }
+
+        match pool.get_timeout(timeout) {
+            Ok(conn) => {
+                breaker.lock().record_success();
+                return Ok(conn);
+            }
+            Err(e) => {
+                warn!(
+                    "Couldn't get a database connection (attempt {}/{}): {}",
+                    attempt + 1,
+                    attempts,
+                    e
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+
+    breaker.lock().record_failure();
+    Err(DatabaseErrorKind::Unavailable(
+        last_error
+            .map(|e| e.to_string())
+            .unwrap_or_else(|| String::from("no attempts were made")),
+    )
+    .into())
+}
+
+/// Whether a command may run even though the settings needed to authorise it
+/// couldn't be looked up because of the error.
+///
+/// Only the database being unreachable counts as an outage. Any other error is
+/// a bug, which mustn't let commands get around a blacklist.
+pub fn permits_degraded(
+    config: &ResilienceConfiguration,
+    command: &str,
+    error: &failure::Error,
+) -> bool {
+    if error.downcast_ref::<DatabaseErrorKind>().is_none() {
+        return false;
+    }
+    match config.failure_policy() {
+        FailurePolicy::Open => true,
+        FailurePolicy::Closed => config
+            .degraded_commands()
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(command)),
+    }
+}
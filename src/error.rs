@@ -6,10 +6,84 @@ pub type Result<T> = StdResult<T, FError>;
 
 #[derive(Debug, Fail)]
 pub enum CommandUsageKind {
-    #[fail(display = "There should only be a code block specified.")]
-    DeveloperEvaluateUsage,
+    #[fail(
+        display = "Put the code in code blocks or inline code, or attach it as a `.lisp` or \
+                   `.ket` file."
+    )]
+    DeveloperEvaluateNoCode,
+    #[fail(display = "Only ketos can be evaluated, not `{}`.", _0)]
+    DeveloperEvaluateLanguage(String),
+    #[fail(
+        display = "Only `.lisp` and `.ket` files can be evaluated, not `{}`.",
+        _0
+    )]
+    DeveloperEvaluateAttachment(String),
     #[fail(display = "No IDs were specified.")]
     DeveloperBlacklistNoIds,
+    #[fail(display = "Usage: `cache [clear <server|user> [id]]`.")]
+    DeveloperCacheUsage,
+    #[fail(display = "Usage: `session <reset|bindings|repl>`.")]
+    DeveloperSessionUsage,
+    #[fail(display = "Usage: `audit <user|server> [id] [amount]`.")]
+    AuditUsage,
+    #[fail(display = "Run `a!forgetme` first, then confirm within a minute.")]
+    ForgetMeUnconfirmed,
+    #[fail(display = "Usage: `exportconfig [json|toml]`.")]
+    ExportConfigUsage,
+    #[fail(display = "Attach a configuration file exported with `exportconfig`.")]
+    ImportConfigNoAttachment,
+    #[fail(display = "Usage: `cc <create|edit> <name> <code>`.")]
+    CustomCommandUsage,
+    #[fail(
+        display = "Usage: `hook [global] <list|show|set|remove> [event] [code]` or \
+                   `hook <enable|disable|errors [channel|off]>`."
+    )]
+    HookUsage,
+    #[fail(display = "Usage: `reason <case number> <reason>`.")]
+    ModerationReasonUsage,
+    #[fail(display = "Usage: `messagelog <channel|off>` or \
+                      `messagelog <ignore|unignore> <channel>`.")]
+    MessageLogUsage,
+    #[fail(
+        display = "Usage: `automod [list]`, `automod <set|remove> <rule> [action] [limit] \
+                   [duration]`, `automod words <add|remove> <word|/regex/>` or \
+                   `automod <exempt|unexempt> <rule> <role|channel>`."
+    )]
+    AutomodUsage,
+    #[fail(
+        display = "Usage: `antiraid [status]`, `antiraid <on|off|start|end>`, \
+                   `antiraid joins <amount> <seconds>`, `antiraid similar <amount>`, \
+                   `antiraid accountage <duration|off>` or `antiraid response <response>`."
+    )]
+    AntiraidUsage,
+    #[fail(
+        display = "Usage: `{0} [show|test|off]`, `{0} set <template>`, \
+                   `{0} channel <channel|dm>` or `{0} embed <on|off>`.",
+        _0
+    )]
+    GreetingUsage(String),
+    #[fail(
+        display = "Usage: `autorole [list]`, `autorole <add|remove> <role>` or \
+                   `autorole delay <duration|off>`."
+    )]
+    AutoroleUsage,
+    #[fail(
+        display = "Usage: `gate [status]`, `gate <reaction|command|captcha> [channel]`, \
+                   `gate off`, `gate timeout <duration|off>` or `gate approve <member>`."
+    )]
+    GateUsage,
+}
+
+#[derive(Debug, Fail)]
+pub enum PermissionErrorKind {
+    #[fail(display = "You may only view the history of a server you administrate.")]
+    AuditForeignTarget,
+    #[fail(display = "Only administrators of this server may manage its hooks.")]
+    HookNotAdministrator,
+    #[fail(display = "Only the owners of the bot may manage global hooks.")]
+    HookGlobalNotOwner,
+    #[fail(display = "<#{}> isn't a channel of this server.", _0)]
+    ForeignChannel(u64),
 }
 
 #[derive(Debug, Fail)]
@@ -19,6 +93,187 @@ Please fill it with proper information.")]
     ConfigFileGenerated,
 }
 
+#[derive(Debug, Fail)]
+pub enum CliErrorKind {
+    #[fail(display = "Usage: `asami [export|import] <archive file>`.")]
+    Usage,
+    #[fail(
+        display = "The archive is of format version {}, which isn't supported.",
+        _0
+    )]
+    UnsupportedArchive(u32),
+    #[fail(
+        display = "The archive is of schema version {}, but the database is of version {}.",
+        archive, current
+    )]
+    SchemaMismatch { archive: u32, current: u32 },
+    #[fail(
+        display = "The table {} doesn't match this version of the bot; missing columns: {}, \
+                   unexpected columns: {}.",
+        table, missing, unexpected
+    )]
+    SchemaDiffers {
+        table: String,
+        missing: String,
+        unexpected: String,
+    },
+    #[fail(
+        display = "The table {} isn't empty; only empty databases can be imported into.",
+        _0
+    )]
+    DatabaseNotEmpty(String),
+    #[fail(display = "The database couldn't be reached: {}", _0)]
+    DatabaseUnreachable(String),
+}
+
+#[derive(Debug, Fail)]
+pub enum ScriptingErrorKind {
+    #[fail(display = "The session ended before the code could be evaluated; try again.")]
+    SessionEnded,
+}
+
+#[derive(Debug, Fail)]
+pub enum CustomCommandErrorKind {
+    #[fail(
+        display = "Command names may only contain up to 32 letters, digits, dashes and \
+                   underscores."
+    )]
+    InvalidName,
+    #[fail(display = "`{}` is already a built-in command.", _0)]
+    Builtin(String),
+    #[fail(
+        display = "There already is a command named `{}`; use `cc edit` to change it.",
+        _0
+    )]
+    AlreadyExists(String),
+    #[fail(display = "There is no command named `{}`.", _0)]
+    NotFound(String),
+    #[fail(display = "A server may only have up to {} commands.", _0)]
+    TooMany(usize),
+    #[fail(display = "The code of a command may only be up to {} bytes long.", _0)]
+    TooLong(usize),
+}
+
+#[derive(Debug, Fail)]
+pub enum EventHookErrorKind {
+    #[fail(display = "There is no event named `{}`; the events are {}.", _0, _1)]
+    UnknownEvent(String, String),
+    #[fail(display = "There is no hook for `{}`.", _0)]
+    NotSet(String),
+}
+
+#[derive(Debug, Fail)]
+pub enum ModerationErrorKind {
+    #[fail(display = "You can't take action against yourself.")]
+    SelfTarget,
+    #[fail(display = "I can't take action against myself.")]
+    BotTarget,
+    #[fail(display = "Nobody can take action against the owner of the server.")]
+    OwnerTarget,
+    #[fail(display = "You can only take action against members whose roles are below yours.")]
+    Hierarchy,
+    #[fail(display = "This server has no mute role; set one with `muterole` first.")]
+    NoMuteRole,
+    #[fail(display = "There is no case #{}.", _0)]
+    CaseNotFound(i32),
+    #[fail(display = "That duration is too long.")]
+    DurationTooLong,
+}
+
+#[derive(Debug, Fail)]
+pub enum AutomodErrorKind {
+    #[fail(display = "There is no rule named `{}`; the rules are {}.", _0, _1)]
+    UnknownRule(String, String),
+    #[fail(display = "There is no action named `{}`; the actions are {}.", _0, _1)]
+    UnknownAction(String, String),
+    #[fail(
+        display = "There is no `{}` rule; add it with `automod set` first.",
+        _0
+    )]
+    NotSet(String),
+    #[fail(display = "`{}` isn't a valid regular expression: {}", _0, _1)]
+    InvalidPattern(String, String),
+    #[fail(
+        display = "A server may only have up to {} banned words and patterns.",
+        _0
+    )]
+    TooManyPatterns(usize),
+    #[fail(
+        display = "Banned words and patterns may only be up to {} bytes long.",
+        _0
+    )]
+    PatternTooLong(usize),
+    #[fail(display = "`{}` is neither a role nor a channel of this server.", _0)]
+    UnknownExemption(String),
+}
+
+#[derive(Debug, Fail)]
+pub enum AntiraidErrorKind {
+    #[fail(
+        display = "There is no response named `{}`; the responses are {}.",
+        _0, _1
+    )]
+    UnknownResponse(String, String),
+    #[fail(
+        display = "Joins may only count towards a raid for up to {} seconds.",
+        _0
+    )]
+    WindowTooLong(u64),
+    #[fail(display = "The server is already in raid mode.")]
+    AlreadyActive,
+    #[fail(display = "The server isn't in raid mode.")]
+    NotActive,
+}
+
+#[derive(Debug, Fail)]
+pub enum GreetingErrorKind {
+    #[fail(
+        display = "There is no placeholder named `{}`; the placeholders are {}.",
+        _0, _1
+    )]
+    UnknownPlaceholder(String, String),
+    #[fail(display = "A placeholder isn't closed; write `{{{{` for a brace of its own.")]
+    UnclosedPlaceholder,
+    #[fail(display = "Templates may only be up to {} characters long.", _0)]
+    TooLong(usize),
+    #[fail(
+        display = "There is no {} message; set one with `{} set` first.",
+        _0, _0
+    )]
+    NotSet(String),
+    #[fail(display = "Members who left can't be sent a DM.")]
+    FarewellDirectMessage,
+    #[fail(
+        display = "There is no greeting named `{}`; the greetings are {}.",
+        _0, _1
+    )]
+    UnknownKind(String, String),
+}
+
+#[derive(Debug, Fail)]
+pub enum VerificationErrorKind {
+    #[fail(display = "There is nothing to verify; you already have access to this server.")]
+    NotPending,
+    #[fail(display = "That isn't the code you were shown.")]
+    WrongAnswer,
+    #[fail(display = "Verify yourself by reacting to the message you were asked with.")]
+    ReactionRequired,
+    #[fail(display = "That member doesn't have to verify themselves.")]
+    MemberNotPending,
+    #[fail(display = "Add a role with `autorole add` first; verified members get those roles.")]
+    NoRoles,
+    #[fail(display = "This server already gives {} roles to joining members.", _0)]
+    TooManyRoles(usize),
+}
+
+#[derive(Debug, Fail)]
+pub enum DatabaseErrorKind {
+    #[fail(display = "The database is currently unavailable: {}", _0)]
+    Unavailable(String),
+    #[fail(display = "The database is currently unavailable; waiting before trying again.")]
+    CircuitOpen,
+}
+
 #[derive(Debug, Fail)]
 pub enum ForeignErrorKind {
     #[fail(display = "{}", _0)]
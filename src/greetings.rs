@@ -0,0 +1,245 @@
+//! Greeting members who join servers and bidding those who leave farewell.
+//!
+//! Greetings are templates in which placeholders between braces, such as
+//! `{user}`, are replaced with what they stand for. Doubled braces stand for
+//! braces themselves.
+
+use crate::{
+    antiraid, consts,
+    data::{
+        antiraid::RaidProtection,
+        greetings::{Greeting, GreetingKind, GuildGreetings},
+    },
+    prelude::*,
+};
+use chrono::Utc;
+use serenity::{
+    model::{
+        id::{ChannelId, GuildId},
+        misc::Mentionable,
+        user::User,
+    },
+    prelude::*,
+};
+use std::time::Duration;
+
+const GREETING_COLOUR: u32 = 0x002e_cc71;
+
+/// The placeholders of templates and what they stand for.
+pub const PLACEHOLDERS: [(&str, &str); 7] = [
+    ("user", "a mention of the member"),
+    ("name", "the name of the member"),
+    ("tag", "the name and discriminator of the member"),
+    ("id", "the ID of the member"),
+    ("server", "the name of the server"),
+    ("count", "the amount of members of the server"),
+    ("age", "how old the account of the member is"),
+];
+
+/// Welcomes a member who joined a server, if it welcomes members.
+pub fn member_joined(ctx: &Context, guild: GuildId, user: &User) {
+    greet(ctx, guild, user, GreetingKind::Welcome);
+}
+
+/// Bids a member who left a server farewell, if it does that.
+pub fn member_left(ctx: &Context, guild: GuildId, user: &User) {
+    greet(ctx, guild, user, GreetingKind::Farewell);
+}
+
+fn greet(ctx: &Context, guild: GuildId, user: &User, kind: GreetingKind) {
+    if user.bot {
+        return;
+    }
+    let result: Result<()> = try {
+        // Greeting every raider would only add to the spam
+        let raided = RaidProtection::new(guild.0, &ctx.data)?
+            .read()
+            .raid_started_at
+            .is_some();
+        if !raided {
+            send(ctx, guild, user, kind)?;
+        }
+    };
+    if let Err(e) = result {
+        error!(
+            "Couldn't send the {} of {} to {}: {:?}",
+            kind, guild.0, user.id.0, e
+        );
+    }
+}
+
+/// Sends a greeting of a server about the user, returning whether the server
+/// has one of the kind.
+pub fn send(ctx: &Context, guild: GuildId, user: &User, kind: GreetingKind) -> Result<bool> {
+    let greeting = match GuildGreetings::new(guild.0, &ctx.data)?
+        .read()
+        .greetings
+        .get(&kind)
+    {
+        Some(s) => s.clone(),
+        None => return Ok(false),
+    };
+    deliver(ctx, guild, user, &greeting)?;
+    Ok(true)
+}
+
+fn deliver(ctx: &Context, guild: GuildId, user: &User, greeting: &Greeting) -> Result<()> {
+    let text = render(&greeting.template, &values(ctx, guild, user))?
+        .chars()
+        .take(consts::MESSAGE_LENGTH_LIMIT)
+        .collect::<String>();
+    let channel = match greeting.channel_id {
+        Some(s) => ChannelId(s as u64),
+        None => user.id.create_dm_channel(&ctx.http)?.id,
+    };
+    channel.send_message(&ctx.http, |m| {
+        if greeting.embed {
+            m.embed(|e| {
+                e.description(&text)
+                    .colour(GREETING_COLOUR)
+                    .thumbnail(user.face())
+            })
+        } else {
+            m.content(&text)
+        }
+    })?;
+    Ok(())
+}
+
+/// What the placeholders stand for when greeting the user in the server.
+pub fn values(ctx: &Context, guild: GuildId, user: &User) -> Vec<(&'static str, String)> {
+    let (server, count) = guild
+        .to_guild_cached(&ctx.cache)
+        .map(|g| {
+            let g = g.read();
+            (g.name.clone(), g.member_count)
+        })
+        .unwrap_or_default();
+    let age = Utc::now()
+        .signed_duration_since(antiraid::created_at(user.id))
+        .to_std()
+        .unwrap_or_default();
+    vec![
+        ("user", user.mention()),
+        ("name", user.name.clone()),
+        ("tag", user.tag()),
+        ("id", user.id.0.to_string()),
+        ("server", server),
+        ("count", count.to_string()),
+        ("age", format_age(age)),
+    ]
+}
+
+/// Replaces the placeholders of a template with their values.
+pub fn render(template: &str, values: &[(&str, String)]) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '}' if chars.peek() == Some(&c) => {
+                chars.next();
+                rendered.push(c);
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(GreetingErrorKind::UnclosedPlaceholder.into()),
+                    }
+                }
+                let name = name.trim().to_lowercase();
+                match values.iter().find(|(n, _)| *n == name) {
+                    Some((_, value)) => rendered.push_str(value),
+                    None => {
+                        let placeholders = PLACEHOLDERS
+                            .iter()
+                            .map(|(p, _)| format!("`{{{}}}`", p))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        return Err(
+                            GreetingErrorKind::UnknownPlaceholder(name, placeholders).into()
+                        );
+                    }
+                }
+            }
+            c => rendered.push(c),
+        }
+    }
+    Ok(rendered)
+}
+
+/// Formats how old an account is in its largest unit, e.g. `3 days`.
+fn format_age(age: Duration) -> String {
+    const UNITS: &[(u64, &str)] = &[
+        (60 * 60 * 24 * 365, "year"),
+        (60 * 60 * 24 * 30, "month"),
+        (60 * 60 * 24, "day"),
+        (60 * 60, "hour"),
+        (60, "minute"),
+    ];
+
+    let seconds = age.as_secs();
+    let (size, unit) = UNITS
+        .iter()
+        .cloned()
+        .find(|&(size, _)| seconds >= size)
+        .unwrap_or((60, "minute"));
+    let amount = seconds / size;
+    format!("{} {}{}", amount, unit, if amount == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> Vec<(&'static str, String)> {
+        vec![
+            ("user", String::from("<@1>")),
+            ("name", String::from("Asami")),
+            ("server", String::from("Republic City")),
+        ]
+    }
+
+    #[test]
+    fn replaces_placeholders() {
+        assert_eq!(
+            render("Welcome {user} to {server}!", &values()).unwrap(),
+            "Welcome <@1> to Republic City!"
+        );
+        // Names ignore case and surrounding spaces
+        assert_eq!(render("Bye { NAME }.", &values()).unwrap(), "Bye Asami.");
+        assert_eq!(
+            render("No placeholders", &values()).unwrap(),
+            "No placeholders"
+        );
+    }
+
+    #[test]
+    fn doubled_braces_are_kept() {
+        assert_eq!(render("{{user}}", &values()).unwrap(), "{user}");
+        assert_eq!(render("{{{name}}}", &values()).unwrap(), "{Asami}");
+        assert_eq!(render("a } b", &values()).unwrap(), "a } b");
+    }
+
+    #[test]
+    fn rejects_unclosed_placeholders() {
+        for template in &["Hi {user", "{", "{{{name"] {
+            let error = render(template, &values()).unwrap_err();
+            match error.downcast_ref::<GreetingErrorKind>() {
+                Some(GreetingErrorKind::UnclosedPlaceholder) => {}
+                other => panic!("unexpected error for {}: {:?}", template, other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let error = render("Hi {nickname}", &values()).unwrap_err();
+        match error.downcast_ref::<GreetingErrorKind>() {
+            Some(GreetingErrorKind::UnknownPlaceholder(name, _)) => assert_eq!(name, "nickname"),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+}
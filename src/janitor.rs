@@ -0,0 +1,102 @@
+use crate::{
+    config::RetentionConfiguration,
+    data::{
+        antiraid,
+        audit::{self, Actor, AuditTarget, PendingChange},
+        automod, customcommands, eventhooks, greetings, moderation, onboarding, scheduledjobs,
+        ServerSettingsContainer,
+    },
+    prelude::*,
+};
+use chrono::{Duration as ChronoDuration, Utc};
+use diesel::prelude::*;
+use parking_lot::RwLock;
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use typemap::ShareMap;
+
+/// Spawns the thread which periodically deletes the data of servers the bot
+/// left longer than the grace period ago.
+pub fn spawn(data: Arc<RwLock<ShareMap>>, config: RetentionConfiguration) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name(String::from("janitor"))
+        .spawn(move || loop {
+            thread::sleep(Duration::from_secs(*config.janitor_interval_seconds()));
+            match run(&data, &config) {
+                Ok(0) => {}
+                Ok(n) => info!("The janitor deleted the data of {} departed servers.", n),
+                Err(e) => error!("The janitor couldn't clean up: {:?}", e),
+            }
+        })
+        .expect("couldn't spawn the janitor thread")
+}
+
+/// Whether the grace period of a server which departed at the given time has
+/// run out.
+pub fn expired(departed_at: chrono::DateTime<Utc>, config: &RetentionConfiguration) -> bool {
+    departed_at < cutoff(config)
+}
+
+fn cutoff(config: &RetentionConfiguration) -> chrono::DateTime<Utc> {
+    Utc::now() - ChronoDuration::seconds(*config.grace_period_seconds() as i64)
+}
+
+/// Deletes the data of every server whose grace period has run out.
+///
+/// Blacklisted servers are kept so the blacklist still applies if they invite
+/// the bot again.
+pub fn run(data: &Arc<RwLock<ShareMap>>, config: &RetentionConfiguration) -> Result<usize> {
+    use crate::scheme::server_settings::dsl::*;
+
+    let pgconn = crate::database::connection(data)?;
+    let expired = server_settings
+        .filter(departed_at.lt(cutoff(config)))
+        .filter(blacklisted.eq(false))
+        .select(id)
+        .load::<i64>(&pgconn)?;
+
+    for &server in &expired {
+        pgconn
+            .transaction::<_, diesel::result::Error, _>(|| purge_guild(&pgconn, server as u64))?;
+    }
+
+    let evicted = {
+        let mut write = data.write();
+        let cache = write.get_mut::<ServerSettingsContainer>().failure()?;
+        expired
+            .iter()
+            .filter_map(|&server| cache.remove(&(server as u64)))
+            .collect::<Vec<_>>()
+    };
+    // Dropping settings may save them, which needs the share map
+    drop(evicted);
+
+    Ok(expired.len())
+}
+
+/// Deletes everything stored about a server.
+///
+/// This should be run in a transaction, and the cached settings of the server
+/// evicted afterwards.
+pub fn purge_guild(conn: &PgConnection, guild: u64) -> QueryResult<()> {
+    use crate::scheme::server_settings::dsl::*;
+
+    diesel::delete(server_settings.filter(id.eq(guild as i64))).execute(conn)?;
+    customcommands::delete_all(conn, guild)?;
+    eventhooks::delete_all(conn, guild)?;
+    moderation::delete_all(conn, guild)?;
+    scheduledjobs::delete_all(conn, guild)?;
+    automod::delete_all(conn, guild)?;
+    antiraid::delete_all(conn, guild)?;
+    greetings::delete_all(conn, guild)?;
+    onboarding::delete_all(conn, guild)?;
+    audit::record(
+        conn,
+        AuditTarget::Server,
+        guild,
+        &[PendingChange::new(Actor::event(), "deleted", false, true)],
+    )
+}
@@ -1,6 +1,6 @@
 use ketos::io::{IoError, IoMode, SharedWrite};
 use std::{
-    cell::UnsafeCell,
+    cell::{Cell, UnsafeCell},
     fmt::Arguments,
     io::{prelude::*, Cursor},
     path::Path,
@@ -11,6 +11,10 @@ where
     W: Write,
 {
     write: UnsafeCell<W>,
+    /// The maximum amount of bytes passed on to the writer; the rest is dropped.
+    limit: Option<usize>,
+    written: Cell<usize>,
+    truncated: Cell<bool>,
 }
 
 impl<W> KetosWriteWrapper<W>
@@ -20,21 +24,61 @@ where
     pub fn new(write: W) -> Self {
         KetosWriteWrapper {
             write: UnsafeCell::new(write),
+            limit: None,
+            written: Cell::new(0),
+            truncated: Cell::new(false),
         }
     }
 
-    pub fn inner(&self) -> &W {
-        unsafe { &*self.write.get() }
+    pub fn with_limit(write: W, limit: usize) -> Self {
+        KetosWriteWrapper {
+            limit: Some(limit),
+            ..Self::new(write)
+        }
+    }
+
+    /// Whether anything was dropped because of the limit.
+    pub fn truncated(&self) -> bool {
+        self.truncated.get()
+    }
+
+    fn write_capped(&self, buf: &[u8]) -> std::io::Result<()> {
+        let buf = match self.limit {
+            Some(limit) => {
+                let left = limit.saturating_sub(self.written.get());
+                if buf.len() > left {
+                    self.truncated.set(true);
+                    &buf[..left]
+                } else {
+                    buf
+                }
+            }
+            None => buf,
+        };
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let mutable = unsafe { &mut *self.write.get() };
+        mutable.write_all(buf)?;
+        self.written.set(self.written.get() + buf.len());
+        Ok(())
     }
 }
 
 impl KetosWriteWrapper<Cursor<Vec<u8>>> {
-    pub fn bytearray() -> Self {
-        Self::new(Cursor::new(Vec::new()))
+    pub fn bytearray_with_limit(limit: usize) -> Self {
+        Self::with_limit(Cursor::new(Vec::new()), limit)
     }
 
-    pub fn as_string(&self) -> Result<String, std::string::FromUtf8Error> {
-        String::from_utf8(self.inner().clone().into_inner())
+    /// Takes everything written so far, leaving the buffer empty and resetting
+    /// the limit.
+    pub fn take_string(&self) -> String {
+        let mutable = unsafe { &mut *self.write.get() };
+        let bytes = std::mem::replace(mutable, Cursor::new(Vec::new())).into_inner();
+        self.written.set(0);
+        self.truncated.set(false);
+        String::from_utf8_lossy(&bytes).into_owned()
     }
 }
 
@@ -43,17 +87,13 @@ where
     W: Write,
 {
     fn write_all(&self, buf: &[u8]) -> Result<(), IoError> {
-        let mutable = unsafe { &mut *self.write.get() };
-        mutable
-            .write_all(buf)
+        self.write_capped(buf)
             .map_err(|e| IoError::new(IoMode::Write, Path::new("<KetosWriteWrapper>"), e))?;
         Ok(())
     }
 
     fn write_fmt(&self, fmt: Arguments) -> Result<(), IoError> {
-        let mutable = unsafe { &mut *self.write.get() };
-        mutable
-            .write_fmt(fmt)
+        self.write_capped(fmt.to_string().as_bytes())
             .map_err(|e| IoError::new(IoMode::Write, Path::new("<KetosWriteWrapper>"), e))?;
         Ok(())
     }
@@ -2,25 +2,60 @@
 
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate ketos;
+#[macro_use]
+extern crate ketos_derive;
 
 use self::{
-    commands::{DEVELOPER_GROUP, MISCELLANEOUS_GROUP},
+    commands::{
+        ADMINISTRATION_GROUP, CUSTOMCOMMANDS_GROUP, DEVELOPER_GROUP, MISCELLANEOUS_GROUP,
+        MODERATION_GROUP, PRIVACY_GROUP,
+    },
     config::Configuration,
     data::{
-        OwnerContainer, PostgreSqlContainer, ServerSettings, ServerSettingsContainer,
-        ShardManagerContainer, UserSettings, UserSettingsContainer,
+        antiraid::{JoinTracker, JoinTrackerContainer, RaidProtectionContainer},
+        automod::{AutomodContainer, SpamTracker, SpamTrackerContainer},
+        eventhooks::EventHooksContainer,
+        greetings::GreetingsContainer,
+        messagecache::{MessageCache, MessageCacheContainer},
+        onboarding::OnboardingContainer,
+        CircuitBreaker, CircuitBreakerContainer, ConfigurationContainer, OwnerContainer,
+        PendingDeletionsContainer, PostgreSqlContainer, ServerSettings, ServerSettingsContainer,
+        SettingsCache, ShardManagerContainer, UserSettings, UserSettingsContainer,
     },
     prelude::*,
+    scripting::{
+        custom::{RunningScripts, RunningScriptsContainer},
+        EvaluationsContainer, ReplChannelsContainer, SessionsContainer,
+    },
+};
+use serenity::{
+    framework::{standard::DispatchError, StandardFramework},
+    prelude::*,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
 };
-use lru_time_cache::LruCache;
-use serenity::{framework::StandardFramework, prelude::*};
-use std::{collections::HashSet, sync::Arc, time::Duration};
 
+mod antiraid;
+mod automod;
+mod backup;
 mod commands;
 mod config;
 mod data;
+mod database;
 mod error;
+mod greetings;
+mod janitor;
 mod ketoswritewrapper;
+mod messagelog;
+mod modlog;
+mod onboarding;
+mod scheduler;
+mod scripting;
 mod serenityhandler;
 
 pub mod consts;
@@ -54,6 +89,7 @@ fn main() -> Result<()> {
             }
         }
     };
+    let config = Arc::new(config);
 
     // Initialise and use the SimpleLog logger along with the log crate's macros.
     println!("Making logger...");
@@ -73,9 +109,25 @@ fn main() -> Result<()> {
 
     // Connect to PgSql with R2D2 pooling.
     info!("Creating connection pool with PostgreSql...");
-    let pgsql = DieselConnectionManager::new(config.pgsql_url().to_owned());
-    let pgsql = PgPool::new(pgsql)?;
-    info!("PgSql connection pool created!");
+    let pgsql = self::database::create_pool(config.pgsql_url(), config.pgsql_pool())?;
+    let reachable = self::database::check_connection(config.pgsql_url(), config.pgsql_pool());
+    match &reachable {
+        Ok(()) => info!("PgSql connection pool created!"),
+        Err(e) => error!(
+            "PgSql connection pool created, but the database couldn't be reached: {}",
+            e
+        ),
+    }
+
+    // Run the command line interface instead of the bot if it was asked for.
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        // Unlike the bot, backups can't do anything without the database
+        if let Err(e) = reachable {
+            return Err(CliErrorKind::DatabaseUnreachable(e.to_string()).into());
+        }
+        return self::backup::run_cli(&args, &pgsql);
+    }
 
     // Create the Discord client.
     let mut discord_client: Client =
@@ -100,18 +152,49 @@ fn main() -> Result<()> {
         let mut data = discord_client.data.write();
         data.insert::<ShardManagerContainer>(Arc::clone(&discord_client.shard_manager));
         data.insert::<PostgreSqlContainer>(pgsql.clone());
-        #[rustfmt::skip]
-        data.insert::<ServerSettingsContainer>(
-            LruCache::with_expiry_duration(Duration::from_secs(60 * 5))
-        );
-        #[rustfmt::skip]
-        data.insert::<UserSettingsContainer>(
-            LruCache::with_expiry_duration(Duration::from_secs(60 * 5))
-        );
+        data.insert::<CircuitBreakerContainer>(Arc::new(Mutex::new(CircuitBreaker::new(
+            config.database_resilience().clone(),
+        ))));
+        data.insert::<ServerSettingsContainer>(SettingsCache::new(config.server_settings_cache()));
+        data.insert::<UserSettingsContainer>(SettingsCache::new(config.user_settings_cache()));
+        data.insert::<EventHooksContainer>(SettingsCache::new(config.event_hooks_cache()));
+        data.insert::<AutomodContainer>(SettingsCache::new(config.automod_cache()));
+        data.insert::<SpamTrackerContainer>(Mutex::new(SpamTracker::new(Duration::from_secs(
+            *config.automod().spam_window_seconds(),
+        ))));
+        data.insert::<RaidProtectionContainer>(SettingsCache::new(config.raid_protection_cache()));
+        data.insert::<JoinTrackerContainer>(Mutex::new(JoinTracker::new(
+            *config.antiraid().tracked_guilds(),
+            Duration::from_secs(*config.antiraid().max_join_window_seconds()),
+        )));
+        data.insert::<GreetingsContainer>(SettingsCache::new(config.greetings_cache()));
+        data.insert::<OnboardingContainer>(SettingsCache::new(config.onboarding_cache()));
+        data.insert::<MessageCacheContainer>(Mutex::new(MessageCache::new(config.message_cache())));
         data.insert::<OwnerContainer>(owners.clone());
+        data.insert::<ConfigurationContainer>(Arc::clone(&config));
+        data.insert::<PendingDeletionsContainer>(HashMap::new());
+        data.insert::<EvaluationsContainer>(HashMap::new());
+        data.insert::<RunningScriptsContainer>(Arc::new(Mutex::new(RunningScripts::default())));
+        data.insert::<SessionsContainer>(HashMap::new());
+        data.insert::<ReplChannelsContainer>(HashSet::new());
     }
 
+    // Clean up after servers the bot has left
+    self::janitor::spawn(
+        Arc::clone(&discord_client.data),
+        config.guild_retention().clone(),
+    );
+
+    // Run temporary bans, mutes and blacklistings out
+    self::scheduler::spawn(
+        discord_client.cache_and_http.cache.clone(),
+        Arc::clone(&discord_client.cache_and_http.http),
+        Arc::clone(&discord_client.data),
+        config.scheduler().clone(),
+    );
+
     // Configure the bot
+    let resilience = config.database_resilience().clone();
     discord_client.with_framework(
         StandardFramework::new()
             .configure(|c| {
@@ -119,10 +202,10 @@ fn main() -> Result<()> {
                     .case_insensitivity(true) // allow `a!cOmMaNd`
                     .no_dm_prefix(true) // allow `command` in dm
                     .owners(owners) // set owners of the bot
-                    .prefix("a!") // set the prefix to `a!`
+                    .prefix(consts::PREFIX) // set the prefix to `a!`
                     .delimiters(vec![" "]) // split arguments at space; `a!test a b c` => `[a!test, a, b, c]`
             })
-            .before(|ctx, msg, _| {
+            .before(move |ctx, msg, command| {
                 {
                     // Always allow owners of the bot to use it
                     let data = ctx.data.read();
@@ -137,17 +220,24 @@ fn main() -> Result<()> {
 
                 // TODO: Support server-specific permission levels
                 // TODO: Support user blacklisting set by server admins
-                let settings = match UserSettings::new(msg.author.id.0, &ctx.data) {
+                match UserSettings::new(msg.author.id.0, &ctx.data) {
                     Err(e) => {
                         error!("Couldn't get user data: {:?}", e);
-                        let _ = msg.reply(&ctx, "An error occurred while fetching your user data.");
-                        return false;
+                        if !self::database::permits_degraded(&resilience, command, &e) {
+                            let _ = msg.reply(
+                                &ctx,
+                                "An error occurred while fetching your user data. \
+                                 Only a few commands are available until it is resolved.",
+                            );
+                            return false;
+                        }
+                        warn!("Letting `{}` through in degraded mode.", command);
+                    }
+                    Ok(settings) => {
+                        if *settings.read().blacklisted() {
+                            return false;
+                        }
                     }
-                    Ok(s) => s,
-                };
-                let read = settings.read();
-                if *read.blacklisted() {
-                    return false;
                 }
 
                 // TODO: Support channel white-/blacklisting set by server admins
@@ -156,15 +246,13 @@ fn main() -> Result<()> {
                     Some(s) => s,
                     None => return true,
                 };
-                let settings = match ServerSettings::new(guild_id.0, &ctx.data) {
+                match ServerSettings::new(guild_id.0, &ctx.data) {
                     Err(e) => {
                         error!("Couldn't get server data: {:?}", e);
-                        return false;
+                        self::database::permits_degraded(&resilience, command, &e)
                     }
-                    Ok(s) => s,
-                };
-                let read = settings.read();
-                !*read.blacklisted()
+                    Ok(settings) => !*settings.read().blacklisted(),
+                }
             })
             .after(|ctx, msg, _, err| {
                 if let Err(e) = err {
@@ -175,8 +263,23 @@ fn main() -> Result<()> {
                     );
                 }
             })
+            .on_dispatch_error(|ctx, msg, err| {
+                if let DispatchError::Ratelimited(seconds) = err {
+                    let _ = msg.reply(&ctx, &format!("Please try again in {} seconds.", seconds));
+                }
+            })
+            .unrecognised_command(|ctx, msg, name| {
+                // Commands servers define are run when nothing built-in matches
+                self::scripting::custom::dispatch(ctx, msg, name)
+            })
+            .bucket("privacy", |b| b.delay(60 * 10)) // one data request per user every 10 minutes
+            .bucket("verification", |b| b.delay(5)) // one captcha guess per user every 5 seconds
             .help(&self::commands::help::HELP_MENU_HELP_COMMAND)
             .group(&MISCELLANEOUS_GROUP)
+            .group(&ADMINISTRATION_GROUP)
+            .group(&MODERATION_GROUP)
+            .group(&PRIVACY_GROUP)
+            .group(&CUSTOMCOMMANDS_GROUP)
             .group(&DEVELOPER_GROUP),
     );
 
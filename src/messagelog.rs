@@ -0,0 +1,332 @@
+//! Logging edited and deleted messages to the message log channels of servers.
+//!
+//! Discord only tells which messages were deleted, not what they said, so the
+//! messages of servers which log them are remembered for a while.
+
+use crate::{
+    consts,
+    data::{
+        messagecache::{CachedMessage, MessageCacheContainer},
+        ServerSettings,
+    },
+    prelude::*,
+};
+use chrono::Utc;
+use serenity::{
+    builder::CreateEmbed,
+    model::{
+        channel::{Channel, Message},
+        event::MessageUpdateEvent,
+        id::{ChannelId, GuildId, MessageId},
+        misc::Mentionable,
+    },
+    prelude::*,
+};
+
+const EDITED_COLOUR: u32 = 0x0034_98db;
+const DELETED_COLOUR: u32 = 0x00e7_4c3c;
+
+/// The name of the transcript attached when messages are deleted in bulk.
+const TRANSCRIPT_FILE_NAME: &str = "deleted-messages.txt";
+
+/// Remembers a message if its server logs the messages of its channel.
+pub fn remember(ctx: &Context, guild: GuildId, msg: &Message) {
+    if msg.author.bot {
+        return;
+    }
+    match log_channel(ctx, guild, msg.channel_id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return,
+        Err(e) => {
+            error!("Couldn't look up the message log of {}: {:?}", guild.0, e);
+            return;
+        }
+    }
+
+    if let Some(cache) = ctx.data.read().get::<MessageCacheContainer>() {
+        cache
+            .lock()
+            .insert(msg.id.0, CachedMessage::new(guild, msg));
+    }
+}
+
+/// Logs the content of a message before and after it was edited.
+pub fn edited(ctx: &Context, event: &MessageUpdateEvent) {
+    if let Err(e) = try_edited(ctx, event) {
+        error!("Couldn't log the edit of message {}: {:?}", event.id.0, e);
+    }
+}
+
+/// Logs what a deleted message said, if it was remembered.
+pub fn deleted(ctx: &Context, channel: ChannelId, message: MessageId) {
+    if let Err(e) = try_deleted(ctx, channel, message) {
+        error!(
+            "Couldn't log the deletion of message {}: {:?}",
+            message.0, e
+        );
+    }
+}
+
+/// Logs messages which were deleted at once, attaching a transcript of those
+/// which were remembered.
+pub fn bulk_deleted(ctx: &Context, channel: ChannelId, messages: &[MessageId]) {
+    if let Err(e) = try_bulk_deleted(ctx, channel, messages) {
+        error!(
+            "Couldn't log the deletion of {} messages in {}: {:?}",
+            messages.len(),
+            channel.0,
+            e
+        );
+    }
+}
+
+fn try_edited(ctx: &Context, event: &MessageUpdateEvent) -> Result<()> {
+    // Updates without content are embeds being resolved, not edits
+    let content = match event.content {
+        Some(ref s) => s,
+        None => return Ok(()),
+    };
+
+    let old = take(ctx, event.id)?;
+    let (guild, before) = match old {
+        Some(mut old) => {
+            if old.content == *content {
+                remember_again(ctx, event.id, old)?;
+                return Ok(());
+            }
+            let before = old.clone();
+            old.content = content.clone();
+            remember_again(ctx, event.id, old)?;
+            (GuildId(before.guild_id), Some(before))
+        }
+        None => {
+            if event.author.as_ref().map(|a| a.bot).unwrap_or(false) {
+                return Ok(());
+            }
+            match guild_of(ctx, event.channel_id) {
+                Some(s) => (s, None),
+                None => return Ok(()),
+            }
+        }
+    };
+    let log = match log_channel(ctx, guild, event.channel_id)? {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+
+    let author = match (&before, &event.author) {
+        (Some(before), _) => format!("<@{}> ({})", before.author_id, before.author_tag),
+        (None, Some(author)) => format!("{} ({})", author.mention(), author.tag()),
+        (None, None) => String::from("Unknown"),
+    };
+    log.send_message(&ctx.http, |m| {
+        m.embed(|e| {
+            header(
+                e,
+                "Message edited",
+                EDITED_COLOUR,
+                guild,
+                event.channel_id,
+                event.id,
+            )
+            .field("Author", &author, true)
+            .field("Channel", event.channel_id.mention(), true)
+            .field(
+                "Before",
+                match before {
+                    Some(ref before) => field_value(&before.content),
+                    None => String::from("*The message wasn't remembered.*"),
+                },
+                false,
+            )
+            .field("After", field_value(content), false)
+        })
+    })?;
+    Ok(())
+}
+
+fn try_deleted(ctx: &Context, channel: ChannelId, message: MessageId) -> Result<()> {
+    let cached = take(ctx, message)?;
+    let guild = match cached {
+        Some(ref cached) => GuildId(cached.guild_id),
+        None => match guild_of(ctx, channel) {
+            Some(s) => s,
+            None => return Ok(()),
+        },
+    };
+    let log = match log_channel(ctx, guild, channel)? {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+
+    log.send_message(&ctx.http, |m| {
+        m.embed(|e| {
+            header(
+                e,
+                "Message deleted",
+                DELETED_COLOUR,
+                guild,
+                channel,
+                message,
+            );
+            match cached {
+                Some(ref cached) => {
+                    e.field(
+                        "Author",
+                        format!("<@{}> ({})", cached.author_id, cached.author_tag),
+                        true,
+                    )
+                    .field("Channel", channel.mention(), true)
+                    .field("Content", field_value(&cached.content), false);
+                    if !cached.attachments.is_empty() {
+                        e.field(
+                            "Attachments",
+                            field_value(&cached.attachments.join("\n")),
+                            false,
+                        );
+                    }
+                    e
+                }
+                None => e.field("Channel", channel.mention(), true).field(
+                    "Content",
+                    "*The message wasn't remembered.*",
+                    false,
+                ),
+            }
+        })
+    })?;
+    Ok(())
+}
+
+fn try_bulk_deleted(ctx: &Context, channel: ChannelId, messages: &[MessageId]) -> Result<()> {
+    let mut cached = {
+        let data = ctx.data.read();
+        let mut cache = data.get::<MessageCacheContainer>().failure()?.lock();
+        messages
+            .iter()
+            .filter_map(|id| cache.remove(&id.0))
+            .collect::<Vec<_>>()
+    };
+    let guild = match guild_of(ctx, channel) {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+    let log = match log_channel(ctx, guild, channel)? {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+
+    let summary = format!(
+        "{} messages were deleted at once in {}; {} of them were remembered.",
+        messages.len(),
+        channel.mention(),
+        cached.len()
+    );
+    if cached.is_empty() {
+        log.say(&ctx.http, &summary)?;
+        return Ok(());
+    }
+
+    cached.sort_by_key(|m| m.created_at);
+    let transcript = cached
+        .iter()
+        .map(|m| {
+            let mut line = format!(
+                "[{}] {} ({}): {}",
+                m.created_at.format("%Y-%m-%d %H:%M:%S"),
+                m.author_tag,
+                m.author_id,
+                m.content
+            );
+            if !m.attachments.is_empty() {
+                line.push_str(&format!(" [attachments: {}]", m.attachments.join(", ")));
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    log.send_files(
+        &ctx.http,
+        vec![(transcript.as_bytes(), TRANSCRIPT_FILE_NAME)],
+        |m| m.content(&summary),
+    )?;
+    Ok(())
+}
+
+/// The channel the messages of the channel in the server are logged to, if they
+/// are logged.
+fn log_channel(ctx: &Context, guild: GuildId, channel: ChannelId) -> Result<Option<ChannelId>> {
+    let settings = ServerSettings::new(guild.0, &ctx.data)?;
+    let settings = settings.read();
+    let log = match *settings.message_log_channel() {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    // Logging the log itself would only repeat what is already in it
+    if log == channel.0 || settings.message_log_ignored().contains(&channel.0) {
+        return Ok(None);
+    }
+    Ok(Some(ChannelId(log)))
+}
+
+fn guild_of(ctx: &Context, channel: ChannelId) -> Option<GuildId> {
+    match channel.to_channel_cached(&ctx.cache) {
+        Some(Channel::Guild(channel)) => Some(channel.read().guild_id),
+        _ => None,
+    }
+}
+
+fn take(ctx: &Context, message: MessageId) -> Result<Option<CachedMessage>> {
+    Ok(ctx
+        .data
+        .read()
+        .get::<MessageCacheContainer>()
+        .failure()?
+        .lock()
+        .remove(&message.0))
+}
+
+fn remember_again(ctx: &Context, message: MessageId, cached: CachedMessage) -> Result<()> {
+    ctx.data
+        .read()
+        .get::<MessageCacheContainer>()
+        .failure()?
+        .lock()
+        .insert(message.0, cached);
+    Ok(())
+}
+
+/// Fills in what every post of the message log starts with.
+fn header<'a>(
+    e: &'a mut CreateEmbed,
+    title: &str,
+    colour: u32,
+    guild: GuildId,
+    channel: ChannelId,
+    message: MessageId,
+) -> &'a mut CreateEmbed {
+    e.title(title)
+        .colour(colour)
+        .description(format!(
+            "[Jump to the conversation](https://discordapp.com/channels/{}/{}/{})",
+            guild.0, channel.0, message.0
+        ))
+        .footer(|f| f.text(format!("Message ID: {}", message.0)))
+        .timestamp(&Utc::now())
+}
+
+/// Fits text into the value of a field of an embed.
+fn field_value(text: &str) -> String {
+    if text.is_empty() {
+        return String::from("*No text.*");
+    }
+    if text.chars().count() <= consts::EMBED_FIELD_LENGTH_LIMIT {
+        return text.to_owned();
+    }
+    let mut value = text
+        .chars()
+        .take(consts::EMBED_FIELD_LENGTH_LIMIT - 1)
+        .collect::<String>();
+    value.push('…');
+    value
+}
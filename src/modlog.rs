@@ -0,0 +1,140 @@
+//! Posting moderation cases to the modlog channels of servers, and keeping the
+//! posts up to date when cases change.
+
+use crate::{
+    consts,
+    data::{
+        moderation::{self, Case, CaseAction},
+        ServerSettings,
+    },
+    prelude::*,
+};
+use parking_lot::RwLock;
+use serenity::{
+    builder::CreateEmbed,
+    http::Http,
+    model::id::{ChannelId, MessageId},
+};
+use std::sync::Arc;
+use typemap::ShareMap;
+
+/// Posts a case to the modlog channel of its server, if it has one.
+///
+/// Failing to post doesn't undo the action the case is about, so errors are only
+/// logged.
+pub fn post(http: &Arc<Http>, data: &Arc<RwLock<ShareMap>>, case: &Case) {
+    if let Err(e) = try_post(http, data, case) {
+        error!(
+            "Couldn't post case #{} of {} to the modlog: {:?}",
+            case.case_number, case.guild_id, e
+        );
+    }
+}
+
+/// Updates the post of a case after it was changed, posting it anew if the
+/// original post can't be edited anymore.
+pub fn update(http: &Arc<Http>, data: &Arc<RwLock<ShareMap>>, case: &Case) {
+    if let Err(e) = try_update(http, data, case) {
+        error!(
+            "Couldn't update case #{} of {} in the modlog: {:?}",
+            case.case_number, case.guild_id, e
+        );
+    }
+}
+
+fn try_post(http: &Arc<Http>, data: &Arc<RwLock<ShareMap>>, case: &Case) -> Result<()> {
+    let channel = match channel(data, case)? {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+
+    let message = channel.send_message(http, |m| m.embed(|e| embed(e, case)))?;
+
+    let pgconn = crate::database::connection(data)?;
+    moderation::set_modlog_message(
+        &pgconn,
+        case.guild_id as u64,
+        case.case_number,
+        message.id.0,
+    )?;
+    Ok(())
+}
+
+fn try_update(http: &Arc<Http>, data: &Arc<RwLock<ShareMap>>, case: &Case) -> Result<()> {
+    let channel = match channel(data, case)? {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+
+    if let Some(message) = case.modlog_message_id {
+        // The post may have been deleted, or the modlog moved to another channel.
+        let edited = channel.edit_message(http, MessageId(message as u64), |m| {
+            m.embed(|e| embed(e, case))
+        });
+        if edited.is_ok() {
+            return Ok(());
+        }
+    }
+    try_post(http, data, case)
+}
+
+fn channel(data: &Arc<RwLock<ShareMap>>, case: &Case) -> Result<Option<ChannelId>> {
+    let settings = ServerSettings::new(case.guild_id as u64, data)?;
+    let channel = *settings.read().modlog_channel();
+    Ok(channel.map(ChannelId))
+}
+
+/// The colour of the posts of an action, from red for the harshest to green for
+/// those undoing others.
+fn colour(action: &str) -> u32 {
+    match action.parse() {
+        Ok(CaseAction::Ban) => 0x00e7_4c3c,
+        Ok(CaseAction::Softban) | Ok(CaseAction::Kick) => 0x00e6_7e22,
+        Ok(CaseAction::Mute) | Ok(CaseAction::Warn) => 0x00f1_c40f,
+        Ok(CaseAction::Unban) | Ok(CaseAction::Unmute) => 0x002e_cc71,
+        Err(()) => 0x0095_a5a6,
+    }
+}
+
+/// Fills in the embed every case is posted as.
+fn embed<'a>(e: &'a mut CreateEmbed, case: &Case) -> &'a mut CreateEmbed {
+    e.title(format!("Case #{} | {}", case.case_number, case.action))
+        .colour(colour(&case.action))
+        .field("User", format!("<@{0}> ({0})", case.target_id), true)
+        .field(
+            "Moderator",
+            match case.moderator_id {
+                Some(id) => format!("<@{}>", id),
+                None => String::from("Automatic"),
+            },
+            true,
+        );
+    if let Some(duration) = case.duration() {
+        e.field("Duration", moderation::format_duration(duration), true);
+    }
+    e.field(
+        "Reason",
+        match case.reason {
+            Some(ref reason) => reason
+                .chars()
+                .take(consts::EMBED_FIELD_LENGTH_LIMIT)
+                .collect::<String>(),
+            None => format!(
+                "No reason given yet; set one with `{}reason {} <reason>`.",
+                consts::PREFIX,
+                case.case_number
+            ),
+        },
+        false,
+    )
+    .timestamp(&case.created_at);
+    if case.updated_at != case.created_at {
+        e.footer(|f| {
+            f.text(format!(
+                "Last changed {}",
+                case.updated_at.format("%Y-%m-%d %H:%M:%S UTC")
+            ))
+        });
+    }
+    e
+}
@@ -0,0 +1,359 @@
+//! Giving joining members the roles of their server, once they have verified
+//! themselves if the server asks them to.
+//!
+//! Members who have to verify themselves are asked to in the verification
+//! channel of the server. Kicking those who don't in time, and giving roles
+//! after a delay, is left to the scheduler so it survives restarts.
+
+use crate::{
+    consts,
+    data::{
+        moderation,
+        onboarding::{self, Onboarding, PendingVerification, VerificationMethod},
+        scheduledjobs::{self, JobKind},
+    },
+    prelude::*,
+};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::Connection;
+use parking_lot::RwLock;
+use rand::Rng;
+use serenity::{
+    http::Http,
+    model::{
+        channel::{Reaction, ReactionType},
+        guild::Member,
+        id::{ChannelId, GuildId, MessageId, UserId},
+        misc::Mentionable,
+        user::User,
+    },
+    prelude::*,
+};
+use std::sync::Arc;
+use typemap::ShareMap;
+
+/// The emoji members react with to verify themselves.
+pub const VERIFY_EMOJI: &str = "✅";
+
+const CAPTCHA_LENGTH: usize = 6;
+/// The characters of captchas, leaving out those which are easily mistaken for
+/// one another.
+const CAPTCHA_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// Asks a member who joined a server to verify themselves, or gives them the
+/// roles of the server if it doesn't ask members to.
+pub fn member_joined(ctx: &Context, guild: GuildId, member: &Member) {
+    let user = member.user.read().clone();
+    // Bots can only be added by administrators
+    if user.bot {
+        return;
+    }
+    let result: Result<()> = try {
+        let settings = Onboarding::new(guild.0, &ctx.data)?.read().clone();
+        match settings.verification() {
+            Some(method) => ask(ctx, guild, &user, &settings, method)?,
+            None => grant(ctx, guild, user.id, &settings, Utc::now())?,
+        }
+    };
+    if let Err(e) = result {
+        error!("Couldn't onboard {} in {}: {:?}", user.id.0, guild.0, e);
+    }
+}
+
+/// Forgets about a member who left a server before verifying themselves or
+/// getting their roles.
+pub fn member_left(ctx: &Context, guild: GuildId, user: &User) {
+    if user.bot {
+        return;
+    }
+    let result: Result<()> = try {
+        let pending = {
+            let pgconn = crate::database::connection(&ctx.data)?;
+            pgconn.transaction::<_, diesel::result::Error, _>(|| {
+                scheduledjobs::cancel(&pgconn, JobKind::KickUnverified, guild.0, user.id.0)?;
+                scheduledjobs::cancel(&pgconn, JobKind::AddRoles, guild.0, user.id.0)?;
+                onboarding::remove_pending(&pgconn, guild.0, user.id.0)
+            })?
+        };
+        if let Some(pending) = pending {
+            remove_prompt(&ctx.http, &pending);
+        }
+    };
+    if let Err(e) = result {
+        error!(
+            "Couldn't forget the onboarding of {} in {}: {:?}",
+            user.id.0, guild.0, e
+        );
+    }
+}
+
+/// Verifies a member who reacted to the message they were asked with.
+pub fn reaction_added(ctx: &Context, guild: GuildId, reaction: &Reaction) {
+    match &reaction.emoji {
+        ReactionType::Unicode(emoji) if emoji == VERIFY_EMOJI => {}
+        _ => return,
+    }
+    let result: Result<()> = try {
+        // Most servers don't gate members, so this spares looking up reactions
+        let gated = Onboarding::new(guild.0, &ctx.data)?
+            .read()
+            .verification_channel_id
+            .is_some();
+        if gated {
+            let pending = {
+                let pgconn = crate::database::connection(&ctx.data)?;
+                onboarding::pending_by_message(&pgconn, reaction.message_id.0)?
+            };
+            let reacted = pending.map_or(false, |p| {
+                p.user_id as u64 == reaction.user_id.0 && p.method() == VerificationMethod::Reaction
+            });
+            if reacted {
+                verify(ctx, guild, reaction.user_id)?;
+            }
+        }
+    };
+    if let Err(e) = result {
+        error!(
+            "Couldn't verify {} in {} by reaction: {:?}",
+            reaction.user_id.0, guild.0, e
+        );
+    }
+}
+
+/// Asks a member to verify themselves in the verification channel, and has
+/// them kicked if they don't in time.
+fn ask(
+    ctx: &Context,
+    guild: GuildId,
+    user: &User,
+    settings: &Onboarding,
+    method: VerificationMethod,
+) -> Result<()> {
+    let channel = ChannelId(settings.verification_channel_id.failure()? as u64);
+    let answer = match method {
+        VerificationMethod::Captcha => Some(captcha()),
+        _ => None,
+    };
+    let mut text = match (method, &answer) {
+        (VerificationMethod::Reaction, _) => format!(
+            "{}, welcome! React with {} to this message to get access to the server.",
+            user.mention(),
+            VERIFY_EMOJI
+        ),
+        (VerificationMethod::Captcha, Some(answer)) => format!(
+            "{}, welcome! Run `{}verify <code>` here with the code below to get access to \
+             the server.\n```\n{}\n```",
+            user.mention(),
+            consts::PREFIX,
+            // Spacing the code out keeps it from being searched for as a whole
+            answer
+                .chars()
+                .map(String::from)
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+        _ => format!(
+            "{}, welcome! Run `{}verify` here to get access to the server.",
+            user.mention(),
+            consts::PREFIX
+        ),
+    };
+    if let Some(timeout) = settings.verification_timeout() {
+        text.push_str(&format!(
+            "\nYou'll be kicked if you don't within {}.",
+            moderation::format_duration(timeout)
+        ));
+    }
+
+    // The member has to be verified even if they can't be asked, so moderators
+    // can let them in by hand
+    let message = match channel.say(&ctx.http, &text) {
+        Ok(message) => {
+            if method == VerificationMethod::Reaction {
+                let emoji = ReactionType::Unicode(VERIFY_EMOJI.to_owned());
+                if let Err(e) = channel.create_reaction(&ctx.http, message.id, emoji) {
+                    warn!(
+                        "Couldn't react to the verification of {}: {:?}",
+                        user.id.0, e
+                    );
+                }
+            }
+            Some(message.id.0 as i64)
+        }
+        Err(e) => {
+            warn!(
+                "Couldn't ask {} to verify themselves in {}: {:?}",
+                user.id.0, guild.0, e
+            );
+            None
+        }
+    };
+
+    let pending = PendingVerification {
+        guild_id: guild.0 as i64,
+        user_id: user.id.0 as i64,
+        method: method.as_str().to_owned(),
+        channel_id: channel.0 as i64,
+        message_id: message,
+        answer,
+        joined_at: Utc::now(),
+    };
+    let kick_at = settings
+        .verification_timeout()
+        .and_then(scheduledjobs::due_in);
+    let pgconn = crate::database::connection(&ctx.data)?;
+    pgconn.transaction::<_, diesel::result::Error, _>(|| {
+        onboarding::add_pending(&pgconn, &pending)?;
+        if let Some(at) = kick_at {
+            scheduledjobs::schedule(
+                &pgconn,
+                JobKind::KickUnverified,
+                guild.0,
+                user.id.0,
+                None,
+                at,
+            )?;
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Lets a member who verified themselves in, giving them the roles of the
+/// server. Returns whether the member still had to verify themselves.
+pub fn verify(ctx: &Context, guild: GuildId, user: UserId) -> Result<bool> {
+    let pending = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        pgconn.transaction::<_, diesel::result::Error, _>(|| {
+            scheduledjobs::cancel(&pgconn, JobKind::KickUnverified, guild.0, user.0)?;
+            onboarding::remove_pending(&pgconn, guild.0, user.0)
+        })?
+    };
+    let pending = match pending {
+        Some(s) => s,
+        None => return Ok(false),
+    };
+    remove_prompt(&ctx.http, &pending);
+
+    let settings = Onboarding::new(guild.0, &ctx.data)?.read().clone();
+    grant(ctx, guild, user, &settings, pending.joined_at)?;
+    Ok(true)
+}
+
+/// Whether the code given answers a captcha, which isn't case-sensitive and
+/// ignores whitespace.
+pub fn answers(answer: &str, given: &str) -> bool {
+    let given = given
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    given.eq_ignore_ascii_case(answer)
+}
+
+/// Gives a member the roles of the server once the delay since they joined has
+/// passed, leaving it to the scheduler if it hasn't yet.
+fn grant(
+    ctx: &Context,
+    guild: GuildId,
+    user: UserId,
+    settings: &Onboarding,
+    joined_at: DateTime<Utc>,
+) -> Result<()> {
+    if settings.auto_roles.is_empty() {
+        return Ok(());
+    }
+    let due = settings
+        .role_delay()
+        .and_then(|d| ChronoDuration::from_std(d).ok())
+        .and_then(|d| joined_at.checked_add_signed(d));
+    match due {
+        Some(at) if at > Utc::now() => {
+            let pgconn = crate::database::connection(&ctx.data)?;
+            scheduledjobs::schedule(&pgconn, JobKind::AddRoles, guild.0, user.0, None, at)?;
+        }
+        _ => add_roles(&ctx.http, &ctx.data, guild, user)?,
+    }
+    Ok(())
+}
+
+/// Gives a member the roles the server currently gives joining members.
+pub fn add_roles(
+    http: &Arc<Http>,
+    data: &Arc<RwLock<ShareMap>>,
+    guild: GuildId,
+    user: UserId,
+) -> Result<()> {
+    let roles = Onboarding::new(guild.0, data)?.read().auto_roles.clone();
+    for role in roles {
+        http.add_member_role(guild.0, user.0, role as u64)?;
+    }
+    Ok(())
+}
+
+/// Kicks a member who didn't verify themselves in time, returning whether they
+/// were, which they aren't if they did verify themselves after all.
+pub fn kick_unverified(
+    http: &Arc<Http>,
+    conn: &PgConnection,
+    guild: GuildId,
+    user: UserId,
+) -> Result<bool> {
+    // Only forgetting the verification once the member is kicked lets the kick
+    // be retried
+    let pending = match onboarding::pending(conn, guild.0, user.0)? {
+        Some(s) => s,
+        None => return Ok(false),
+    };
+    guild.kick(http, user)?;
+    onboarding::remove_pending(conn, guild.0, user.0)?;
+    remove_prompt(http, &pending);
+    Ok(true)
+}
+
+/// Deletes the message a member was asked to verify themselves with, if it is
+/// still there.
+fn remove_prompt(http: &Arc<Http>, pending: &PendingVerification) {
+    if let Some(message) = pending.message_id {
+        let channel = ChannelId(pending.channel_id as u64);
+        if let Err(e) = channel.delete_message(http, MessageId(message as u64)) {
+            debug!(
+                "Couldn't delete the verification of {} in {}: {:?}",
+                pending.user_id, pending.guild_id, e
+            );
+        }
+    }
+}
+
+fn captcha() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CAPTCHA_LENGTH)
+        .map(|_| CAPTCHA_ALPHABET[rng.gen_range(0, CAPTCHA_ALPHABET.len())] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_ignore_case_and_whitespace() {
+        assert!(answers("K7QX2M", "K7QX2M"));
+        assert!(answers("K7QX2M", "k7qx2m"));
+        assert!(answers("K7QX2M", " k7q x2m\n"));
+    }
+
+    #[test]
+    fn wrong_answers_are_rejected() {
+        assert!(!answers("K7QX2M", "K7QX2N"));
+        assert!(!answers("K7QX2M", "K7QX2"));
+        assert!(!answers("K7QX2M", "K7QX2MM"));
+        assert!(!answers("K7QX2M", ""));
+    }
+
+    #[test]
+    fn captchas_answer_themselves() {
+        let code = captcha();
+        assert_eq!(code.chars().count(), CAPTCHA_LENGTH);
+        assert!(answers(&code, &code.to_lowercase()));
+    }
+}
@@ -0,0 +1,273 @@
+//! Running scheduled jobs, such as lifting temporary bans, once they are due.
+//!
+//! Jobs are stored in the database so they survive restarts, and every process
+//! only runs the jobs of servers on its own shards. Jobs are claimed for a lease
+//! before they run, so processes don't run the same job twice, and each one is
+//! finished in a transaction of its own once its work is done.
+
+use crate::{
+    config::SchedulerConfiguration,
+    data::{
+        audit::Actor,
+        moderation::{self, Case, CaseAction},
+        scheduledjobs::{self, Job, JobKind},
+        ServerSettings, ShardManagerContainer, UserSettings,
+    },
+    modlog, onboarding,
+    prelude::*,
+};
+use chrono::{Duration as ChronoDuration, Utc};
+use diesel::prelude::*;
+use parking_lot::RwLock;
+use serenity::{
+    cache::CacheRwLock,
+    http::Http,
+    model::id::{GuildId, UserId},
+};
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use typemap::ShareMap;
+
+/// Spawns the thread which periodically runs the due jobs of this process.
+pub fn spawn(
+    cache: CacheRwLock,
+    http: Arc<Http>,
+    data: Arc<RwLock<ShareMap>>,
+    config: SchedulerConfiguration,
+) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name(String::from("scheduler"))
+        .spawn(move || loop {
+            thread::sleep(Duration::from_secs(*config.poll_interval_seconds()));
+            match run(&cache, &http, &data, &config) {
+                Ok(0) => {}
+                Ok(n) => debug!("The scheduler ran {} jobs.", n),
+                Err(e) => error!("The scheduler couldn't run the due jobs: {:?}", e),
+            }
+        })
+        .expect("couldn't spawn the scheduler thread")
+}
+
+/// Runs the due jobs of servers on the shards of this process, returning how
+/// many were attempted.
+pub fn run(
+    cache: &CacheRwLock,
+    http: &Arc<Http>,
+    data: &Arc<RwLock<ShareMap>>,
+    config: &SchedulerConfiguration,
+) -> Result<usize> {
+    let shard_count = {
+        let cache = cache.read();
+        // Until a shard is ready the total amount of shards isn't known, so
+        // jobs can't be told apart from those of other processes.
+        if cache.user.id.0 == 0 {
+            return Ok(0);
+        }
+        cache.shard_count
+    };
+    let shards = {
+        let data = data.read();
+        let manager = data.get::<ShardManagerContainer>().failure()?;
+        let shards = manager.lock().shards_instantiated();
+        shards.into_iter().map(|s| s.0).collect::<Vec<_>>()
+    };
+
+    let pgconn = crate::database::connection(data)?;
+    let leased_until = Utc::now() + ChronoDuration::seconds(*config.lease_seconds() as i64);
+    let jobs = scheduledjobs::claim_due(
+        &pgconn,
+        &shards,
+        shard_count,
+        *config.batch_size(),
+        leased_until,
+    )?;
+    for job in &jobs {
+        match execute(http, data, &pgconn, job).and_then(|case| finish(&pgconn, job, case)) {
+            // The case has to be committed before its post can be recorded.
+            Ok(Some(case)) => modlog::post(http, data, &case),
+            Ok(None) => {}
+            // Rescheduling is done outside of the transaction finishing the
+            // job, so the database erring there can't keep it from happening
+            Err(e) => {
+                if let Err(fail_error) = fail(&pgconn, config, job, &e) {
+                    error!("Couldn't reschedule job {}: {:?}", job.id, fail_error);
+                }
+            }
+        }
+    }
+
+    Ok(jobs.len())
+}
+
+/// Removes a job which was done, opening the case for it in the same
+/// transaction so neither happens twice.
+fn finish(
+    conn: &PgConnection,
+    job: &Job,
+    case: Option<(CaseAction, String)>,
+) -> Result<Option<Case>> {
+    let case = conn.transaction::<_, diesel::result::Error, _>(|| {
+        let case = match case {
+            Some((action, reason)) => Some(moderation::open(
+                conn,
+                job.guild_id as u64,
+                action,
+                job.target_id as u64,
+                None,
+                Some(&reason),
+                None,
+            )?),
+            None => None,
+        };
+        scheduledjobs::remove(conn, job.id)?;
+        Ok(case)
+    })?;
+    Ok(case)
+}
+
+/// Does the work of a job outside of any transaction, returning the action and
+/// reason of the case to open for it if any.
+fn execute(
+    http: &Arc<Http>,
+    data: &Arc<RwLock<ShareMap>>,
+    conn: &PgConnection,
+    job: &Job,
+) -> Result<Option<(CaseAction, String)>> {
+    let guild = GuildId(job.guild_id as u64);
+    let target = job.target_id as u64;
+    let kind = job.kind.parse::<JobKind>().map_err(|()| {
+        StdErrorKind::StringValue(format!("There is no job kind named `{}`.", job.kind))
+    })?;
+
+    match kind {
+        JobKind::Unban => {
+            guild.unban(http, UserId(target))?;
+            Ok(Some((CaseAction::Unban, expiry_reason(job, "ban"))))
+        }
+        JobKind::Unmute => {
+            let role = *ServerSettings::new(guild.0, data)?.read().mute_role();
+            let role = match role {
+                Some(s) => s,
+                None => return Err(ModerationErrorKind::NoMuteRole.into()),
+            };
+            http.remove_member_role(guild.0, target, role)?;
+            Ok(Some((CaseAction::Unmute, expiry_reason(job, "mute"))))
+        }
+        JobKind::UnblacklistUser => {
+            let settings = UserSettings::new(target, data)?;
+            let mut write = settings.write();
+            write.set_blacklisted(false, Actor::scheduler());
+            write.save()?;
+            Ok(None)
+        }
+        JobKind::UnblacklistServer => {
+            let settings = ServerSettings::new(target, data)?;
+            let mut write = settings.write();
+            write.set_blacklisted(false, Actor::scheduler());
+            write.save()?;
+            Ok(None)
+        }
+        JobKind::AddRoles => {
+            onboarding::add_roles(http, data, guild, UserId(target))?;
+            Ok(None)
+        }
+        JobKind::KickUnverified => {
+            if !onboarding::kick_unverified(http, conn, guild, UserId(target))? {
+                return Ok(None);
+            }
+            let reason = "Verification: didn't verify themselves in time.";
+            Ok(Some((CaseAction::Kick, reason.to_owned())))
+        }
+    }
+}
+
+/// The reason of the case opened when a temporary action runs out.
+fn expiry_reason(job: &Job, action: &str) -> String {
+    match job.case_number {
+        Some(number) => format!("The temporary {} of case #{} ran out.", action, number),
+        None => format!("A temporary {} ran out.", action),
+    }
+}
+
+/// Reschedules a failed job with exponential backoff, or gives up on it once it
+/// failed too often.
+fn fail(
+    conn: &PgConnection,
+    config: &SchedulerConfiguration,
+    job: &Job,
+    error: &failure::Error,
+) -> QueryResult<()> {
+    let attempts = job.attempts + 1;
+    let backoff = match backoff(config, attempts) {
+        Some(s) => s,
+        None => {
+            error!(
+                "Giving up on job {} ({} of {} in {}) after {} attempts: {}",
+                job.id, job.kind, job.target_id, job.guild_id, attempts, error
+            );
+            return scheduledjobs::remove(conn, job.id);
+        }
+    };
+
+    warn!(
+        "Job {} ({} of {} in {}) failed, retrying in {} seconds: {}",
+        job.id, job.kind, job.target_id, job.guild_id, backoff, error
+    );
+    scheduledjobs::retry_at(
+        conn,
+        job.id,
+        Utc::now() + ChronoDuration::seconds(backoff as i64),
+        &error.to_string(),
+    )
+}
+
+/// How many seconds a job which failed the given amount of times waits before it
+/// is retried, or `None` if it is given up on.
+fn backoff(config: &SchedulerConfiguration, attempts: i32) -> Option<u64> {
+    if attempts >= *config.max_attempts() {
+        return None;
+    }
+    Some(
+        config
+            .retry_backoff_seconds()
+            .saturating_mul(1 << (attempts - 1).max(0).min(20))
+            .min(*config.max_backoff_seconds()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SchedulerConfiguration {
+        toml::from_str("retry_backoff_seconds = 30\nmax_backoff_seconds = 300\nmax_attempts = 6")
+            .unwrap()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let config = config();
+        let delays = (1..6).map(|a| backoff(&config, a)).collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [Some(30), Some(60), Some(120), Some(240), Some(300)]
+        );
+    }
+
+    #[test]
+    fn gives_up_after_the_last_attempt() {
+        let config = config();
+        assert_eq!(backoff(&config, 6), None);
+        assert_eq!(backoff(&config, 100), None);
+    }
+
+    #[test]
+    fn backoff_doesnt_overflow() {
+        let config: SchedulerConfiguration =
+            toml::from_str("retry_backoff_seconds = 1000000\nmax_attempts = 100").unwrap();
+        assert_eq!(backoff(&config, 99), Some(*config.max_backoff_seconds()));
+    }
+}
@@ -15,5 +15,252 @@ table! {
         id -> BigInt,
         /// Whether the server is blacklisted from using the bot entirely.
         blacklisted -> Bool,
+        /// When the bot was removed from the server, if it currently isn't in it.
+        departed_at -> Nullable<Timestamptz>,
+        /// Whether the event hooks of the server are run.
+        hooks_enabled -> Bool,
+        /// The ID of the channel errors of event hooks are reported to, if any.
+        hook_error_channel -> Nullable<BigInt>,
+        /// The ID of the role given to muted members, if any.
+        mute_role -> Nullable<BigInt>,
+        /// The ID of the channel moderation cases are posted to, if any.
+        modlog_channel -> Nullable<BigInt>,
+        /// The ID of the channel edited and deleted messages are logged to, if
+        /// any.
+        message_log_channel -> Nullable<BigInt>,
+        /// The IDs of the channels whose messages aren't logged.
+        message_log_ignored -> Array<BigInt>,
+    }
+}
+
+table! {
+    /// An append-only log of every change made to settings.
+    settings_audit (id) {
+        /// The serial ID of the entry.
+        id -> BigInt,
+        /// The kind of settings changed, e.g. `user` or `server`.
+        target_kind -> Text,
+        /// The ID of the user or server whose settings were changed.
+        target_id -> BigInt,
+        /// The ID of the user who made the change, if any.
+        actor_id -> Nullable<BigInt>,
+        /// Through what the change was made, e.g. `command` or `event`.
+        source -> Text,
+        /// The name of the setting changed.
+        field -> Text,
+        /// The value before the change.
+        old_value -> Text,
+        /// The value after the change.
+        new_value -> Text,
+        /// When the change was made, which may be before it was saved.
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    /// The commands defined by the admins of servers, scripted in ketos.
+    custom_commands (guild_id, name) {
+        /// The ID of the server the command belongs to.
+        guild_id -> BigInt,
+        /// The name the command is invoked with, in lowercase.
+        name -> Text,
+        /// The ketos script run when the command is invoked.
+        source -> Text,
+        /// The ID of the user who last defined the command, if known.
+        author_id -> Nullable<BigInt>,
+        /// When the command was created.
+        created_at -> Timestamptz,
+        /// When the source of the command was last changed.
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    /// The ketos scripts servers run when events happen in them.
+    event_hooks (guild_id, event) {
+        /// The ID of the server the hook belongs to, or 0 for hooks the owners
+        /// of the bot run in every server.
+        guild_id -> BigInt,
+        /// The name of the event the hook runs on, e.g. `member-join`.
+        event -> Text,
+        /// The ketos script run when the event happens.
+        source -> Text,
+        /// The ID of the user who last registered the hook, if known.
+        author_id -> Nullable<BigInt>,
+        /// When the hook was last registered.
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    /// The moderation actions taken in servers, numbered per server.
+    moderation_cases (guild_id, case_number) {
+        /// The ID of the server the action was taken in.
+        guild_id -> BigInt,
+        /// The number of the case within its server, starting at 1.
+        case_number -> Integer,
+        /// What was done, e.g. `ban` or `warn`.
+        action -> Text,
+        /// The ID of the user the action was taken against.
+        target_id -> BigInt,
+        /// The ID of the moderator who took the action, if it wasn't automatic.
+        moderator_id -> Nullable<BigInt>,
+        /// Why the action was taken, if a reason was given.
+        reason -> Nullable<Text>,
+        /// How long the action lasts for, in seconds, if it is temporary.
+        duration_seconds -> Nullable<BigInt>,
+        /// When the action was taken.
+        created_at -> Timestamptz,
+        /// When the case was last changed.
+        updated_at -> Timestamptz,
+        /// The ID of the message the case was posted as in the modlog channel
+        /// of the server, if it was.
+        modlog_message_id -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    /// Work to be done at a later time, such as lifting temporary bans.
+    scheduled_jobs (id) {
+        /// The serial ID of the job.
+        id -> BigInt,
+        /// What to do, e.g. `unban` or `unblacklist-user`.
+        kind -> Text,
+        /// The ID of the server the job is run for, which decides the shard
+        /// running it, or 0 for jobs which don't belong to a server.
+        guild_id -> BigInt,
+        /// The ID of the user or server the job is done to.
+        target_id -> BigInt,
+        /// The number of the moderation case which caused the job, if any.
+        case_number -> Nullable<Integer>,
+        /// When the job is due next.
+        run_at -> Timestamptz,
+        /// How many times running the job has failed.
+        attempts -> Integer,
+        /// Why running the job last failed, if it did.
+        last_error -> Nullable<Text>,
+        /// When the job was scheduled.
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    /// The rules servers automatically moderate messages by.
+    automod_rules (guild_id, kind) {
+        /// The ID of the server the rule belongs to.
+        guild_id -> BigInt,
+        /// What the rule looks for, e.g. `invites` or `caps`.
+        kind -> Text,
+        /// What is done to messages breaking the rule, e.g. `delete` or `kick`.
+        action -> Text,
+        /// The banned words and `/regular expressions/` of `words` rules.
+        patterns -> Array<Text>,
+        /// The limit of the rule, e.g. the amount of mentions, if it has one
+        /// other than the default.
+        threshold -> Nullable<Integer>,
+        /// How long members are timed out for, in seconds, if not for the
+        /// default duration.
+        timeout_seconds -> Nullable<BigInt>,
+        /// The IDs of the roles whose members the rule doesn't apply to.
+        exempt_roles -> Array<BigInt>,
+        /// The IDs of the channels the rule doesn't apply in.
+        exempt_channels -> Array<BigInt>,
+        /// The ID of the user who last changed the rule, if known.
+        author_id -> Nullable<BigInt>,
+        /// When the rule was last changed.
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    /// How servers are protected from raids, and the raids they are in.
+    raid_protection (guild_id) {
+        /// The ID of the server the protection belongs to.
+        guild_id -> BigInt,
+        /// Whether joins are watched for raids.
+        enabled -> Bool,
+        /// How many members joining within the window count as a raid.
+        join_limit -> Integer,
+        /// How long joins count towards a raid, in seconds.
+        join_window_seconds -> Integer,
+        /// How many new accounts, or accounts with the same avatar or name,
+        /// joining within the window count as a raid.
+        similar_limit -> Integer,
+        /// How old accounts have to be not to count as new, in seconds, if new
+        /// accounts are watched for.
+        min_account_age_seconds -> Nullable<BigInt>,
+        /// What is done once a raid is detected, e.g. `lockdown` or `kick`.
+        response -> Text,
+        /// When the current raid was detected, if the server is in one.
+        raid_started_at -> Nullable<Timestamptz>,
+        /// The IDs of the channels locked for the current raid.
+        locked_channels -> Array<BigInt>,
+        /// The verification level of the server before the current raid raised
+        /// it, if it did.
+        previous_verification_level -> Nullable<SmallInt>,
+    }
+}
+
+table! {
+    /// The messages servers greet joining members and bid leaving members
+    /// farewell with.
+    greetings (guild_id, kind) {
+        /// The ID of the server the greeting belongs to.
+        guild_id -> BigInt,
+        /// When the greeting is sent, i.e. `welcome` or `farewell`.
+        kind -> Text,
+        /// The ID of the channel the greeting is sent to, or `NULL` to send it to
+        /// the member directly.
+        channel_id -> Nullable<BigInt>,
+        /// The template of the greeting, with placeholders such as `{user}`.
+        template -> Text,
+        /// Whether the greeting is sent as an embed.
+        embed -> Bool,
+        /// The ID of the user who last changed the greeting, if known.
+        author_id -> Nullable<BigInt>,
+        /// When the greeting was last changed.
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    /// The roles servers give joining members, and how members have to verify
+    /// themselves before getting them.
+    onboarding (guild_id) {
+        /// The ID of the server the settings belong to.
+        guild_id -> BigInt,
+        /// The IDs of the roles given to joining members.
+        auto_roles -> Array<BigInt>,
+        /// How long after joining members get the roles, in seconds, if not right
+        /// away.
+        role_delay_seconds -> Nullable<BigInt>,
+        /// How members verify themselves before getting the roles, e.g.
+        /// `reaction` or `captcha`, if they have to.
+        verification -> Nullable<Text>,
+        /// The ID of the channel members are asked to verify themselves in.
+        verification_channel_id -> Nullable<BigInt>,
+        /// How long members have to verify themselves before they are kicked, in
+        /// seconds, if they are kicked at all.
+        verification_timeout_seconds -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    /// The members who haven't verified themselves yet.
+    pending_verifications (guild_id, user_id) {
+        /// The ID of the server the member joined.
+        guild_id -> BigInt,
+        /// The ID of the member.
+        user_id -> BigInt,
+        /// How the member has to verify themselves, e.g. `reaction`.
+        method -> Text,
+        /// The ID of the channel the member was asked in.
+        channel_id -> BigInt,
+        /// The ID of the message the member was asked with, if it was sent.
+        message_id -> Nullable<BigInt>,
+        /// The code the member has to answer with, for captchas.
+        answer -> Nullable<Text>,
+        /// When the member joined.
+        joined_at -> Timestamptz,
     }
 }
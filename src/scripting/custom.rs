@@ -0,0 +1,312 @@
+//! Running the custom commands servers define, in a sandbox which only lets them
+//! reply to whoever invoked them.
+
+use super::EvaluationOutcome;
+use crate::{
+    config::CustomCommandConfiguration,
+    consts::{MESSAGE_LENGTH_LIMIT, PREFIX},
+    data::{customcommands, ConfigurationContainer, ServerSettings, UserSettings},
+    prelude::*,
+};
+use ketos::{Interpreter, RestrictConfig};
+use serenity::{model::prelude::*, prelude::*};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+use typemap::{Key as TypeMapKey, ShareMap};
+
+/// How many scripts of servers are running, in total and by server.
+#[derive(Default)]
+pub struct RunningScripts {
+    total: usize,
+    by_guild: HashMap<u64, usize>,
+}
+
+pub struct RunningScriptsContainer;
+
+impl TypeMapKey for RunningScriptsContainer {
+    type Value = Arc<Mutex<RunningScripts>>;
+}
+
+/// A script of a server which is counted as running until this is dropped.
+pub struct ScriptSlot {
+    running: Arc<Mutex<RunningScripts>>,
+    guild: u64,
+}
+
+impl Drop for ScriptSlot {
+    fn drop(&mut self) {
+        let mut running = self.running.lock();
+        running.total -= 1;
+        if let Entry::Occupied(mut e) = running.by_guild.entry(self.guild) {
+            *e.get_mut() -= 1;
+            if *e.get() == 0 {
+                e.remove();
+            }
+        }
+    }
+}
+
+/// Counts a script of the server as running, or returns `None` if as many as
+/// the configuration allows already are, in which case it mustn't be run.
+///
+/// Every script runs on a thread of its own, so this keeps servers from having
+/// the bot start any amount of threads by spamming commands or events.
+pub fn claim_slot(
+    data: &Arc<RwLock<ShareMap>>,
+    guild: u64,
+    config: &CustomCommandConfiguration,
+) -> Result<Option<ScriptSlot>> {
+    let running = Arc::clone(data.read().get::<RunningScriptsContainer>().failure()?);
+    {
+        let mut scripts = running.lock();
+        let in_guild = scripts.by_guild.get(&guild).cloned().unwrap_or(0);
+        if scripts.total >= *config.max_running() || in_guild >= *config.max_running_per_guild() {
+            return Ok(None);
+        }
+        scripts.total += 1;
+        *scripts.by_guild.entry(guild).or_insert(0) += 1;
+    }
+    Ok(Some(ScriptSlot { running, guild }))
+}
+
+/// The restrictions put on custom commands.
+pub fn restrictions(config: &CustomCommandConfiguration) -> RestrictConfig {
+    RestrictConfig {
+        execution_time: Some(Duration::from_millis(*config.time_limit_ms())),
+        memory_limit: *config.memory_limit(),
+        ..RestrictConfig::strict()
+    }
+}
+
+/// What a custom command was invoked with, bound to names in its script.
+struct Invocation {
+    args: Vec<String>,
+    author_id: u64,
+    author_name: String,
+    channel_id: u64,
+    guild_id: u64,
+}
+
+impl Invocation {
+    fn new(msg: &Message, guild: GuildId) -> Self {
+        Invocation {
+            args: arguments(&msg.content),
+            author_id: msg.author.id.0,
+            author_name: msg.author.name.clone(),
+            channel_id: msg.channel_id.0,
+            guild_id: guild.0,
+        }
+    }
+
+    fn bind(self, interpreter: &Interpreter) {
+        let scope = interpreter.scope();
+        scope.add_named_value("args", self.args.into());
+        scope.add_named_value("author-id", self.author_id.into());
+        scope.add_named_value("author-name", self.author_name.into());
+        scope.add_named_value("channel-id", self.channel_id.into());
+        scope.add_named_value("guild-id", self.guild_id.into());
+    }
+}
+
+/// The words a custom command was invoked with after its name.
+fn arguments(content: &str) -> Vec<String> {
+    content
+        .trim_start_matches(PREFIX)
+        .split_whitespace()
+        .skip(1)
+        .map(String::from)
+        .collect()
+}
+
+/// Runs the custom command of the server with the given name, if there is one,
+/// and replies with what it printed and returned.
+///
+/// This is called for every command the framework doesn't recognise, which
+/// skips the `before` hook, so blacklists are checked here.
+pub fn dispatch(ctx: &mut Context, msg: &Message, name: &str) {
+    if let Err(e) = try_dispatch(ctx, msg, name) {
+        error!("Couldn't run the custom command `{}`: {:?}", name, e);
+    }
+}
+
+fn try_dispatch(ctx: &mut Context, msg: &Message, name: &str) -> Result<()> {
+    let guild = match msg.guild_id {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+    if msg.author.bot {
+        return Ok(());
+    }
+
+    let name = name.to_lowercase();
+    let command = {
+        let pgconn = crate::database::connection(&ctx.data)?;
+        customcommands::find(&pgconn, guild.0, &name)?
+    };
+    let command = match command {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+    let config = ctx
+        .data
+        .read()
+        .get::<ConfigurationContainer>()
+        .failure()?
+        .custom_commands()
+        .clone();
+
+    if *UserSettings::new(msg.author.id.0, &ctx.data)?
+        .read()
+        .blacklisted()
+        || *ServerSettings::new(guild.0, &ctx.data)?
+            .read()
+            .blacklisted()
+    {
+        return Ok(());
+    }
+
+    let slot = match claim_slot(&ctx.data, guild.0, &config)? {
+        Some(s) => s,
+        None => {
+            debug!(
+                "Dropping the custom command `{}` of {}, too many are running.",
+                name, guild.0
+            );
+            return Ok(());
+        }
+    };
+    let invocation = Invocation::new(msg, guild);
+    let http = Arc::clone(&ctx.http);
+    let channel = msg.channel_id;
+    super::spawn_evaluation(
+        command.source,
+        restrictions(&config),
+        *config.output_limit_bytes(),
+        move |interpreter| invocation.bind(interpreter),
+        move |outcome| {
+            drop(slot);
+            let reply = match reply(outcome) {
+                Ok(Some(s)) => s,
+                Ok(None) => return,
+                Err(e) => sanitize(&format!("The command `{}` failed: {}", name, e)),
+            };
+            if let Err(e) = channel.say(&http, &reply) {
+                error!("Couldn't reply for the custom command `{}`: {:?}", name, e);
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
+/// What a sandboxed script printed, followed by the value it returned unless
+/// that is `()`, made safe to send. `None` if there is nothing to send.
+///
+/// The error is returned as is if the script failed.
+pub(super) fn reply(outcome: EvaluationOutcome) -> std::result::Result<Option<String>, String> {
+    let mut reply = outcome.output;
+    match outcome.result {
+        Ok(ref value) if value == "()" => {}
+        Ok(value) => {
+            if !reply.is_empty() && !reply.ends_with('\n') {
+                reply.push('\n');
+            }
+            reply.push_str(&value);
+        }
+        Err(e) => return Err(e),
+    }
+    if reply.trim().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(sanitize(&reply)))
+}
+
+/// Keeps text which may come from anyone from pinging everyone, members or
+/// roles, and cuts it to the length of a message.
+pub(super) fn sanitize(content: &str) -> String {
+    content
+        .replace("@everyone", "@\u{200b}everyone")
+        .replace("@here", "@\u{200b}here")
+        // Covers members by `<@!…>` and roles by `<@&…>` too
+        .replace("<@", "<@\u{200b}")
+        .chars()
+        .take(MESSAGE_LENGTH_LIMIT)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(result: std::result::Result<&str, &str>, output: &str) -> EvaluationOutcome {
+        EvaluationOutcome {
+            result: result.map(String::from).map_err(String::from),
+            output: output.to_owned(),
+            output_truncated: false,
+            duration: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn takes_the_words_after_the_name_as_arguments() {
+        assert_eq!(arguments(&format!("{}roll 2  d6 ", PREFIX)), ["2", "d6"]);
+        assert!(arguments(&format!("{}roll", PREFIX)).is_empty());
+    }
+
+    #[test]
+    fn neutralises_mentions() {
+        assert_eq!(
+            sanitize("@everyone @here"),
+            "@\u{200b}everyone @\u{200b}here"
+        );
+        assert_eq!(
+            sanitize("<@1> <@!2> <@&3>"),
+            "<@\u{200b}1> <@\u{200b}!2> <@\u{200b}&3>"
+        );
+        assert_eq!(sanitize("mail@example.com"), "mail@example.com");
+    }
+
+    #[test]
+    fn cuts_to_the_length_of_a_message() {
+        let long = "é".repeat(MESSAGE_LENGTH_LIMIT + 10);
+        assert_eq!(sanitize(&long).chars().count(), MESSAGE_LENGTH_LIMIT);
+        // Neutralising mentions makes text longer
+        let pings = "@here".repeat(MESSAGE_LENGTH_LIMIT / 5);
+        assert_eq!(sanitize(&pings).chars().count(), MESSAGE_LENGTH_LIMIT);
+    }
+
+    #[test]
+    fn replies_with_the_output_and_value() {
+        assert_eq!(
+            reply(outcome(Ok("3"), "hi")),
+            Ok(Some(String::from("hi\n3")))
+        );
+        assert_eq!(
+            reply(outcome(Ok("()"), "hi\n")),
+            Ok(Some(String::from("hi\n")))
+        );
+        assert_eq!(reply(outcome(Ok("()"), " \n")), Ok(None));
+        assert_eq!(reply(outcome(Err("oops"), "hi")), Err(String::from("oops")));
+    }
+
+    #[test]
+    fn caps_running_scripts() {
+        let config: CustomCommandConfiguration =
+            toml::from_str("max_running = 2\nmax_running_per_guild = 1").unwrap();
+        let mut sharemap = ShareMap::custom();
+        sharemap.insert::<RunningScriptsContainer>(Default::default());
+        let data = Arc::new(RwLock::new(sharemap));
+        let claim = |guild| claim_slot(&data, guild, &config).unwrap();
+
+        let first = claim(1).unwrap();
+        assert!(claim(1).is_none());
+        let _second = claim(2).unwrap();
+        assert!(claim(3).is_none());
+        // Finished scripts make room again
+        drop(first);
+        assert!(claim(1).is_some());
+    }
+}
@@ -0,0 +1,151 @@
+//! Running the event hooks of servers and the global ones of the owners, in the
+//! same sandbox and with the same limits as custom commands.
+
+use super::custom;
+use crate::{
+    data::{
+        eventhooks::{self, GuildHooks, HookEvent},
+        ConfigurationContainer, ServerSettings,
+    },
+    prelude::*,
+};
+use ketos::{Interpreter, Value};
+use serenity::{model::prelude::*, prelude::*};
+use std::sync::Arc;
+
+/// A value bound to a name in the script of a hook.
+#[derive(Clone, Debug)]
+pub enum Binding {
+    Integer(u64),
+    Text(String),
+}
+
+impl From<Binding> for Value {
+    fn from(binding: Binding) -> Self {
+        match binding {
+            Binding::Integer(i) => i.into(),
+            Binding::Text(s) => s.into(),
+        }
+    }
+}
+
+/// Runs the hooks registered for the event in the server, the global one first.
+///
+/// Whatever the hooks print and return is sent to `channel`, and errors of the
+/// hook of the server are reported to its hook error channel if it has one.
+pub fn fire(
+    ctx: &Context,
+    guild: GuildId,
+    event: HookEvent,
+    channel: Option<ChannelId>,
+    bindings: Vec<(&'static str, Binding)>,
+) {
+    if let Err(e) = try_fire(ctx, guild, event, channel, bindings) {
+        error!("Couldn't run the {} hooks of {}: {:?}", event, guild.0, e);
+    }
+}
+
+fn try_fire(
+    ctx: &Context,
+    guild: GuildId,
+    event: HookEvent,
+    channel: Option<ChannelId>,
+    bindings: Vec<(&'static str, Binding)>,
+) -> Result<()> {
+    let (run_guild, error_channel) = {
+        let settings = ServerSettings::new(guild.0, &ctx.data)?;
+        let settings = settings.read();
+        if *settings.blacklisted() {
+            return Ok(());
+        }
+        (*settings.hooks_enabled(), *settings.hook_error_channel())
+    };
+
+    let mut sources = Vec::with_capacity(2);
+    let global = GuildHooks::new(eventhooks::GLOBAL, &ctx.data)?;
+    if let Some(source) = global.read().sources.get(&event) {
+        sources.push((Arc::clone(source), None));
+    }
+    if run_guild {
+        let hooks = GuildHooks::new(guild.0, &ctx.data)?;
+        if let Some(source) = hooks.read().sources.get(&event) {
+            sources.push((Arc::clone(source), error_channel.map(ChannelId)));
+        }
+    }
+    if sources.is_empty() {
+        return Ok(());
+    }
+
+    let config = ctx
+        .data
+        .read()
+        .get::<ConfigurationContainer>()
+        .failure()?
+        .custom_commands()
+        .clone();
+
+    for (source, error_channel) in sources {
+        // Global hooks count towards the server they run in
+        let slot = match custom::claim_slot(&ctx.data, guild.0, &config)? {
+            Some(s) => s,
+            None => {
+                debug!(
+                    "Dropping the {} hooks of {}, too many are running.",
+                    event, guild.0
+                );
+                return Ok(());
+            }
+        };
+        let http = Arc::clone(&ctx.http);
+        let bindings = bindings.clone();
+        super::spawn_evaluation(
+            source.to_string(),
+            custom::restrictions(&config),
+            *config.output_limit_bytes(),
+            move |interpreter| bind(interpreter, event, guild, bindings),
+            move |outcome| {
+                drop(slot);
+                let result = match custom::reply(outcome) {
+                    Ok(Some(reply)) => match channel {
+                        Some(channel) => channel.say(&http, &reply).map(|_| ()),
+                        None => Ok(()),
+                    },
+                    Ok(None) => Ok(()),
+                    Err(e) => match error_channel {
+                        Some(error_channel) => {
+                            let report =
+                                custom::sanitize(&format!("The {} hook failed: {}", event, e));
+                            error_channel.say(&http, &report).map(|_| ())
+                        }
+                        None => {
+                            warn!("The {} hook of {} failed: {}", event, guild.0, e);
+                            Ok(())
+                        }
+                    },
+                };
+                if let Err(e) = result {
+                    error!(
+                        "Couldn't send for the {} hook of {}: {:?}",
+                        event, guild.0, e
+                    );
+                }
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+fn bind(
+    interpreter: &Interpreter,
+    event: HookEvent,
+    guild: GuildId,
+    bindings: Vec<(&'static str, Binding)>,
+) {
+    let scope = interpreter.scope();
+    scope.add_named_value("event", event.as_str().into());
+    scope.add_named_value("guild-id", guild.0.into());
+    for (name, binding) in bindings {
+        scope.add_named_value(name, binding.into());
+    }
+}
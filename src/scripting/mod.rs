@@ -0,0 +1,24 @@
+pub mod custom;
+pub mod hooks;
+pub mod owner;
+mod session;
+pub mod stdlib;
+mod worker;
+
+pub use self::session::{ReplChannelsContainer, Session, SessionsContainer};
+pub use self::worker::{
+    spawn_evaluation, EvaluationOutcome, EvaluationsContainer, RunningEvaluation,
+};
+
+use crate::config::EvaluationConfiguration;
+use ketos::RestrictConfig;
+use std::time::Duration;
+
+/// The restrictions put on owner evaluations.
+pub fn owner_restrictions(config: &EvaluationConfiguration) -> RestrictConfig {
+    RestrictConfig {
+        execution_time: Some(Duration::from_millis(*config.time_limit_ms())),
+        memory_limit: *config.memory_limit(),
+        ..RestrictConfig::permissive()
+    }
+}
@@ -0,0 +1,277 @@
+//! Evaluation of code by the owners of the bot, in their own sessions.
+
+use super::{
+    stdlib::Environment, EvaluationOutcome, EvaluationsContainer, RunningEvaluation, Session,
+    SessionsContainer,
+};
+use crate::{consts::MESSAGE_LENGTH_LIMIT, data::ConfigurationContainer, prelude::*};
+use serenity::{http::Http, model::prelude::*, prelude::*};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// The language tags code blocks of ketos may be marked with.
+const LANGUAGE_TAGS: &[&str] = &["lisp", "ketos", "ket", "scheme", "clojure"];
+
+/// The extensions of files which may be evaluated.
+const FILE_EXTENSIONS: &[&str] = &[".lisp", ".ket"];
+
+/// A triple-backtick code block in a message.
+pub struct CodeBlock<'a> {
+    /// The tag on the first line of the block, if it has one.
+    pub language: Option<&'a str>,
+    pub code: &'a str,
+}
+
+impl<'a> CodeBlock<'a> {
+    /// Whether the block is untagged or tagged as a language ketos can run.
+    pub fn is_ketos(&self) -> bool {
+        match self.language {
+            Some(language) => LANGUAGE_TAGS.contains(&language.to_lowercase().as_str()),
+            None => true,
+        }
+    }
+}
+
+/// Finds every closed code block in a message, in order.
+pub fn code_blocks(content: &str) -> Vec<CodeBlock> {
+    let parts = content.split("```").collect::<Vec<_>>();
+    (1..parts.len().saturating_sub(1))
+        .step_by(2)
+        .map(|i| {
+            let block = parts[i];
+            let tagged = block.find('\n').and_then(|end| {
+                let tag = block[..end].trim();
+                let is_tag = !tag.is_empty()
+                    && tag
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '+' || c == '#');
+                if is_tag {
+                    Some((tag, &block[end + 1..]))
+                } else {
+                    None
+                }
+            });
+            match tagged {
+                Some((language, code)) => CodeBlock {
+                    language: Some(language),
+                    code,
+                },
+                None => CodeBlock {
+                    language: None,
+                    code: block,
+                },
+            }
+        })
+        .collect()
+}
+
+/// Finds every closed single-backtick span of inline code in a message which
+/// has no code blocks, in order.
+pub fn inline_code(content: &str) -> Vec<&str> {
+    if content.contains("```") {
+        return Vec::new();
+    }
+    let parts = content.split('`').collect::<Vec<_>>();
+    (1..parts.len().saturating_sub(1))
+        .step_by(2)
+        .map(|i| parts[i])
+        .filter(|code| !code.trim().is_empty())
+        .collect()
+}
+
+/// Extracts the code of the first code block in a message, stripping the
+/// language tag.
+pub fn extract_code(content: &str) -> Option<&str> {
+    code_blocks(content).into_iter().next().map(|b| b.code)
+}
+
+/// Gathers the code to evaluate from a message: its attached `.lisp` and `.ket`
+/// files, then its code blocks, or its inline code if it has no code blocks.
+/// Everything is joined in order into a single program.
+pub fn collect_code(msg: &Message) -> Result<String> {
+    let mut parts = Vec::new();
+
+    for attachment in &msg.attachments {
+        let name = attachment.filename.to_lowercase();
+        if !FILE_EXTENSIONS.iter().any(|e| name.ends_with(e)) {
+            return Err(
+                CommandUsageKind::DeveloperEvaluateAttachment(attachment.filename.clone()).into(),
+            );
+        }
+        parts.push(String::from_utf8(attachment.download()?)?);
+    }
+
+    let blocks = code_blocks(&msg.content);
+    for block in &blocks {
+        if !block.is_ketos() {
+            let language = block.language.unwrap_or_default().to_owned();
+            return Err(CommandUsageKind::DeveloperEvaluateLanguage(language).into());
+        }
+        parts.push(block.code.to_owned());
+    }
+    if blocks.is_empty() {
+        parts.extend(inline_code(&msg.content).into_iter().map(String::from));
+    }
+
+    if parts.iter().all(|p| p.trim().is_empty()) {
+        return Err(CommandUsageKind::DeveloperEvaluateNoCode.into());
+    }
+    Ok(parts.join("\n"))
+}
+
+/// Evaluates the code in the session of the author of the message, starting one
+/// if needed, and replies with the outcome once it is done.
+pub fn evaluate(ctx: &Context, msg: &Message, code: String) -> Result<()> {
+    let discarded = Arc::new(AtomicBool::new(false));
+    let environment = Environment {
+        http: Arc::clone(&ctx.http),
+        cache: ctx.cache.clone(),
+        data: Arc::clone(&ctx.data),
+        message: msg.clone(),
+    };
+    let http = Arc::clone(&ctx.http);
+    let data = Arc::clone(&ctx.data);
+    let (channel, message, author) = (msg.channel_id, msg.id, msg.author.id);
+    let done = {
+        let discarded = Arc::clone(&discarded);
+        move |outcome: EvaluationOutcome| {
+            if let Some(running) = data.write().get_mut::<EvaluationsContainer>() {
+                running.remove(&message);
+            }
+            if discarded.load(Ordering::SeqCst) {
+                return;
+            }
+
+            if let Err(e) = send_outcome(&http, channel, author, outcome) {
+                error!("Couldn't send the outcome of an evaluation: {:?}", e);
+            }
+        }
+    };
+
+    let mut data = ctx.data.write();
+    let config = data
+        .get::<ConfigurationContainer>()
+        .failure()?
+        .evaluation()
+        .clone();
+    let sessions = data.get_mut::<SessionsContainer>().failure()?;
+    if !sessions.contains_key(&author) {
+        let session = Session::spawn(
+            author,
+            super::owner_restrictions(&config),
+            *config.output_limit_bytes(),
+            Duration::from_secs(*config.session_idle_timeout_seconds()),
+            Arc::clone(&ctx.data),
+        )?;
+        sessions.insert(author, session);
+    }
+    sessions
+        .get(&author)
+        .failure()?
+        .evaluate(code, environment, Arc::clone(&discarded), done)?;
+
+    data.get_mut::<EvaluationsContainer>()
+        .failure()?
+        .insert(message, RunningEvaluation { author, discarded });
+
+    Ok(())
+}
+
+/// A part of the outcome of an evaluation, sent either inline or as a file.
+struct Section {
+    title: &'static str,
+    file_name: &'static str,
+    content: String,
+    attached: bool,
+}
+
+/// Sends the outcome of an evaluation, attaching the parts which are too long to
+/// fit in a message as text files.
+pub fn send_outcome(
+    http: &Arc<Http>,
+    channel: ChannelId,
+    author: UserId,
+    outcome: EvaluationOutcome,
+) -> Result<()> {
+    let (title, result) = match outcome.result {
+        Ok(s) => ("Result", s),
+        Err(s) => ("Error", s),
+    };
+    let mut output = outcome.output;
+    if outcome.output_truncated {
+        output.push_str("\n[The output was cut off at the output limit.]");
+    }
+
+    let mut sections = vec![Section {
+        title,
+        file_name: "result.txt",
+        content: result,
+        attached: false,
+    }];
+    if !output.is_empty() {
+        sections.push(Section {
+            title: "Output",
+            file_name: "output.txt",
+            content: output,
+            attached: false,
+        });
+    }
+
+    let header = format!(
+        "{}: Evaluated in {:.2} ms.",
+        author.mention(),
+        outcome.duration.as_micros() as f64 / 1000.0
+    );
+    let content = loop {
+        let content = render(&header, &sections);
+        if content.chars().count() <= MESSAGE_LENGTH_LIMIT {
+            break content;
+        }
+        match sections
+            .iter_mut()
+            .filter(|s| !s.attached)
+            .max_by_key(|s| s.content.len())
+        {
+            Some(longest) => longest.attached = true,
+            None => break content,
+        }
+    };
+
+    let files = sections
+        .iter()
+        .filter(|s| s.attached)
+        .map(|s| (s.content.as_bytes(), s.file_name))
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        channel.say(http, &content)?;
+    } else {
+        channel.send_files(http, files, |m| m.content(&content))?;
+    }
+
+    Ok(())
+}
+
+fn render(header: &str, sections: &[Section]) -> String {
+    let mut content = header.to_owned();
+    for section in sections {
+        if section.attached {
+            content.push_str(&format!(
+                "\n**{}** is attached as `{}`.",
+                section.title, section.file_name
+            ));
+        } else {
+            content.push_str(&format!(
+                "\n**{}**\n```\n{}\n```",
+                section.title,
+                // Keep code blocks in the content from ending the one around it.
+                section.content.replace("```", "`\u{200b}``")
+            ));
+        }
+    }
+    content
+}
@@ -0,0 +1,188 @@
+use super::{
+    stdlib::{self, Environment},
+    worker::{self, EvaluationOutcome},
+};
+use crate::prelude::*;
+use ketos::{Interpreter, Name, RestrictConfig};
+use parking_lot::RwLock;
+use serenity::model::id::{ChannelId, UserId};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use typemap::{Key as TypeMapKey, ShareMap};
+
+/// The ketos sessions of the owners, kept alive between evaluations.
+pub struct SessionsContainer;
+
+impl TypeMapKey for SessionsContainer {
+    type Value = HashMap<UserId, Session>;
+}
+
+/// The channels in which owners' code blocks are evaluated without a command.
+pub struct ReplChannelsContainer;
+
+impl TypeMapKey for ReplChannelsContainer {
+    type Value = HashSet<ChannelId>;
+}
+
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+enum Job {
+    Evaluate {
+        code: String,
+        environment: Environment,
+        discarded: Arc<AtomicBool>,
+        done: Box<dyn FnOnce(EvaluationOutcome) + Send>,
+    },
+    Bindings {
+        done: Box<dyn FnOnce(Vec<String>) + Send>,
+    },
+}
+
+/// An interpreter living on a thread of its own, which keeps its definitions
+/// between evaluations until it has been idle for too long.
+pub struct Session {
+    jobs: Sender<Job>,
+    generation: u64,
+}
+
+impl Session {
+    pub fn spawn(
+        owner: UserId,
+        restrict: RestrictConfig,
+        output_limit: usize,
+        idle_timeout: Duration,
+        data: Arc<RwLock<ShareMap>>,
+    ) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let generation = NEXT_GENERATION.fetch_add(1, Ordering::SeqCst);
+
+        thread::Builder::new()
+            .name(format!("ketos-session-{}", owner.0))
+            .spawn(move || {
+                let (interpreter, output_writer) =
+                    worker::build_interpreter(restrict, output_limit);
+                stdlib::register(&interpreter);
+                let builtins = names(&interpreter);
+
+                loop {
+                    let job = match receiver.recv_timeout(idle_timeout) {
+                        Ok(job) => job,
+                        Err(RecvTimeoutError::Timeout) => {
+                            let mut data = data.write();
+                            // Jobs are only queued with the share map locked, so
+                            // none can be missed after this.
+                            match receiver.try_recv() {
+                                Ok(job) => job,
+                                Err(_) => {
+                                    if let Some(sessions) = data.get_mut::<SessionsContainer>() {
+                                        if sessions.get(&owner).map(|s| s.generation)
+                                            == Some(generation)
+                                        {
+                                            sessions.remove(&owner);
+                                        }
+                                    }
+                                    debug!("The ketos session of {} ended after idling.", owner.0);
+                                    break;
+                                }
+                            }
+                        }
+                        // The session was reset.
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+
+                    match job {
+                        Job::Evaluate {
+                            code,
+                            environment,
+                            discarded,
+                            done,
+                        } => {
+                            // Evaluations discarded while they were queued aren't run
+                            if discarded.load(Ordering::SeqCst) {
+                                continue;
+                            }
+                            stdlib::set_environment(environment);
+                            done(worker::run(&interpreter, &output_writer, &code));
+                        }
+                        Job::Bindings { done } => done(bindings(&interpreter, &builtins)),
+                    }
+                }
+            })?;
+
+        Ok(Session {
+            jobs: sender,
+            generation,
+        })
+    }
+
+    /// Queues code to be evaluated after whatever the session is running now,
+    /// unless it is discarded before then.
+    ///
+    /// The share map has to be locked while queueing, so the session can't end
+    /// in between.
+    pub fn evaluate<F>(
+        &self,
+        code: String,
+        environment: Environment,
+        discarded: Arc<AtomicBool>,
+        done: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(EvaluationOutcome) + Send + 'static,
+    {
+        self.jobs
+            .send(Job::Evaluate {
+                code,
+                environment,
+                discarded,
+                done: Box::new(done),
+            })
+            .map_err(|_| ScriptingErrorKind::SessionEnded.into())
+    }
+
+    /// Lists the definitions made in the session, formatted as `name = value`.
+    pub fn bindings<F>(&self, done: F) -> Result<()>
+    where
+        F: FnOnce(Vec<String>) + Send + 'static,
+    {
+        self.jobs
+            .send(Job::Bindings {
+                done: Box::new(done),
+            })
+            .map_err(|_| ScriptingErrorKind::SessionEnded.into())
+    }
+}
+
+fn names(interpreter: &Interpreter) -> HashSet<Name> {
+    interpreter
+        .scope()
+        .with_values(|values| values.iter().map(|(name, _)| *name).collect())
+}
+
+fn bindings(interpreter: &Interpreter, builtins: &HashSet<Name>) -> Vec<String> {
+    let scope = interpreter.scope();
+    let mut bindings = scope.with_values(|values| {
+        values
+            .iter()
+            .filter(|(name, _)| !builtins.contains(name))
+            .map(|(name, value)| {
+                format!(
+                    "{} = {}",
+                    scope.with_name(*name, |n| n.to_owned()),
+                    interpreter.format_value(value)
+                )
+            })
+            .collect::<Vec<_>>()
+    });
+    bindings.sort();
+    bindings
+}
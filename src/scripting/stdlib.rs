@@ -0,0 +1,233 @@
+//! The functions owners' evaluations get to inspect and script the bot with.
+
+use crate::data::{audit::Actor, ServerSettings, UserSettings};
+use ketos::{Error as KetosError, ExecError, Interpreter, Value};
+use parking_lot::RwLock;
+use serenity::{
+    cache::CacheRwLock,
+    http::Http,
+    model::{
+        channel::Message,
+        id::{ChannelId, GuildId, UserId},
+    },
+};
+use std::{cell::RefCell, fmt::Display, sync::Arc};
+
+thread_local! {
+    /// The environment of the evaluation running on this thread.
+    static ENVIRONMENT: RefCell<Option<Environment>> = RefCell::new(None);
+}
+
+/// Everything the functions need to reach Discord and the bot.
+pub struct Environment {
+    pub http: Arc<Http>,
+    pub cache: CacheRwLock,
+    pub data: Arc<RwLock<typemap::ShareMap>>,
+    /// The message which started the evaluation.
+    pub message: Message,
+}
+
+#[derive(Clone, Debug, ForeignValue, FromValueClone, IntoValue, StructValue)]
+pub struct DiscordMessage {
+    id: u64,
+    channel_id: u64,
+    /// 0 if the message wasn't sent in a guild.
+    guild_id: u64,
+    author_id: u64,
+    author_name: String,
+    content: String,
+}
+
+#[derive(Clone, Debug, ForeignValue, FromValueClone, IntoValue, StructValue)]
+pub struct DiscordChannel {
+    id: u64,
+    guild_id: u64,
+    name: String,
+}
+
+#[derive(Clone, Debug, ForeignValue, FromValueClone, IntoValue, StructValue)]
+pub struct DiscordGuild {
+    id: u64,
+    name: String,
+    owner_id: u64,
+    member_count: u64,
+}
+
+#[derive(Clone, Debug, ForeignValue, FromValueClone, IntoValue, StructValue)]
+pub struct DiscordUser {
+    id: u64,
+    name: String,
+    discriminator: u64,
+    bot: bool,
+}
+
+/// Replaces the environment the functions on this thread use.
+pub fn set_environment(environment: Environment) {
+    ENVIRONMENT.with(|env| *env.borrow_mut() = Some(environment));
+}
+
+/// Adds the functions to the interpreter.
+pub fn register(interpreter: &Interpreter) {
+    let scope = interpreter.scope();
+    scope.register_struct_value::<DiscordMessage>();
+    scope.register_struct_value::<DiscordChannel>();
+    scope.register_struct_value::<DiscordGuild>();
+    scope.register_struct_value::<DiscordUser>();
+
+    ketos_fn! { scope => "message" => fn message() -> DiscordMessage }
+    ketos_fn! { scope => "channel" => fn channel() -> Value }
+    ketos_fn! { scope => "guild" => fn guild() -> Value }
+    ketos_fn! { scope => "cached-user" => fn cached_user(id: u64) -> Value }
+    ketos_fn! { scope => "cached-channel" => fn cached_channel(id: u64) -> Value }
+    ketos_fn! { scope => "cached-guild" => fn cached_guild(id: u64) -> Value }
+    ketos_fn! { scope => "send-message" =>
+    fn send_message(channel: u64, content: &str) -> u64 }
+    ketos_fn! { scope => "user-blacklisted" => fn user_blacklisted(id: u64) -> bool }
+    ketos_fn! { scope => "set-user-blacklisted" =>
+    fn set_user_blacklisted(id: u64, new: bool) -> () }
+    ketos_fn! { scope => "server-blacklisted" => fn server_blacklisted(id: u64) -> bool }
+    ketos_fn! { scope => "set-server-blacklisted" =>
+    fn set_server_blacklisted(id: u64, new: bool) -> () }
+}
+
+/// Turns any error into one the evaluation fails with.
+pub fn script_error(e: impl Display) -> KetosError {
+    KetosError::ExecError(ExecError::Panic(Some(e.to_string().into())))
+}
+
+fn with_environment<T, F>(f: F) -> Result<T, KetosError>
+where
+    F: FnOnce(&Environment) -> Result<T, KetosError>,
+{
+    ENVIRONMENT.with(|env| match &*env.borrow() {
+        Some(env) => f(env),
+        None => Err(script_error("This function isn't available here.")),
+    })
+}
+
+fn channel_value(id: ChannelId, cache: &CacheRwLock) -> Value {
+    match cache.read().guild_channel(id) {
+        Some(channel) => {
+            let channel = channel.read();
+            DiscordChannel {
+                id: channel.id.0,
+                guild_id: channel.guild_id.0,
+                name: channel.name.clone(),
+            }
+            .into()
+        }
+        None => Value::Unit,
+    }
+}
+
+fn guild_value(id: GuildId, cache: &CacheRwLock) -> Value {
+    match cache.read().guild(id) {
+        Some(guild) => {
+            let guild = guild.read();
+            DiscordGuild {
+                id: guild.id.0,
+                name: guild.name.clone(),
+                owner_id: guild.owner_id.0,
+                member_count: guild.member_count,
+            }
+            .into()
+        }
+        None => Value::Unit,
+    }
+}
+
+fn message() -> Result<DiscordMessage, KetosError> {
+    with_environment(|env| {
+        let msg = &env.message;
+        Ok(DiscordMessage {
+            id: msg.id.0,
+            channel_id: msg.channel_id.0,
+            guild_id: msg.guild_id.map(|g| g.0).unwrap_or(0),
+            author_id: msg.author.id.0,
+            author_name: msg.author.name.clone(),
+            content: msg.content.clone(),
+        })
+    })
+}
+
+fn channel() -> Result<Value, KetosError> {
+    with_environment(|env| Ok(channel_value(env.message.channel_id, &env.cache)))
+}
+
+fn guild() -> Result<Value, KetosError> {
+    with_environment(|env| {
+        Ok(match env.message.guild_id {
+            Some(id) => guild_value(id, &env.cache),
+            None => Value::Unit,
+        })
+    })
+}
+
+fn cached_user(id: u64) -> Result<Value, KetosError> {
+    with_environment(|env| {
+        Ok(match env.cache.read().user(UserId(id)) {
+            Some(user) => {
+                let user = user.read();
+                DiscordUser {
+                    id: user.id.0,
+                    name: user.name.clone(),
+                    discriminator: u64::from(user.discriminator),
+                    bot: user.bot,
+                }
+                .into()
+            }
+            None => Value::Unit,
+        })
+    })
+}
+
+fn cached_channel(id: u64) -> Result<Value, KetosError> {
+    with_environment(|env| Ok(channel_value(ChannelId(id), &env.cache)))
+}
+
+fn cached_guild(id: u64) -> Result<Value, KetosError> {
+    with_environment(|env| Ok(guild_value(GuildId(id), &env.cache)))
+}
+
+fn send_message(channel: u64, content: &str) -> Result<u64, KetosError> {
+    with_environment(|env| {
+        ChannelId(channel)
+            .say(&env.http, content)
+            .map(|m| m.id.0)
+            .map_err(script_error)
+    })
+}
+
+fn user_blacklisted(id: u64) -> Result<bool, KetosError> {
+    with_environment(|env| {
+        let settings = UserSettings::new(id, &env.data).map_err(script_error)?;
+        let blacklisted = *settings.read().blacklisted();
+        Ok(blacklisted)
+    })
+}
+
+fn set_user_blacklisted(id: u64, new: bool) -> Result<(), KetosError> {
+    with_environment(|env| {
+        let settings = UserSettings::new(id, &env.data).map_err(script_error)?;
+        let mut write = settings.write();
+        write.set_blacklisted(new, Actor::script(env.message.author.id.0));
+        write.save().map_err(script_error)
+    })
+}
+
+fn server_blacklisted(id: u64) -> Result<bool, KetosError> {
+    with_environment(|env| {
+        let settings = ServerSettings::new(id, &env.data).map_err(script_error)?;
+        let blacklisted = *settings.read().blacklisted();
+        Ok(blacklisted)
+    })
+}
+
+fn set_server_blacklisted(id: u64, new: bool) -> Result<(), KetosError> {
+    with_environment(|env| {
+        let settings = ServerSettings::new(id, &env.data).map_err(script_error)?;
+        let mut write = settings.write();
+        write.set_blacklisted(new, Actor::script(env.message.author.id.0));
+        write.save().map_err(script_error)
+    })
+}
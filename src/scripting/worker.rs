@@ -0,0 +1,143 @@
+use crate::{ketoswritewrapper::KetosWriteWrapper, prelude::*};
+use ketos::{
+    io::SharedWrite as _, Builder, BuiltinModuleLoader, Error as KetosError, Interpreter,
+    RestrictConfig, RestrictError,
+};
+use serenity::model::id::{MessageId, UserId};
+use std::{
+    collections::HashMap,
+    io::{self, Cursor},
+    rc::Rc,
+    sync::{atomic::AtomicBool, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+use typemap::Key as TypeMapKey;
+
+/// The writer evaluations print to.
+pub type OutputWriter = KetosWriteWrapper<Cursor<Vec<u8>>>;
+
+/// The evaluations currently running, by the message which started them.
+pub struct EvaluationsContainer;
+
+impl TypeMapKey for EvaluationsContainer {
+    type Value = HashMap<MessageId, RunningEvaluation>;
+}
+
+pub struct RunningEvaluation {
+    pub author: UserId,
+    /// Set when the evaluation is discarded, so it doesn't run if it is still
+    /// queued and its result is thrown away.
+    pub discarded: Arc<AtomicBool>,
+}
+
+/// What came out of evaluating some code.
+#[derive(Debug)]
+pub struct EvaluationOutcome {
+    /// The formatted value the code returned, or the error it failed with.
+    pub result: std::result::Result<String, String>,
+    /// Everything the code printed, up to the output limit.
+    pub output: String,
+    /// Whether the code printed more than the output limit.
+    pub output_truncated: bool,
+    pub duration: Duration,
+}
+
+/// Evaluates the code in a sandboxed interpreter on a thread of its own, so a
+/// long-running evaluation doesn't hold up the shard it was started from.
+/// `setup` is called with the interpreter before the code runs, and `done` once
+/// the evaluation finishes, both on that thread.
+///
+/// The sandbox can only load the modules built into ketos, not files.
+pub fn spawn_evaluation<S, F>(
+    code: String,
+    restrict: RestrictConfig,
+    output_limit: usize,
+    setup: S,
+    done: F,
+) -> io::Result<()>
+where
+    S: FnOnce(&Interpreter) + Send + 'static,
+    F: FnOnce(EvaluationOutcome) + Send + 'static,
+{
+    thread::Builder::new()
+        .name(String::from("ketos-evaluation"))
+        .spawn(move || {
+            let (builder, output_writer) = interpreter_builder(restrict, output_limit);
+            let interpreter = builder
+                .module_loader(Box::new(BuiltinModuleLoader))
+                .finish();
+            setup(&interpreter);
+            done(run(&interpreter, &output_writer, &code))
+        })?;
+    Ok(())
+}
+
+/// Builds an interpreter whose output goes to the returned writer, which keeps
+/// at most `output_limit` bytes of it.
+pub fn build_interpreter(
+    restrict: RestrictConfig,
+    output_limit: usize,
+) -> (Interpreter, Rc<OutputWriter>) {
+    let (builder, output_writer) = interpreter_builder(restrict, output_limit);
+    (builder.finish(), output_writer)
+}
+
+fn interpreter_builder(
+    restrict: RestrictConfig,
+    output_limit: usize,
+) -> (Builder, Rc<OutputWriter>) {
+    let output_writer = Rc::new(KetosWriteWrapper::bytearray_with_limit(output_limit));
+    let builder = Builder::new()
+        .restrict(restrict)
+        .io(Rc::new(ketos::GlobalIo::new(
+            Rc::clone(&output_writer) as Rc<dyn ketos::SharedWrite>,
+            Rc::clone(&output_writer) as Rc<dyn ketos::SharedWrite>,
+        )));
+    (builder, output_writer)
+}
+
+/// Runs the code in the interpreter, taking whatever it printed from the writer.
+pub fn run(
+    interpreter: &Interpreter,
+    output_writer: &OutputWriter,
+    code: &str,
+) -> EvaluationOutcome {
+    debug!("Entire code to run with ketos:\n{}", code);
+
+    let start = Instant::now();
+    let result = interpreter
+        .compile_exprs(code)
+        .and_then(|compiled| {
+            debug!("Compiled: {:?}", compiled);
+            interpreter.execute_program(compiled)
+        })
+        .map(|ref v| interpreter.format_value(v))
+        .map_err(|ref e| describe_error(e));
+    let duration = start.elapsed();
+
+    let output_truncated = output_writer.truncated();
+    let output = match output_writer.flush() {
+        Ok(()) => output_writer.take_string(),
+        Err(e) => format!("{:?}", e),
+    };
+
+    EvaluationOutcome {
+        result,
+        output,
+        output_truncated,
+        duration,
+    }
+}
+
+fn describe_error(e: &KetosError) -> String {
+    match e {
+        KetosError::RestrictError(RestrictError::ExecutionTimeExceeded) => {
+            String::from("The evaluation ran out of time and was stopped.")
+        }
+        KetosError::RestrictError(RestrictError::MemoryLimitExceeded) => {
+            String::from("The evaluation ran out of memory and was stopped.")
+        }
+        e => format!("{:?}", e),
+    }
+}
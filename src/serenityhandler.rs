@@ -1,6 +1,28 @@
-use super::prelude::*;
+use super::{
+    antiraid, automod,
+    data::{
+        audit::Actor, eventhooks::HookEvent, ConfigurationContainer, OwnerContainer,
+        ServerSettings, ServerSettingsContainer,
+    },
+    greetings, janitor, messagelog, onboarding,
+    prelude::*,
+    scripting::{
+        self,
+        hooks::{self, Binding},
+        ReplChannelsContainer,
+    },
+};
+use chrono::Utc;
+use diesel::Connection;
 use serenity::{
-    model::{channel::Message, gateway::Ready, guild::Member, id::GuildId, user::User},
+    model::{
+        channel::{Channel, Message, Reaction},
+        event::MessageUpdateEvent,
+        gateway::Ready,
+        guild::{Guild, Member, PartialGuild},
+        id::{ChannelId, GuildId, MessageId},
+        user::User,
+    },
     prelude::*,
 };
 use std::sync::Arc;
@@ -9,68 +31,275 @@ pub struct SerenityHandler;
 
 impl EventHandler for SerenityHandler {
     fn guild_member_addition(&self, ctx: Context, guild: GuildId, member: Member) {
-        let current = &ctx.cache.read().user;
-        if current.id != member.user_id() {
+        // Joining servers itself is handled in `guild_create`
+        if ctx.cache.read().user.id == member.user_id() {
             return;
         }
-        let server_settings = match crate::data::ServerSettings::new(guild.0, &ctx.data) {
-            Ok(s) => s,
-            Err(e) => {
-                error!("Couldn't retrieve settings for {}: {:?}", guild.0, e);
-                return;
-            }
-        };
-        if *server_settings.read().blacklisted() {
-            if let Err(e) = ctx.http.leave_guild(guild.0) {
-                error!("Couldn't leave guild {}: {:?}", guild.0, e);
-            }
+        if antiraid::member_joined(&ctx, guild, &member) {
+            return;
         }
+        onboarding::member_joined(&ctx, guild, &member);
+        let user = member.user.read().clone();
+        greetings::member_joined(&ctx, guild, &user);
+        let channel = guild
+            .to_guild_cached(&ctx.cache)
+            .and_then(|g| g.read().system_channel_id);
+        hooks::fire(
+            &ctx,
+            guild,
+            HookEvent::MemberJoin,
+            channel,
+            user_bindings(&user),
+        );
+    }
+
+    fn guild_create(&self, ctx: Context, guild: Guild, is_new: bool) {
+        // Servers the bot was in at startup are restored once it is ready
+        if is_new {
+            rejoined(&ctx, guild.id);
+        }
+    }
+
+    // Outages are dispatched as `guild_unavailable` instead, so this is only
+    // reached once the bot was removed from the server or left it.
+    fn guild_delete(&self, ctx: Context, guild: PartialGuild, _: Option<Arc<RwLock<Guild>>>) {
+        departed(&ctx, guild.id);
     }
 
     fn guild_member_removal(&self, ctx: Context, guild: GuildId, user: User, _: Option<Member>) {
-        let current = &ctx.cache.read().user;
-        if user.id != current.id {
+        // Leaving servers itself is handled in `guild_delete`
+        if ctx.cache.read().user.id == user.id {
             return;
         }
-        let mut write = ctx.data.write();
-        let cache = match write.get_mut::<crate::data::ServerSettingsContainer>() {
-            Some(s) => s,
-            None => {
-                error!("Server settings container is not in the share map.");
-                return;
-            }
-        };
-        if let Some(s) = cache.get(&guild.0) {
-            if *s.read().blacklisted() {
+        onboarding::member_left(&ctx, guild, &user);
+        greetings::member_left(&ctx, guild, &user);
+        let channel = guild
+            .to_guild_cached(&ctx.cache)
+            .and_then(|g| g.read().system_channel_id);
+        hooks::fire(
+            &ctx,
+            guild,
+            HookEvent::MemberLeave,
+            channel,
+            user_bindings(&user),
+        );
+    }
+
+    fn message(&self, ctx: Context, msg: Message) {
+        debug!("{}: {}", msg.author.name, msg.content);
+
+        self.evaluate_repl(&ctx, &msg);
+
+        if let Some(guild) = msg.guild_id {
+            messagelog::remember(&ctx, guild, &msg);
+            // Removed messages shouldn't trigger anything else
+            if automod::check(&ctx, guild, &msg) {
                 return;
             }
         }
-        if let Some(s) = cache.remove(&guild.0) {
-            let s = match Arc::try_unwrap(s) {
-                Ok(s) => s,
-                Err(_) => {
-                    error!("Couldn't unwrap server {}'s arc", guild.0);
-                    return;
-                }
-            };
-            match s.into_inner().delete().err() {
-                None => {}
-                Some(e) => error!("Couldn't delete {}: {}", guild.0, e),
-            }
+
+        // Skipping bots keeps hooks from answering themselves
+        if let (Some(guild), false) = (msg.guild_id, msg.author.bot) {
+            hooks::fire(
+                &ctx,
+                guild,
+                HookEvent::Message,
+                Some(msg.channel_id),
+                vec![
+                    ("author-id", Binding::Integer(msg.author.id.0)),
+                    ("author-name", Binding::Text(msg.author.name.clone())),
+                    ("channel-id", Binding::Integer(msg.channel_id.0)),
+                    ("message-id", Binding::Integer(msg.id.0)),
+                    ("content", Binding::Text(msg.content.clone())),
+                ],
+            );
         }
-        info!("Left guild {}", guild.0);
     }
 
-    fn message(&self, _: Context, msg: Message) {
-        debug!("{}: {}", msg.author.name, msg.content);
+    fn message_update(
+        &self,
+        ctx: Context,
+        _: Option<Message>,
+        _: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        messagelog::edited(&ctx, &event);
+    }
+
+    fn message_delete(&self, ctx: Context, channel: ChannelId, message: MessageId) {
+        messagelog::deleted(&ctx, channel, message);
+    }
+
+    fn message_delete_bulk(&self, ctx: Context, channel: ChannelId, messages: Vec<MessageId>) {
+        messagelog::bulk_deleted(&ctx, channel, &messages);
     }
 
-    fn ready(&self, _: Context, ready: Ready) {
+    fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        if reaction.user_id == ctx.cache.read().user.id {
+            return;
+        }
+        let guild = match reaction.channel_id.to_channel_cached(&ctx.cache) {
+            Some(Channel::Guild(channel)) => channel.read().guild_id,
+            _ => return,
+        };
+
+        onboarding::reaction_added(&ctx, guild, &reaction);
+        hooks::fire(
+            &ctx,
+            guild,
+            HookEvent::ReactionAdd,
+            Some(reaction.channel_id),
+            vec![
+                ("user-id", Binding::Integer(reaction.user_id.0)),
+                ("channel-id", Binding::Integer(reaction.channel_id.0)),
+                ("message-id", Binding::Integer(reaction.message_id.0)),
+                ("emoji", Binding::Text(reaction.emoji.to_string())),
+            ],
+        );
+    }
+
+    fn ready(&self, ctx: Context, ready: Ready) {
         info!(
             "{} is connected on shard {}/{}.",
             ready.user.name,
             ready.shard.map(|o| o[0]).unwrap_or(0) + 1,
             ready.shard.map(|o| o[1]).unwrap_or(0)
         );
+
+        let guilds = ready.guilds.iter().map(|g| g.id().0).collect::<Vec<_>>();
+        match ServerSettings::preload(&guilds, &ctx.data) {
+            Ok(n) => debug!("Warmed up the settings cache with {} servers.", n),
+            Err(e) => error!("Couldn't warm up the server settings cache: {:?}", e),
+        }
+        // The bot may have been added back to servers while it was offline
+        for &guild in &guilds {
+            rejoined(&ctx, GuildId(guild));
+        }
+    }
+}
+
+/// Marks a server the bot is no longer in as departed. Its data is only deleted
+/// by the janitor once the grace period has run out.
+fn departed(ctx: &Context, guild: GuildId) {
+    let server_settings = match ServerSettings::new(guild.0, &ctx.data) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Couldn't retrieve settings for {}: {:?}", guild.0, e);
+            return;
+        }
+    };
+
+    let mut write = server_settings.write();
+    write.set_departed_at(Some(Utc::now()), Actor::event());
+    if let Err(e) = write.save() {
+        error!("Couldn't mark {} as departed: {:?}", guild.0, e);
+    }
+    info!("Left guild {}", guild.0);
+}
+
+/// Restores the data of a server the bot is in again, or purges it if it has
+/// expired since the bot left. Blacklisted servers are left right away.
+fn rejoined(ctx: &Context, guild: GuildId) {
+    let server_settings = match ServerSettings::new(guild.0, &ctx.data) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Couldn't retrieve settings for {}: {:?}", guild.0, e);
+            return;
+        }
+    };
+    if *server_settings.read().blacklisted() {
+        if let Err(e) = ctx.http.leave_guild(guild.0) {
+            error!("Couldn't leave guild {}: {:?}", guild.0, e);
+        }
+        return;
+    }
+
+    let departed_at = match *server_settings.read().departed_at() {
+        Some(s) => s,
+        None => return,
+    };
+    let retention = match ctx.data.read().get::<ConfigurationContainer>() {
+        Some(s) => s.guild_retention().clone(),
+        None => {
+            error!("Configuration container is not in the share map.");
+            return;
+        }
+    };
+    if !janitor::expired(departed_at, &retention) {
+        let mut write = server_settings.write();
+        write.set_departed_at(None, Actor::event());
+        match write.save() {
+            Ok(()) => info!("Rejoined guild {}; its settings were restored.", guild.0),
+            Err(e) => error!("Couldn't restore the settings of {}: {:?}", guild.0, e),
+        }
+        return;
+    }
+
+    // The janitor hasn't gotten to the guild yet, but its data has expired.
+    drop(server_settings);
+    let result: Result<()> = try {
+        {
+            let pgconn = crate::database::connection(&ctx.data)?;
+            pgconn.transaction::<_, diesel::result::Error, _>(|| {
+                janitor::purge_guild(&pgconn, guild.0)
+            })?;
+        }
+        // Dropped once the lock is released, as dropping settings may save them
+        let evicted = ctx
+            .data
+            .write()
+            .get_mut::<ServerSettingsContainer>()
+            .failure()?
+            .remove(&guild.0);
+        drop(evicted);
+    };
+    match result {
+        Ok(()) => info!("Rejoined guild {} after its data expired.", guild.0),
+        Err(e) => error!("Couldn't purge the expired data of {}: {:?}", guild.0, e),
+    }
+}
+
+/// The bindings of hooks for events about a user.
+fn user_bindings(user: &User) -> Vec<(&'static str, Binding)> {
+    vec![
+        ("user-id", Binding::Integer(user.id.0)),
+        ("user-name", Binding::Text(user.name.clone())),
+    ]
+}
+
+impl SerenityHandler {
+    /// Evaluates code blocks sent by owners in REPL channels.
+    fn evaluate_repl(&self, ctx: &Context, msg: &Message) {
+        if msg.content.starts_with(crate::consts::PREFIX) {
+            return;
+        }
+        {
+            let data = ctx.data.read();
+            let repl = data
+                .get::<ReplChannelsContainer>()
+                .map(|s| s.contains(&msg.channel_id))
+                .unwrap_or(false);
+            let owner = data
+                .get::<OwnerContainer>()
+                .map(|s| s.contains(&msg.author.id))
+                .unwrap_or(false);
+            if !repl || !owner {
+                return;
+            }
+        }
+
+        // Code blocks in other languages are only being shown, not run
+        let code = scripting::owner::code_blocks(&msg.content)
+            .iter()
+            .filter(|b| b.is_ketos())
+            .map(|b| b.code)
+            .collect::<Vec<_>>();
+        if code.is_empty() {
+            return;
+        }
+        let code = code.join("\n");
+        if let Err(e) = scripting::owner::evaluate(ctx, msg, code) {
+            let _ = msg.reply(ctx, &format!("The evaluation couldn't be started: {}", e));
+        }
     }
 }